@@ -0,0 +1,93 @@
+// Compares the inverted index against scanning every todo, which is what searching looked like
+// before the index.  Run with `cargo bench --bench search`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rest::search::{self, SearchIndex};
+
+// Made up words so the bench doesn't need a dictionary; a few thousand of them gives a realistic
+// mix of common and rare terms
+fn vocabulary() -> Vec<String> {
+    let syllables = [
+        "ro", "ta", "ca", "ll", "pl", "an", "sp", "ri", "nt", "re", "vi", "ew",
+    ];
+    let mut words = Vec::new();
+    for a in &syllables {
+        for b in &syllables {
+            for c in &syllables {
+                words.push(format!("{}{}{}", a, b, c));
+            }
+        }
+    }
+    words
+}
+
+// Deterministic todos with 3-10 words each, with word choice skewed towards the front of the
+// vocabulary the way real text favours common words
+fn todos(count: u64, vocabulary: &[String]) -> Vec<(u64, String)> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    (1..=count)
+        .map(|id| {
+            let words = 3 + next() % 8;
+            let text: Vec<&str> = (0..words)
+                .map(|_| {
+                    let r = (next() % vocabulary.len() as u64) as usize;
+                    let skewed = r * r / vocabulary.len();
+                    vocabulary[skewed].as_str()
+                })
+                .collect();
+            (id, text.join(" "))
+        })
+        .collect()
+}
+
+fn bench_search(c: &mut Criterion) {
+    let vocabulary = vocabulary();
+    let queries = [
+        ("one term", "rotaca"),
+        ("two terms", "rotaca plan"),
+        ("prefix", "sp"),
+    ];
+
+    for &count in &[1_000u64, 10_000, 50_000] {
+        let todos = todos(count, &vocabulary);
+        let mut index = SearchIndex::new();
+        for (id, text) in &todos {
+            index.insert(*id, text);
+        }
+
+        let mut group = c.benchmark_group(format!("search {} todos", count));
+        for (name, query) in &queries {
+            group.bench_with_input(BenchmarkId::new("index", name), query, |b, query| {
+                b.iter(|| index.search(query))
+            });
+            group.bench_with_input(BenchmarkId::new("scan", name), query, |b, query| {
+                b.iter(|| search::scan(todos.iter().map(|(id, text)| (*id, text.as_str())), query))
+            });
+        }
+        group.finish();
+    }
+}
+
+fn bench_maintenance(c: &mut Criterion) {
+    let vocabulary = vocabulary();
+    let todos = todos(10_000, &vocabulary);
+    let mut index = SearchIndex::new();
+    for (id, text) in &todos {
+        index.insert(*id, text);
+    }
+
+    // What every create/update pays to keep the index current
+    c.bench_function("reindex one todo of 10000", |b| {
+        b.iter(|| index.insert(42, "rotate the on-call schedule"))
+    });
+}
+
+criterion_group!(benches, bench_search, bench_maintenance);
+criterion_main!(benches);
@@ -0,0 +1,110 @@
+// Load test for the todo store under a mix of concurrent reads and writes, comparing the RwLock
+// the server uses now against the single Mutex it used to have.  Run with
+// `cargo bench --bench store_load`; it prints operations per second for each mix.
+//
+// Reads do what GET /todos does with the lock held (copy out a page of todos) and writes do
+// what PUT /todos/:id does, so the numbers reflect how long each side keeps the lock.
+
+use rest::models::{Todo, TodoStore};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+const TODOS: u64 = 10_000;
+const TASKS: usize = 8;
+const PAGE: usize = 100;
+const RUN_FOR: Duration = Duration::from_secs(2);
+
+// The two locks being compared
+enum Db {
+    Mutex(Mutex<TodoStore>),
+    RwLock(RwLock<TodoStore>),
+}
+
+impl Db {
+    async fn read<R>(&self, f: impl FnOnce(&TodoStore) -> R) -> R {
+        match self {
+            Db::Mutex(db) => f(&*db.lock().await),
+            Db::RwLock(db) => f(&*db.read().await),
+        }
+    }
+
+    async fn write<R>(&self, f: impl FnOnce(&mut TodoStore) -> R) -> R {
+        match self {
+            Db::Mutex(db) => f(&mut *db.lock().await),
+            Db::RwLock(db) => f(&mut *db.write().await),
+        }
+    }
+}
+
+fn store() -> TodoStore {
+    let mut store = TodoStore::new();
+    for id in 1..=TODOS {
+        store
+            .insert(Todo::new(
+                id,
+                format!("todo number {} of the load test", id),
+            ))
+            .unwrap();
+    }
+    store
+}
+
+// Runs TASKS tasks hammering `db` for RUN_FOR, `reads_per_100` of every 100 operations being
+// reads, and returns the operations per second they managed between them
+async fn run(db: Db, reads_per_100: u64) -> f64 {
+    let db = Arc::new(db);
+    let stop = Arc::new(AtomicBool::new(false));
+    let ops = Arc::new(AtomicU64::new(0));
+
+    let tasks: Vec<_> = (0..TASKS as u64)
+        .map(|task| {
+            let (db, stop, ops) = (db.clone(), stop.clone(), ops.clone());
+            tokio::spawn(async move {
+                let mut n = task;
+                while !stop.load(Ordering::Relaxed) {
+                    n += TASKS as u64;
+                    let id = n % TODOS + 1;
+                    if n % 100 < reads_per_100 {
+                        let offset = (id as usize) % (TODOS as usize - PAGE);
+                        let page: Vec<Todo> = db
+                            .read(|store| store.iter().skip(offset).take(PAGE).cloned().collect())
+                            .await;
+                        assert_eq!(page.len(), PAGE);
+                    } else {
+                        let todo = Todo::new(id, format!("todo number {} updated", id));
+                        db.write(|store| store.replace(id, todo)).await.unwrap();
+                    }
+                    ops.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    let started = Instant::now();
+    tokio::time::sleep(RUN_FOR).await;
+    stop.store(true, Ordering::Relaxed);
+    for task in tasks {
+        task.await.unwrap();
+    }
+    ops.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 8)]
+async fn main() {
+    println!(
+        "{} tasks over {} todos, {:?} per run",
+        TASKS, TODOS, RUN_FOR
+    );
+    println!(
+        "{:>8} {:>14} {:>14}",
+        "reads", "mutex ops/s", "rwlock ops/s"
+    );
+
+    for &reads in &[50, 90, 99] {
+        let mutex = run(Db::Mutex(Mutex::new(store())), reads).await;
+        let rwlock = run(Db::RwLock(RwLock::new(store())), reads).await;
+        println!("{:>7}% {:>14.0} {:>14.0}", reads, mutex, rwlock);
+    }
+}
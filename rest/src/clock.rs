@@ -0,0 +1,57 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use std::sync::Arc;
+
+/// Anything in the server that needs to know "now" asks a Clock rather than calling `Utc::now()`
+/// directly, so tests can pin time to a known value instead of racing the real one.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Due dates are plain calendar dates, so most callers only care about today's date.
+    fn today(&self) -> NaiveDate {
+        self.now().date_naive()
+    }
+}
+
+/// Like Db this gets cloned into every filter chain that needs it, so it's shared behind an Arc.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The real wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock for tests that only moves when told to.
+#[cfg(test)]
+pub struct ManualClock(std::sync::Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> ManualClock {
+        ManualClock(std::sync::Mutex::new(now))
+    }
+
+    pub fn at_date(year: i32, month: u32, day: u32) -> ManualClock {
+        let date = NaiveDate::from_ymd_opt(year, month, day).expect("valid date");
+        ManualClock::new(date.and_hms_opt(12, 0, 0).unwrap().and_utc())
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        let mut now = self.0.lock().unwrap();
+        *now += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,396 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
+use warp::http::{HeaderMap, HeaderValue, Response, StatusCode};
+use warp::hyper::body::{self, Bytes};
+use warp::hyper::Body;
+use warp::Reply;
+
+/// Header clients set on a request they might retry.
+pub const KEY_HEADER: &str = "idempotency-key";
+
+/// Added to responses that were served from the store rather than by running the request again.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const MAX_KEY_LEN: usize = 255;
+
+// Each entry holds on to a request body of up to 16 KB, so this is around 16 MB at most
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// Remembers the first response sent for each `Idempotency-Key` so that a client retrying
+/// after a timeout gets the same answer back instead of creating a duplicate (or getting a 400
+/// because its first attempt actually went through).
+///
+/// Keys belong to the caller that sent them, so one client can't replay another's response by
+/// guessing its key.  Callers that don't authenticate all share one set of keys.
+///
+/// Entries live for `ttl` and are swept whenever a new key comes in.  There are at most
+/// `max_entries` of them, 1000 unless set, and the oldest is forgotten early to make room for a
+/// new one.  The map is behind a plain std Mutex rather than tokio's since it's never held
+/// across an await.
+pub struct Store {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<Entries>,
+}
+
+// A key as sent by a caller, who's None when they didn't authenticate
+type ScopedKey = (Option<String>, String);
+
+struct Entries {
+    map: HashMap<ScopedKey, Entry>,
+    // Keys in the order they came in, which is also the order they expire in, with when that
+    // is.  Entries removed early are left in here until they reach the front, and only count
+    // if the entry in `map` still has the same expiry.
+    order: VecDeque<(ScopedKey, DateTime<Utc>)>,
+}
+
+struct Entry {
+    request_body: Bytes,
+    expires: DateTime<Utc>,
+    // None while the first request is still being handled
+    response: Option<StoredResponse>,
+}
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    fn replay(&self) -> warp::reply::Response {
+        let mut resp = Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        *resp.headers_mut() = self.headers.clone();
+        resp.headers_mut()
+            .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        resp
+    }
+}
+
+enum Lookup {
+    Fresh,
+    Replay(StoredResponse),
+    InProgress,
+    Mismatch,
+}
+
+impl Store {
+    pub fn new(ttl: Duration) -> Store {
+        Store {
+            ttl,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// The most keys to remember at once.  At least 1.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Store {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// Runs `handler` with the request body, unless this key has been seen before in which case
+    /// the stored response is replayed (same body) or the request is refused with a 422 (different
+    /// body).  A retry that arrives while the first attempt is still running gets a 409.
+    ///
+    /// Requests without a key are passed straight through.  Server errors aren't stored so the
+    /// client can retry those for real.  `identity` is who the caller authenticated as, if
+    /// they did.
+    pub async fn handle<F, Fut, R>(
+        &self,
+        identity: Option<&str>,
+        key: Option<String>,
+        request_body: Bytes,
+        now: DateTime<Utc>,
+        handler: F,
+    ) -> warp::reply::Response
+    where
+        F: FnOnce(Bytes) -> Fut,
+        Fut: Future<Output = R>,
+        R: Reply,
+    {
+        let key = match key {
+            Some(key) => key,
+            None => return handler(request_body).await.into_response(),
+        };
+
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "Idempotency-Key must be between 1 and 255 characters",
+            );
+        }
+
+        let key = (identity.map(str::to_string), key);
+        match self.begin(&key, &request_body, now) {
+            Lookup::Fresh => {}
+            Lookup::Replay(stored) => {
+                log::debug!("    -> replaying response for idempotency key {}", key.1);
+                return stored.replay();
+            }
+            Lookup::InProgress => {
+                return error(
+                    StatusCode::CONFLICT,
+                    "A request with this Idempotency-Key is still in progress",
+                )
+            }
+            Lookup::Mismatch => {
+                return error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Idempotency-Key was already used with a different request body",
+                )
+            }
+        }
+
+        // If this future gets dropped part way through (client hung up) the guard clears the
+        // in-flight marker so the retry isn't stuck with 409s until the key expires.
+        let mut guard = InFlight {
+            store: self,
+            key: &key,
+            finished: false,
+        };
+
+        let resp = handler(request_body).await.into_response();
+        if resp.status().is_server_error() {
+            return resp;
+        }
+
+        let (parts, resp_body) = resp.into_parts();
+        let resp_body = match body::to_bytes(resp_body).await {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                return error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to buffer response",
+                )
+            }
+        };
+        let stored = StoredResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: resp_body.clone(),
+        };
+
+        if let Some(entry) = self.entries.lock().unwrap().map.get_mut(&key) {
+            entry.response = Some(stored);
+        }
+        guard.finished = true;
+
+        Response::from_parts(parts, Body::from(resp_body))
+    }
+
+    fn begin(&self, key: &ScopedKey, request_body: &Bytes, now: DateTime<Utc>) -> Lookup {
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.map.get(key) {
+            if entry.expires > now {
+                if entry.request_body != *request_body {
+                    return Lookup::Mismatch;
+                }
+                return match &entry.response {
+                    Some(stored) => Lookup::Replay(stored.clone()),
+                    None => Lookup::InProgress,
+                };
+            }
+        }
+
+        // Expired entries are at the front, and so are the oldest if there's no room
+        while let Some((_, expires)) = entries.order.front() {
+            if *expires > now && entries.order.len() < self.max_entries {
+                break;
+            }
+            entries.pop_oldest();
+        }
+
+        let expires = now + self.ttl;
+        entries.map.insert(
+            key.clone(),
+            Entry {
+                request_body: request_body.clone(),
+                expires,
+                response: None,
+            },
+        );
+        entries.order.push_back((key.clone(), expires));
+        Lookup::Fresh
+    }
+}
+
+impl Entries {
+    fn pop_oldest(&mut self) {
+        if let Some((key, expires)) = self.order.pop_front() {
+            // Unless it was removed early, and maybe sent again since
+            if self
+                .map
+                .get(&key)
+                .is_some_and(|entry| entry.expires == expires)
+            {
+                self.map.remove(&key);
+            }
+        }
+    }
+}
+
+struct InFlight<'a> {
+    store: &'a Store,
+    key: &'a ScopedKey,
+    finished: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.store.entries.lock().unwrap().map.remove(self.key);
+        }
+    }
+}
+
+fn error(status: StatusCode, message: &'static str) -> warp::reply::Response {
+    warp::reply::with_status(message, status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_retry_gets_conflict_until_first_finishes() {
+        let store = Store::new(Duration::hours(1));
+        let now = Utc::now();
+        let (release, wait) = tokio::sync::oneshot::channel::<()>();
+
+        let first = store.handle(None, Some("k".into()), Bytes::from("{}"), now, |_| async {
+            wait.await.unwrap();
+            StatusCode::CREATED
+        });
+        tokio::pin!(first);
+
+        // Poll the first request once so it registers itself, then leave it parked
+        assert!(futures_poll_once(first.as_mut()).await.is_none());
+
+        let retry = store
+            .handle(None, Some("k".into()), Bytes::from("{}"), now, |_| async {
+                StatusCode::CREATED
+            })
+            .await;
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+
+        release.send(()).unwrap();
+        assert_eq!(first.await.status(), StatusCode::CREATED);
+
+        let replay = store
+            .handle(None, Some("k".into()), Bytes::from("{}"), now, |_| async {
+                StatusCode::BAD_REQUEST
+            })
+            .await;
+        assert_eq!(replay.status(), StatusCode::CREATED);
+        assert_eq!(replay.headers()[REPLAYED_HEADER], "true");
+    }
+
+    #[tokio::test]
+    async fn abandoned_request_frees_its_key() {
+        let store = Store::new(Duration::hours(1));
+        let now = Utc::now();
+
+        {
+            let first = store.handle(None, Some("k".into()), Bytes::from("{}"), now, |_| {
+                std::future::pending::<StatusCode>()
+            });
+            tokio::pin!(first);
+            assert!(futures_poll_once(first.as_mut()).await.is_none());
+        }
+
+        let retry = store
+            .handle(None, Some("k".into()), Bytes::from("{}"), now, |_| async {
+                StatusCode::CREATED
+            })
+            .await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn keys_belong_to_their_caller() {
+        let store = Store::new(Duration::hours(1));
+        let now = Utc::now();
+        let post = |identity, status| {
+            store.handle(
+                identity,
+                Some("k".into()),
+                Bytes::from("{}"),
+                now,
+                move |_| async move { status },
+            )
+        };
+
+        assert_eq!(
+            post(Some("alice"), StatusCode::CREATED).await.status(),
+            StatusCode::CREATED
+        );
+        // Someone else using the same key gets their own request run, not alice's response
+        let bob = post(Some("bob"), StatusCode::ACCEPTED).await;
+        assert_eq!(bob.status(), StatusCode::ACCEPTED);
+        assert!(bob.headers().get(REPLAYED_HEADER).is_none());
+        let anonymous = post(None, StatusCode::OK).await;
+        assert_eq!(anonymous.status(), StatusCode::OK);
+
+        let alice = post(Some("alice"), StatusCode::BAD_REQUEST).await;
+        assert_eq!(alice.status(), StatusCode::CREATED);
+        assert_eq!(alice.headers()[REPLAYED_HEADER], "true");
+    }
+
+    #[tokio::test]
+    async fn forgets_the_oldest_keys_when_full() {
+        let store = Store::new(Duration::hours(1)).with_max_entries(2);
+        let now = Utc::now();
+        let post = |key: &str, status| {
+            store.handle(
+                None,
+                Some(key.into()),
+                Bytes::from("{}"),
+                now,
+                move |_| async move { status },
+            )
+        };
+
+        for key in &["a", "b", "c"] {
+            post(key, StatusCode::CREATED).await;
+        }
+        assert_eq!(store.entries.lock().unwrap().map.len(), 2);
+
+        // "a" made way for "c", so it runs again; "c" is still remembered
+        assert_eq!(
+            post("a", StatusCode::ACCEPTED).await.status(),
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(
+            post("c", StatusCode::ACCEPTED).await.status(),
+            StatusCode::CREATED
+        );
+
+        // Abandoned requests leave nothing behind to grow without bound either
+        for n in 0..10 {
+            let abandoned = store.handle(None, Some(n.to_string()), Bytes::new(), now, |_| {
+                std::future::pending::<StatusCode>()
+            });
+            tokio::pin!(abandoned);
+            assert!(futures_poll_once(abandoned.as_mut()).await.is_none());
+        }
+        let entries = store.entries.lock().unwrap();
+        assert!(entries.map.len() <= 2 && entries.order.len() <= 2);
+    }
+
+    async fn futures_poll_once<F: Future + Unpin>(fut: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            out = fut => Some(out),
+            _ = std::future::ready(()) => None,
+        }
+    }
+}
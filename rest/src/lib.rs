@@ -0,0 +1,1084 @@
+//! The Todo server: the warp filters making up the API and everything behind them.  The binary
+//! in main.rs just wires these up and starts serving.
+
+pub mod clock;
+pub mod idempotency;
+mod negotiate;
+pub mod search;
+pub mod server;
+pub mod snapshot;
+pub mod telemetry;
+pub mod tls;
+
+pub mod filters {
+    use super::clock::SharedClock;
+    use super::handlers;
+    use super::idempotency;
+    use super::models::{Db, ListOptions, SearchOptions, Todo};
+    use super::snapshot::Snapshotter;
+    use super::telemetry::{self, route};
+    use std::sync::Arc;
+    use warp::{Filter, Rejection};
+
+    #[derive(Debug)]
+    enum CustomStatusCode {
+        NotAuthorized,
+    }
+
+    impl warp::reject::Reject for CustomStatusCode {}
+
+    /// The 5 TODOs filters combined.
+    pub fn todos(
+        db: Db,
+        clock: SharedClock,
+        idempotency_keys: Arc<idempotency::Store>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        todos_list(db.clone())
+            .or(todos_search(db.clone()))
+            .or(todos_create(db.clone(), clock.clone(), idempotency_keys))
+            .or(todos_update(db.clone(), clock))
+            .or(todos_delete(db))
+            .recover(handle_rejection)
+    }
+
+    /// GET /todos?offset=3&limit=5
+    pub fn todos_list(
+        db: Db,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("todos")
+            .and(warp::get())
+            .and(route("GET /todos"))
+            .and(warp::query::<ListOptions>())
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .and(with_db(db))
+            .and_then(handlers::list_todos)
+    }
+
+    /// GET /todos/search?q=rotate+on&limit=10
+    pub fn todos_search(
+        db: Db,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("todos" / "search")
+            .and(warp::get())
+            .and(route("GET /todos/search"))
+            .and(warp::query::<SearchOptions>())
+            .and(with_db(db))
+            .and_then(handlers::search_todos)
+    }
+
+    /// POST /todos with JSON body
+    pub fn todos_create(
+        db: Db,
+        clock: SharedClock,
+        idempotency_keys: Arc<idempotency::Store>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        // Unlike the other routes this takes the raw body rather than json_body(), since replaying
+        // a retried request means comparing it byte for byte with the original.
+        warp::path!("todos")
+            .and(warp::post())
+            .and(route("POST /todos"))
+            .and(optional_authn())
+            .and(warp::header::optional::<String>(idempotency::KEY_HEADER))
+            .and(warp::body::content_length_limit(1024 * 16))
+            .and(warp::body::bytes())
+            .and(with_db(db))
+            .and(with_clock(clock))
+            .and(warp::any().map(move || idempotency_keys.clone()))
+            .and_then(handlers::create_todo)
+    }
+
+    /// PUT /todos/:id with JSON body
+    pub fn todos_update(
+        db: Db,
+        clock: SharedClock,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("todos" / u64)
+            .and(warp::put())
+            .and(route("PUT /todos/:id"))
+            .and(json_body())
+            .and(with_db(db))
+            .and(with_clock(clock))
+            .and_then(handlers::update_todo)
+    }
+
+    /// DELETE /todos/:id
+    pub fn todos_delete(
+        db: Db,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        // We'll make one of our endpoints admin-only to show how authentication filters are used.
+        // But this is a piss poor way of doing the auth
+        let _admin_only = warp::header::exact("Authorization", "Bearer admin");
+
+        warp::path!("todos" / u64)
+            // As below, it's important that we realize the filters are processed sequentially;
+            // if this is below the admin filter then it will match any path that is todos/:id
+            // regardless of the method
+            .and(warp::delete())
+            .and(route("DELETE /todos/:id"))
+            // It is important to put the auth check _after_ the path filters.
+            // If we put the auth check before, the request `PUT /todos/invalid-string`
+            // would try this filter and reject because the authorization header doesn't match,
+            // rather because the param is wrong for that other path.
+            //.and(admin_only)
+            .and(authn())
+            .and(with_db(db))
+            .and_then(handlers::delete_todo)
+    }
+
+    /// The admin routes, which are only there when the server has somewhere to keep snapshots.
+    /// Kept apart from `todos` so the server can leave them out.
+    pub fn admin(
+        db: Db,
+        clock: SharedClock,
+        snapshots: Arc<Snapshotter>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        admin_snapshot(db, clock, snapshots).recover(handle_rejection)
+    }
+
+    /// POST /admin/snapshot
+    pub fn admin_snapshot(
+        db: Db,
+        clock: SharedClock,
+        snapshots: Arc<Snapshotter>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "snapshot")
+            .and(warp::post())
+            .and(route("POST /admin/snapshot"))
+            .and(authn())
+            .and(with_db(db))
+            .and(with_clock(clock))
+            .and(warp::any().map(move || snapshots.clone()))
+            .and_then(handlers::take_snapshot)
+    }
+
+    // Here's an actual auth filter; it grabs the auth header and checks the token, with the ability to bail
+    // out early if we error
+    fn authn() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
+        warp::header::header("Authorization")
+            .and_then(check_token)
+    }
+
+    // For routes anyone can use, but that tell callers apart when they do authenticate.  A bad
+    // token is still a 401, rather than quietly treated as no token at all.
+    fn optional_authn() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Copy {
+        warp::header::optional::<String>("Authorization")
+            .and_then(check_optional_token)
+    }
+
+    async fn check_optional_token(token: Option<String>) -> Result<Option<String>, Rejection> {
+        match token {
+            Some(token) => check_token(token).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Simple token checker, token must be "my-token"
+    async fn check_token(token: String) -> Result<String, Rejection> {
+        if token == "my-token" {
+            // in a real program we'd look up who the token belongs to; it's also what goes in the request's
+            // trace, since logging the token itself would be handing it out to anyone who can read the logs
+            let identity = String::from("demo-user");
+            telemetry::record_identity(&identity);
+            Ok(identity)
+        } else {
+            //return Err(warp::reject::reject()); // a 404 rejection here aborts the filter processing and causes it to look
+                                                // for other filters
+            Err(warp::reject::custom(CustomStatusCode::NotAuthorized)) // instead we have a custom reject and warp
+                                                                              // will dispatch to a default recover filter
+                                                                              // which turns this into a 500 with the message
+                                                                              // from the Debug impl.  To get proper handling
+                                                                              // of this error we have our own recover filter,
+                                                                              // handle_rejection below
+        }
+    }
+
+    // Recover filter for our custom rejections, so a bad token is a 401 that clients can act on
+    // rather than a 500.  Anything else gets passed along to warp's default handling.
+    async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+        if let Some(CustomStatusCode::NotAuthorized) = err.find() {
+            return Ok(warp::reply::with_status(
+                "Not authorized",
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+
+        Err(err)
+    }
+
+    // This is a mechanism to ensure a refernece to the database is part of the filter chain.
+    // SInce Db is an Arc<RwLock<TheActualDb>> we can cheaply clone it and pass it down and everyone 
+    // will have access to it.  If we had a fancier DB like a struct that interacted with a mysql
+    // database we might instead just have an Arc<DbClient> with no lock required.
+    fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || db.clone())
+    }
+
+    // Same trick for the clock, which lets tests swap in one they control
+    fn with_clock(clock: SharedClock) -> impl Filter<Extract = (SharedClock,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || clock.clone())
+    }
+
+    fn json_body() -> impl Filter<Extract = (Todo,), Error = warp::Rejection> + Clone {
+        // When accepting a body, we want a JSON body
+        // (and to reject huge payloads)...
+        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    }
+}
+
+/// These are our API handlers, the ends of each filter chain.
+/// Notice how thanks to using `Filter::and`, we can define a function
+/// with the exact arguments we'd expect from each filter in the chain.
+/// No tuples are needed, it's auto flattened for the functions.
+mod handlers {
+    use super::clock::SharedClock;
+    use super::idempotency;
+    use super::models::{Db, ListOptions, SearchOptions, Todo};
+    use super::negotiate::{self, Encoding, Representation};
+    use super::snapshot::Snapshotter;
+    use std::convert::Infallible;
+    use std::error::Error;
+    use std::sync::Arc;
+    use warp::http::{header, HeaderValue, StatusCode};
+    use warp::hyper::body::Bytes;
+    use warp::Reply;
+
+    pub async fn list_todos(
+        opts: ListOptions,
+        accept: Option<String>,
+        accept_encoding: Option<String>,
+        db: Db,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let repr = match Representation::from_accept(accept.as_deref()) {
+            Some(repr) => repr,
+            None => {
+                log::debug!("    -> no acceptable representation for {:?}", accept);
+                return Ok(warp::reply::with_status(
+                    "Supported types are application/json, text/csv and application/msgpack",
+                    StatusCode::NOT_ACCEPTABLE,
+                )
+                .into_response());
+            }
+        };
+
+        // Return an array of todos, applying the limit and offset.  They're copied out so the
+        // lock is let go before the slow part, encoding and compressing.
+        let todos: Vec<Todo> = db
+            .read()
+            .await
+            .iter()
+            .skip(opts.offset.unwrap_or(0))
+            .take(opts.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+
+        let encoding = Encoding::from_accept_encoding(accept_encoding.as_deref());
+        let body = match encode_todos(&todos, repr) {
+            // Small bodies aren't worth compressing whatever the client says
+            Ok(body) if body.len() < negotiate::MIN_COMPRESS_LEN => Ok((body, Encoding::Identity)),
+            Ok(body) => encoding.encode(body).map(|body| (body, encoding)).map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        let (body, encoding) = match body {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                log::error!(target: "todos", "list_todos: failed to encode response: {}", e);
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        };
+
+        let mut resp = warp::reply::Response::new(body.into());
+        let headers = resp.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(repr.content_type()));
+        // Caches have to know the same URL can come back in different shapes
+        headers.insert(header::VARY, HeaderValue::from_static("Accept, Accept-Encoding"));
+        if let Some(coding) = encoding.header_value() {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(coding));
+        }
+        Ok(resp)
+    }
+
+    fn encode_todos(todos: &[Todo], repr: Representation) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(match repr {
+            Representation::Json => serde_json::to_vec(todos)?,
+            // Named fields, since the optional ones are skipped when empty and a positional
+            // encoding would shift everything after them
+            Representation::MessagePack => rmp_serde::to_vec_named(todos)?,
+            Representation::Csv => {
+                // Written by hand rather than through serde for the same reason: every row needs
+                // every column, empty or not.
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                writer.write_record(["id", "text", "completed", "due", "recurrence"])?;
+                for todo in todos {
+                    writer.write_record(&[
+                        todo.id.to_string(),
+                        todo.text.clone(),
+                        todo.completed.to_string(),
+                        todo.due.map(|due| due.to_string()).unwrap_or_default(),
+                        todo.recurrence.as_ref().map(|r| r.to_string()).unwrap_or_default(),
+                    ])?;
+                }
+                writer.into_inner().map_err(|e| e.into_error())?
+            }
+        })
+    }
+
+    pub async fn create_todo(
+        identity: Option<String>,
+        idempotency_key: Option<String>,
+        body: Bytes,
+        db: Db,
+        clock: SharedClock,
+        idempotency_keys: Arc<idempotency::Store>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let resp = idempotency_keys
+            .handle(identity.as_deref(), idempotency_key, body, clock.now(), |body| async move {
+                // Same shape of error warp::body::json() would have given us
+                match serde_json::from_slice(&body) {
+                    Ok(create) => insert_todo(create, db).await,
+                    Err(e) => warp::reply::with_status(
+                        format!("Request body deserialize error: {}", e),
+                        StatusCode::BAD_REQUEST,
+                    )
+                    .into_response(),
+                }
+            })
+            .await;
+
+        Ok(resp)
+    }
+
+    pub async fn search_todos(opts: SearchOptions, db: Db) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("search_todos: {:?}", opts);
+
+        if opts.q.trim().is_empty() {
+            return Ok(warp::reply::with_status(
+                "The q parameter needs at least one word to search for",
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response());
+        }
+
+        let store = db.read().await;
+        let found: Vec<&Todo> = store
+            .search(&opts.q)
+            .into_iter()
+            .take(opts.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(warp::reply::json(&found).into_response())
+    }
+
+    async fn insert_todo(create: Todo, db: Db) -> warp::reply::Response {
+        log::debug!("create_todo: {:?}", create);
+
+        let mut store = db.write().await;
+
+        if let Err(create) = store.insert(create) {
+            log::debug!("    -> id already exists: {}", create.id);
+            // Todo with id already exists, return `400 BadRequest`.
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+
+        // No existing Todo with id, so it went in; return `201 Created`.
+        StatusCode::CREATED.into_response()
+    }
+
+    pub async fn update_todo(
+        id: u64,
+        update: Todo,
+        db: Db,
+        clock: SharedClock,
+    ) -> Result<Box<dyn warp::Reply>, Infallible> {
+        log::debug!("update_todo: id={}, todo={:?}", id, update);
+        let mut store = db.write().await;
+
+        // Look for the specified Todo...
+        let was_completed = match store.get(id) {
+            Some(todo) => todo.completed,
+            None => {
+                log::debug!("    -> todo id not found!");
+                return Ok(Box::new(StatusCode::NOT_FOUND));
+            }
+        };
+
+        // Completing a recurring Todo spawns its next occurrence, which goes back to the client
+        // as the body so it knows the new id without having to go list everything.
+        let next = if !was_completed && update.completed {
+            next_occurrence(&update, store.max_id() + 1, clock.today())
+        } else {
+            None
+        };
+
+        store.replace(id, update);
+
+        if let Some(next) = next {
+            log::debug!("    -> scheduled next occurrence: {:?}", next);
+            let reply = warp::reply::json(&next);
+            // Can't clash, the id is past everything in the store
+            let _ = store.insert(next);
+            return Ok(Box::new(reply));
+        }
+
+        Ok(Box::new(StatusCode::OK))
+    }
+
+    // The follow up Todo for a completed recurring one, if its rule has any occurrences left.
+    // Ids are picked by the client on create, so spawned ones just go after the biggest id in use.
+    fn next_occurrence(done: &Todo, id: u64, today: chrono::NaiveDate) -> Option<Todo> {
+        let rule = done.recurrence.as_ref()?;
+        let (due, recurrence) = rule.advance(done.due.unwrap_or(today), today)?;
+
+        Some(Todo {
+            id,
+            text: done.text.clone(),
+            completed: false,
+            due: Some(due),
+            recurrence: Some(recurrence),
+        })
+    }
+
+    pub async fn take_snapshot(
+        _identity: String,
+        db: Db,
+        clock: SharedClock,
+        snapshots: Arc<Snapshotter>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("take_snapshot: path={}", snapshots.path().display());
+
+        match snapshots.take(&db, clock.now()).await {
+            Ok(count) => Ok(warp::reply::json(&serde_json::json!({
+                "path": snapshots.path(),
+                "todos": count,
+            }))
+            .into_response()),
+            Err(e) => {
+                log::error!(target: "todos", "take_snapshot: {}", e);
+                Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+            }
+        }
+    }
+
+    pub async fn delete_todo(id: u64, _identity: String, db: Db) -> Result<impl warp::Reply, Infallible> {
+        log::debug!("delete_todo: id={}", id);
+
+        let mut store = db.write().await;
+
+        // If there was a Todo to remove, we found and deleted it!
+        let deleted = store.remove(id).is_some();
+
+        if deleted {
+            // respond with a `204 No Content`, which means successful,
+            // yet no body expected...
+            Ok(StatusCode::NO_CONTENT)
+        } else {
+            log::debug!("    -> todo id not found!");
+            Ok(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+pub mod models {
+    use super::search::SearchIndex;
+    use serde_derive::Deserialize;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    pub use todo_api::models::{ListOptions, Todo};
+
+    /// So we don't have to tackle how different database work, we'll just use
+    /// a simple in-memory DB, a TodoStore behind a read/write lock.  Listing and searching only
+    /// need a read lock, so they run side by side and only wait on writers.
+    pub type Db = Arc<RwLock<TodoStore>>;
+
+    pub fn blank_db() -> Db {
+        Arc::new(RwLock::new(TodoStore::new()))
+    }
+
+    /// The Todos keyed by id and kept in the order they were created, plus the search index over
+    /// their text.  All changes go through here so the index can't fall out of step with the
+    /// Todos.
+    #[derive(Debug, Default)]
+    pub struct TodoStore {
+        todos: HashMap<u64, Entry>,
+        // Creation order as sequence number -> id, so removing one doesn't shuffle the rest
+        order: BTreeMap<u64, u64>,
+        next_seq: u64,
+        max_id: u64,
+        index: SearchIndex,
+    }
+
+    #[derive(Debug)]
+    struct Entry {
+        seq: u64,
+        todo: Todo,
+    }
+
+    impl TodoStore {
+        pub fn new() -> TodoStore {
+            TodoStore::default()
+        }
+
+        pub fn len(&self) -> usize {
+            self.todos.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.todos.is_empty()
+        }
+
+        /// The Todos in the order they were created.
+        pub fn iter(&self) -> impl Iterator<Item = &Todo> {
+            self.order.values().map(move |id| &self.todos[id].todo)
+        }
+
+        pub fn get(&self, id: u64) -> Option<&Todo> {
+            self.todos.get(&id).map(|entry| &entry.todo)
+        }
+
+        /// The biggest id that's been used, 0 when nothing has been added yet.  Deleted Todos
+        /// still count, which keeps this O(1) and means an id is never handed out twice.
+        pub fn max_id(&self) -> u64 {
+            self.max_id
+        }
+
+        /// Makes sure `max_id` is at least `id`, for picking up where a snapshot left off.
+        pub fn reserve_ids(&mut self, id: u64) {
+            self.max_id = self.max_id.max(id);
+        }
+
+        /// Adds a new Todo, handing it back if its id is already taken.
+        pub fn insert(&mut self, todo: Todo) -> Result<(), Todo> {
+            if self.todos.contains_key(&todo.id) {
+                return Err(todo);
+            }
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            self.max_id = self.max_id.max(todo.id);
+            self.index.insert(todo.id, &todo.text);
+            self.order.insert(seq, todo.id);
+            self.todos.insert(todo.id, Entry { seq, todo });
+            Ok(())
+        }
+
+        /// Overwrites the Todo with `id`, returning the old one or None if there wasn't one.
+        /// The id stays put whatever the new Todo claims, otherwise it could end up clashing with
+        /// another one.
+        pub fn replace(&mut self, id: u64, mut todo: Todo) -> Option<Todo> {
+            let entry = self.todos.get_mut(&id)?;
+            todo.id = id;
+            self.index.insert(id, &todo.text);
+            Some(std::mem::replace(&mut entry.todo, todo))
+        }
+
+        pub fn remove(&mut self, id: u64) -> Option<Todo> {
+            let entry = self.todos.remove(&id)?;
+            self.order.remove(&entry.seq);
+            self.index.remove(id);
+            Some(entry.todo)
+        }
+
+        /// Todos matching a full text query, best match first.
+        pub fn search(&self, query: &str) -> Vec<&Todo> {
+            self.index
+                .search(query)
+                .into_iter()
+                .filter_map(|id| self.get(id))
+                .collect()
+        }
+    }
+
+    // The query parameters for search_todos.
+    #[derive(Debug, Deserialize)]
+    pub struct SearchOptions {
+        pub q: String,
+        pub limit: Option<usize>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+    use warp::test::request;
+
+    use super::{
+        clock::{self, ManualClock},
+        filters, idempotency,
+        models::{self, Todo},
+        snapshot::Snapshotter,
+        telemetry,
+    };
+    use chrono::NaiveDate;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_post() {
+        let db = models::blank_db();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .method("POST")
+            .path("/todos")
+            .json(&todo1())
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_post_conflict() {
+        let db = models::blank_db();
+        db.write().await.insert(todo1()).unwrap();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .method("POST")
+            .path("/todos")
+            .json(&todo1())
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_put_unknown() {
+        telemetry::try_init();
+        let db = models::blank_db();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .method("PUT")
+            .path("/todos/1")
+            .header("authorization", "Bearer admin")
+            .json(&todo1())
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_complete_recurring_spawns_next() {
+        let db = models::blank_db();
+        let mut chore = todo1();
+        chore.due = NaiveDate::from_ymd_opt(2021, 3, 1);
+        chore.recurrence = Some("weekly".parse().unwrap());
+        db.write().await.insert(chore.clone()).unwrap();
+        // Completed a couple of days late, the next one still lands on the schedule
+        let api = filters::todos(db.clone(), Arc::new(ManualClock::at_date(2021, 3, 3)), idempotency_keys());
+
+        chore.completed = true;
+        let resp = request()
+            .method("PUT")
+            .path("/todos/1")
+            .json(&chore)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let next: Todo = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(next.id, 2);
+        assert!(!next.completed);
+        assert_eq!(next.due, NaiveDate::from_ymd_opt(2021, 3, 8));
+        assert_eq!(db.read().await.len(), 2);
+
+        // Saving it again while already completed mustn't spawn another one
+        let resp = request()
+            .method("PUT")
+            .path("/todos/1")
+            .json(&chore)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.body().is_empty());
+        assert_eq!(db.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_complete_undated_recurring_uses_clock() {
+        let db = models::blank_db();
+        let mut chore = todo1();
+        chore.recurrence = Some("FREQ=DAILY;INTERVAL=2".parse().unwrap());
+        db.write().await.insert(chore.clone()).unwrap();
+        let api = filters::todos(db, Arc::new(ManualClock::at_date(2021, 3, 3)), idempotency_keys());
+
+        chore.completed = true;
+        let resp = request()
+            .method("PUT")
+            .path("/todos/1")
+            .json(&chore)
+            .reply(&api)
+            .await;
+
+        let next: Todo = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(next.due, NaiveDate::from_ymd_opt(2021, 3, 5));
+        assert_eq!(next.recurrence, chore.recurrence);
+    }
+
+    #[tokio::test]
+    async fn test_complete_recurring_past_the_calendar() {
+        let db = models::blank_db();
+        let mut chore = todo1();
+        chore.recurrence = Some("FREQ=DAILY;INTERVAL=4294967295".parse().unwrap());
+        db.write().await.insert(chore.clone()).unwrap();
+        let api = filters::todos(
+            db.clone(),
+            Arc::new(ManualClock::at_date(2021, 3, 3)),
+            idempotency_keys(),
+        );
+
+        // The next one would be past the last date there is, so there's no next one
+        chore.completed = true;
+        let resp = request()
+            .method("PUT")
+            .path("/todos/1")
+            .json(&chore)
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.body().is_empty());
+        assert_eq!(db.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_post_bad_recurrence() {
+        let db = models::blank_db();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .method("POST")
+            .path("/todos")
+            .json(&serde_json::json!({
+                "id": 1,
+                "text": "test 1",
+                "completed": false,
+                "recurrence": "FREQ=YEARLY"
+            }))
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_post_idempotent_replay() {
+        let db = models::blank_db();
+        let api = filters::todos(db.clone(), clock::system_clock(), idempotency_keys());
+
+        let first = request()
+            .method("POST")
+            .path("/todos")
+            .header("Idempotency-Key", "abc")
+            .json(&todo1())
+            .reply(&api)
+            .await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(first.headers().get(idempotency::REPLAYED_HEADER).is_none());
+
+        // Without the key this retry would be a 400 since the Todo already exists
+        let retry = request()
+            .method("POST")
+            .path("/todos")
+            .header("Idempotency-Key", "abc")
+            .json(&todo1())
+            .reply(&api)
+            .await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()[idempotency::REPLAYED_HEADER], "true");
+        assert_eq!(db.read().await.len(), 1);
+
+        let mut other = todo1();
+        other.text = "something else".into();
+        let reused = request()
+            .method("POST")
+            .path("/todos")
+            .header("Idempotency-Key", "abc")
+            .json(&other)
+            .reply(&api)
+            .await;
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn test_post_idempotency_key_expires() {
+        let db = models::blank_db();
+        let clock = Arc::new(ManualClock::at_date(2021, 3, 1));
+        let api = filters::todos(db, clock.clone(), idempotency_keys());
+
+        let post = || {
+            request()
+                .method("POST")
+                .path("/todos")
+                .header("Idempotency-Key", "abc")
+                .json(&todo1())
+        };
+
+        assert_eq!(post().reply(&api).await.status(), StatusCode::CREATED);
+        clock.advance(chrono::Duration::hours(25));
+        // Once the key is forgotten the request really runs again and hits the conflict
+        assert_eq!(post().reply(&api).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_list_compressed() {
+        let db = models::blank_db();
+        {
+            let mut store = db.write().await;
+            for id in 1..=100 {
+                store.insert(Todo { id, ..todo1() }).unwrap();
+            }
+        }
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .path("/todos")
+            .header("accept-encoding", "gzip")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(resp.body().as_ref())
+            .read_to_string(&mut json)
+            .unwrap();
+        let todos: Vec<Todo> = serde_json::from_str(&json).unwrap();
+        assert_eq!(todos.len(), 100);
+
+        let resp = request()
+            .path("/todos")
+            .header("accept-encoding", "gzip, br")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.headers()["content-encoding"], "br");
+        let mut json = String::new();
+        brotli::Decompressor::new(resp.body().as_ref(), 4096)
+            .read_to_string(&mut json)
+            .unwrap();
+        let todos: Vec<Todo> = serde_json::from_str(&json).unwrap();
+        assert_eq!(todos.len(), 100);
+
+        // A short page isn't worth compressing
+        let resp = request()
+            .path("/todos?limit=2")
+            .header("accept-encoding", "gzip")
+            .reply(&api)
+            .await;
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.headers()["vary"], "Accept, Accept-Encoding");
+    }
+
+    #[tokio::test]
+    async fn test_list_alternative_representations() {
+        let db = models::blank_db();
+        let mut chore = todo1();
+        chore.text = "take out, the trash".into();
+        chore.recurrence = Some("weekly".parse().unwrap());
+        db.write().await.insert(chore).unwrap();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .path("/todos")
+            .header("accept", "text/csv")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.headers()["content-type"], "text/csv; charset=utf-8");
+        assert_eq!(
+            resp.body().as_ref(),
+            b"id,text,completed,due,recurrence\n1,\"take out, the trash\",false,,weekly\n"
+        );
+
+        let resp = request()
+            .path("/todos")
+            .header("accept", "application/json;q=0.5, application/msgpack")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.headers()["content-type"], "application/msgpack");
+        let todos: Vec<Todo> = rmp_serde::from_slice(resp.body()).unwrap();
+        assert_eq!(todos[0].text, "take out, the trash");
+
+        let resp = request()
+            .path("/todos")
+            .header("accept", "text/html")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    async fn test_delete_bad_token() {
+        let db = models::blank_db();
+        db.write().await.insert(todo1()).unwrap();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        let resp = request()
+            .method("DELETE")
+            .path("/todos/1")
+            .header("authorization", "not-my-token")
+            .reply(&api)
+            .await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_search_follows_changes() {
+        let db = models::blank_db();
+        let api = filters::todos(db, clock::system_clock(), idempotency_keys());
+
+        for (id, text) in [(1, "rotate on-call"), (2, "rotate logs"), (3, "call mum")] {
+            let resp = request()
+                .method("POST")
+                .path("/todos")
+                .json(&Todo {
+                    id,
+                    text: text.into(),
+                    ..todo1()
+                })
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let search = |q: &str| request().path(&format!("/todos/search?q={}", q));
+        let ids = |body: &[u8]| -> Vec<u64> {
+            let todos: Vec<Todo> = serde_json::from_slice(body).unwrap();
+            todos.into_iter().map(|todo| todo.id).collect()
+        };
+
+        let resp = search("rot%20on").reply(&api).await;
+        assert_eq!(ids(resp.body()), vec![1]);
+        let resp = search("call").reply(&api).await;
+        assert_eq!(ids(resp.body()), vec![3, 1]);
+
+        // Renaming and deleting show up in the next search
+        let resp = request()
+            .method("PUT")
+            .path("/todos/2")
+            .json(&Todo {
+                id: 2,
+                text: "call plumber".into(),
+                ..todo1()
+            })
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = request()
+            .method("DELETE")
+            .path("/todos/3")
+            .header("authorization", "my-token")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let resp = search("call&limit=5").reply(&api).await;
+        assert_eq!(ids(resp.body()), vec![2, 1]);
+        assert!(ids(search("logs").reply(&api).await.body()).is_empty());
+
+        let resp = search("%20").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_reads_and_writes() {
+        let db = models::blank_db();
+        let api = filters::todos(db.clone(), clock::system_clock(), idempotency_keys());
+
+        let mut tasks = Vec::new();
+        for id in 1..=200 {
+            let api = api.clone();
+            tasks.push(tokio::spawn(async move {
+                let resp = request()
+                    .method("POST")
+                    .path("/todos")
+                    .json(&Todo { id, ..todo1() })
+                    .reply(&api)
+                    .await;
+                assert_eq!(resp.status(), StatusCode::CREATED);
+
+                let resp = request().path("/todos").reply(&api).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+                // Our own create has to be visible to the read that follows it
+                let todos: Vec<Todo> = serde_json::from_slice(resp.body()).unwrap();
+                assert!(todos.iter().any(|todo| todo.id == id));
+
+                if id % 2 == 0 {
+                    let resp = request()
+                        .method("DELETE")
+                        .path(&format!("/todos/{}", id))
+                        .header("authorization", "my-token")
+                        .reply(&api)
+                        .await;
+                    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let store = db.read().await;
+        assert_eq!(store.len(), 100);
+        assert!(store.iter().all(|todo| todo.id % 2 == 1));
+        assert_eq!(store.max_id(), 200);
+        assert_eq!(store.search("test").len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_admin_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = Arc::new(Snapshotter::new(dir.path().join("todos.json")));
+        let db = models::blank_db();
+        db.write().await.insert(todo1()).unwrap();
+        let api = filters::admin(db, clock::system_clock(), snapshots.clone());
+
+        let snapshot = || request().method("POST").path("/admin/snapshot");
+        assert_eq!(snapshot().reply(&api).await.status(), StatusCode::BAD_REQUEST);
+        let resp = snapshot().header("authorization", "nope").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(snapshots.restore().unwrap().is_none());
+
+        let resp = snapshot().header("authorization", "my-token").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["todos"], 1);
+        assert_eq!(snapshots.restore().unwrap().unwrap().get(1), Some(&todo1()));
+    }
+
+    #[test]
+    fn test_store_keeps_creation_order() {
+        let mut store = models::TodoStore::new();
+        for id in [5, 2, 9, 7] {
+            store.insert(Todo { id, ..todo1() }).unwrap();
+        }
+        assert!(store.insert(Todo { id: 9, ..todo1() }).is_err());
+
+        assert_eq!(store.remove(2).map(|todo| todo.id), Some(2));
+        assert!(store.remove(2).is_none());
+        store.replace(9, Todo { id: 100, text: "renamed".into(), ..todo1() });
+        store.insert(Todo { id: 1, ..todo1() }).unwrap();
+
+        let ids: Vec<u64> = store.iter().map(|todo| todo.id).collect();
+        assert_eq!(ids, vec![5, 9, 7, 1]);
+        assert_eq!(store.get(9).unwrap().text, "renamed");
+        assert!(store.get(100).is_none());
+        assert_eq!(store.max_id(), 9);
+    }
+
+    fn idempotency_keys() -> Arc<idempotency::Store> {
+        Arc::new(idempotency::Store::new(chrono::Duration::hours(24)))
+    }
+
+    fn todo1() -> Todo {
+        Todo {
+            id: 1,
+            text: "test 1".into(),
+            completed: false,
+            due: None,
+            recurrence: None,
+        }
+    }
+}
\ No newline at end of file
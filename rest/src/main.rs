@@ -1,328 +1,217 @@
+use clap::Parser;
+use std::convert::Infallible;
 use std::env;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use warp::http::{Request, Response};
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::Body;
 use warp::Filter;
 
+use rest::snapshot::Snapshotter;
+use rest::tls::{self, CertResolver, TlsConfig};
+use rest::{clock, filters, idempotency, models, server, telemetry};
+
 /// Provides a RESTful web server managing some Todos.
 ///
 /// API will be:
 ///
-/// - `GET /todos`: return a JSON list of Todos.
-/// - `POST /todos`: create a new Todo.
-/// - `PUT /todos/:id`: update a specific Todo.
+/// - `GET /todos`: return a list of Todos as JSON, CSV or MessagePack depending on `Accept`,
+///   compressed when the client allows and the list is big enough to be worth it.
+/// - `GET /todos/search?q=`: full text search over the Todos' text, best matches first.
+/// - `POST /todos`: create a new Todo; send an `Idempotency-Key` header to make retries safe.
+/// - `PUT /todos/:id`: update a specific Todo; completing a recurring Todo schedules the next one.
 /// - `DELETE /todos/:id`: delete a specific Todo.
+/// - `POST /admin/snapshot`: save a snapshot now (needs a token and `--snapshot`).
+///
+/// Every response carries an `X-Request-Id` (the caller's, if it sent one) which also tags all the
+/// log lines for that request.  Set `LOG_FORMAT=json` for JSON logs.
+///
+/// Pass `--tls-cert` and `--tls-key` (or set `TLS_CERT` and `TLS_KEY`) to serve HTTPS instead of
+/// plain HTTP; sending the process a SIGHUP makes it pick up renewed files.  The `todo` CLI can
+/// talk to it over `https://` as long as the certificate chains to a public CA.
+///
+/// With `--snapshot <file>` the Todos are restored from that file at startup and saved back to it
+/// every few minutes, on `POST /admin/snapshot` and on the way out, so they survive a restart.
+#[derive(Debug, Parser)]
+#[command(name = "rest")]
+struct Args {
+    /// PEM certificate chain, to serve HTTPS
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for the certificate
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Also take plain HTTP on this port, redirecting it all to HTTPS
+    #[arg(long, env = "HTTP_REDIRECT_PORT", requires = "tls_cert")]
+    http_redirect_port: Option<u16>,
+
+    /// File to restore the Todos from at startup and save snapshots to
+    #[arg(long, env = "SNAPSHOT_PATH")]
+    snapshot: Option<PathBuf>,
+
+    /// Seconds between automatic snapshots, 0 to only take them on request and at shutdown
+    #[arg(long, env = "SNAPSHOT_EVERY", default_value_t = 300)]
+    snapshot_every: u64,
+}
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
     if env::var_os("RUST_LOG").is_none() {
         // Set `RUST_LOG=todos=debug` to see debug logs,
         // this only shows access logs.
         env::set_var("RUST_LOG", "todos=info");
     }
-    pretty_env_logger::init();
-
-    let db = models::blank_db();
-
-    let api = filters::todos(db);
-
-    // View access logs by setting `RUST_LOG=todos`.
-    let routes = api.with(warp::log("todos"));
-    // Start up the server...
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
-
-mod filters {
-    use super::handlers;
-    use super::models::{Db, ListOptions, Todo};
-    use warp::{Filter, Rejection};
-
-    #[derive(Debug)]
-    enum CustomStatusCode {
-        NotAuthorized,
-    }
-
-    impl warp::reject::Reject for CustomStatusCode {}
-
-    /// The 4 TODOs filters combined.
-    pub fn todos(
-        db: Db,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        todos_list(db.clone())
-            .or(todos_create(db.clone()))
-            .or(todos_update(db.clone()))
-            .or(todos_delete(db))
-    }
+    telemetry::init(env::var("LOG_FORMAT").is_ok_and(|format| format == "json"));
 
-    /// GET /todos?offset=3&limit=5
-    pub fn todos_list(
-        db: Db,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos")
-            .and(warp::get())
-            .and(warp::query::<ListOptions>())
-            .and(with_db(db))
-            .and_then(handlers::list_todos)
-    }
-
-    /// POST /todos with JSON body
-    pub fn todos_create(
-        db: Db,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos")
-            .and(warp::post())
-            .and(json_body())
-            .and(with_db(db))
-            .and_then(handlers::create_todo)
-    }
+    let clock = clock::system_clock();
+    let idempotency_keys = Arc::new(idempotency::Store::new(chrono::Duration::hours(24)));
 
-    /// PUT /todos/:id with JSON body
-    pub fn todos_update(
-        db: Db,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        warp::path!("todos" / u64)
-            .and(warp::put())
-            .and(json_body())
-            .and(with_db(db))
-            .and_then(handlers::update_todo)
-    }
-
-    /// DELETE /todos/:id
-    pub fn todos_delete(
-        db: Db,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        // We'll make one of our endpoints admin-only to show how authentication filters are used.
-        // But this is a piss poor way of doing the auth
-        let _admin_only = warp::header::exact("Authorization", "Bearer admin");
-
-        warp::path!("todos" / u64)
-            // As below, it's important that we realize the filters are processed sequentially;
-            // if this is below the admin filter then it will match any path that is todos/:id
-            // regardless of the method
-            .and(warp::delete())
-            // It is important to put the auth check _after_ the path filters.
-            // If we put the auth check before, the request `PUT /todos/invalid-string`
-            // would try this filter and reject because the authorization header doesn't match,
-            // rather because the param is wrong for that other path.
-            //.and(admin_only)
-            .and(authn())
-            .and(with_db(db))
-            .and_then(handlers::delete_todo)
-    }
-
-    // Here's an actual auth filter; it grabs the auth header and checks the token, with the ability to bail
-    // out early if we error
-    fn authn() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
-        warp::header::header("Authorization")
-            .and_then(check_token)
-    }
+    let snapshots = args.snapshot.map(|path| Arc::new(Snapshotter::new(path)));
+    let db = match &snapshots {
+        Some(snapshots) => match snapshots.restore() {
+            Ok(Some(store)) => {
+                log::info!(target: "todos", "restored {} todos from {}", store.len(), snapshots.path().display());
+                Arc::new(RwLock::new(store))
+            }
+            Ok(None) => models::blank_db(),
+            // Starting empty would overwrite the snapshot with nothing at the next save
+            Err(e) => fail(format!(
+                "can't restore {}: {}",
+                snapshots.path().display(),
+                e
+            )),
+        },
+        None => models::blank_db(),
+    };
 
-    // Simple token checker, token must be "my-token"
-    async fn check_token(token: String) -> Result<String, Rejection> {
-        if token == "my-token" {
-            return Ok(token); // in a real program this would return an identity that could be used by the eventual method
-        } else {
-            //return Err(warp::reject::reject()); // a 404 rejection here aborts the filter processing and causes it to look
-                                                // for other filters
-            return Err(warp::reject::custom(CustomStatusCode::NotAuthorized)) // instead we have a custom reject and warp
-                                                                              // will dispatch to a default recover filter
-                                                                              // which turns this into a 500 with the message
-                                                                              // from the Debug impl.  If we want to have
-                                                                              // proper handling of this error we need
-                                                                              // a recover filter
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+        }),
+        _ => None,
+    };
+    let addr: SocketAddr = ([127, 0, 0, 1], 3030).into();
+
+    let todos = filters::todos(db.clone(), clock.clone(), idempotency_keys);
+    let serving: Pin<Box<dyn Future<Output = Result<(), String>>>> = match &snapshots {
+        Some(snapshots) => {
+            if args.snapshot_every > 0 {
+                tokio::spawn(snapshots.clone().run_every(
+                    Duration::from_secs(args.snapshot_every),
+                    db.clone(),
+                    clock.clone(),
+                ));
+            }
+            let admin = filters::admin(db.clone(), clock.clone(), snapshots.clone());
+            Box::pin(serve(
+                warp::service(todos.or(admin)),
+                addr,
+                tls,
+                args.http_redirect_port,
+            ))
         }
-    }
-
-    // This is a mechanism to ensure a refernece to the database is part of the filter chain.
-    // SInce Db is an Arc<Mutex<TheActualDb>> we can cheaply clone it and pass it down and everyone 
-    // will have access to it.  If we had a fancier DB like a struct that interacted with a mysql
-    // database we might instead just have an Arc<DbClient> with no Mutex required.
-    fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || db.clone())
-    }
-
-    fn json_body() -> impl Filter<Extract = (Todo,), Error = warp::Rejection> + Clone {
-        // When accepting a body, we want a JSON body
-        // (and to reject huge payloads)...
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
-    }
-}
-
-/// These are our API handlers, the ends of each filter chain.
-/// Notice how thanks to using `Filter::and`, we can define a function
-/// with the exact arguments we'd expect from each filter in the chain.
-/// No tuples are needed, it's auto flattened for the functions.
-mod handlers {
-    use super::models::{Db, ListOptions, Todo};
-    use std::convert::Infallible;
-    use warp::http::StatusCode;
-
-    pub async fn list_todos(opts: ListOptions, db: Db) -> Result<impl warp::Reply, Infallible> {
-        // Just return a JSON array of todos, applying the limit and offset.
-        let todos = db.lock().await;
-        let todos: Vec<Todo> = todos
-            .clone()
-            .into_iter()
-            .skip(opts.offset.unwrap_or(0))
-            .take(opts.limit.unwrap_or(std::usize::MAX))
-            .collect();
-        Ok(warp::reply::json(&todos))
-    }
-
-    pub async fn create_todo(create: Todo, db: Db) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("create_todo: {:?}", create);
-
-        let mut vec = db.lock().await;
+        // Without a snapshot file the admin routes would have nothing to do
+        None => Box::pin(serve(
+            warp::service(todos),
+            addr,
+            tls,
+            args.http_redirect_port,
+        )),
+    };
 
-        for todo in vec.iter() {
-            if todo.id == create.id {
-                log::debug!("    -> id already exists: {}", create.id);
-                // Todo with id already exists, return `400 BadRequest`.
-                return Ok(StatusCode::BAD_REQUEST);
+    tokio::select! {
+        result = serving => {
+            if let Err(e) = result {
+                log::error!(target: "todos", "server error: {}", e);
             }
         }
-
-        // No existing Todo with id, so insert and return `201 Created`.
-        vec.push(create);
-
-        Ok(StatusCode::CREATED)
+        _ = shutdown_signal() => log::info!(target: "todos", "shutting down"),
     }
 
-    pub async fn update_todo(
-        id: u64,
-        update: Todo,
-        db: Db,
-    ) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("update_todo: id={}, todo={:?}", id, update);
-        let mut vec = db.lock().await;
-
-        // Look for the specified Todo...
-        for todo in vec.iter_mut() {
-            if todo.id == id {
-                *todo = update;
-                return Ok(StatusCode::OK);
+    if let Some(snapshots) = snapshots {
+        match snapshots.take(&db, clock.now()).await {
+            Ok(count) => {
+                log::info!(target: "todos", "saved {} todos to {}", count, snapshots.path().display())
             }
+            Err(e) => log::error!(target: "todos", "final snapshot failed: {}", e),
         }
-
-        log::debug!("    -> todo id not found!");
-
-        // If the for loop didn't return OK, then the ID doesn't exist...
-        Ok(StatusCode::NOT_FOUND)
     }
+}
 
-    pub async fn delete_todo(id: u64, _token: String, db: Db) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("delete_todo: id={}", id);
+// Serves `svc` on `addr`, over TLS if there's a certificate to use, in which case plain HTTP on
+// `http_redirect_port` gets sent over to it.  Only returns if the plain HTTP server fails.
+async fn serve<S>(
+    svc: S,
+    addr: SocketAddr,
+    tls: Option<TlsConfig>,
+    http_redirect_port: Option<u16>,
+) -> Result<(), String>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return server::run(svc, addr).await.map_err(|e| e.to_string()),
+    };
 
-        let mut vec = db.lock().await;
+    let resolver = CertResolver::load(tls).unwrap_or_else(|e| fail(e));
+    let tls = resolver.server_config().unwrap_or_else(|e| fail(e));
+    #[cfg(unix)]
+    tokio::spawn(tls::reload_on_sighup(resolver));
 
-        let len = vec.len();
-        vec.retain(|todo| {
-            // Retain all Todos that aren't this id...
-            // In other words, remove all that *are* this id...
-            todo.id != id
+    if let Some(port) = http_redirect_port {
+        let redirect = service_fn(move |req| async move {
+            Ok::<_, Infallible>(tls::redirect_to_https(&req, addr.port()))
         });
+        tokio::spawn(async move {
+            if let Err(e) = server::run(redirect, ([127, 0, 0, 1], port).into()).await {
+                log::error!(target: "todos", "redirect server error: {}", e);
+            }
+        });
+    }
 
-        // If the vec is smaller, we found and deleted a Todo!
-        let deleted = vec.len() != len;
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|e| fail(e));
+    server::run_tls(svc, listener, tls).await;
+    Ok(())
+}
 
-        if deleted {
-            // respond with a `204 No Content`, which means successful,
-            // yet no body expected...
-            Ok(StatusCode::NO_CONTENT)
-        } else {
-            log::debug!("    -> todo id not found!");
-            Ok(StatusCode::NOT_FOUND)
+// Ctrl-C, or on unix the SIGTERM that service managers stop things with
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = match signal(SignalKind::terminate()) {
+            Ok(term) => term,
+            Err(_) => return tokio::signal::ctrl_c().await.unwrap_or(()),
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
         }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or(());
 }
 
-mod models {
-    use serde_derive::{Deserialize, Serialize};
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
-
-    /// So we don't have to tackle how different database work, we'll just use
-    /// a simple in-memory DB, a vector synchronized by a mutex.
-    pub type Db = Arc<Mutex<Vec<Todo>>>;
-
-    pub fn blank_db() -> Db {
-        Arc::new(Mutex::new(Vec::new()))
-    }
-
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub struct Todo {
-        pub id: u64,
-        pub text: String,
-        pub completed: bool,
-    }
-
-    // The query parameters for list_todos.
-    #[derive(Debug, Deserialize)]
-    pub struct ListOptions {
-        pub offset: Option<usize>,
-        pub limit: Option<usize>,
-    }
+fn fail(e: impl std::fmt::Display) -> ! {
+    log::error!(target: "todos", "can't start: {}", e);
+    process::exit(1);
 }
-
-#[cfg(test)]
-mod tests {
-    use warp::http::StatusCode;
-    use warp::test::request;
-
-    use super::{
-        filters,
-        models::{self, Todo},
-    };
-
-    #[tokio::test]
-    async fn test_post() {
-        let db = models::blank_db();
-        let api = filters::todos(db);
-
-        let resp = request()
-            .method("POST")
-            .path("/todos")
-            .json(&todo1())
-            .reply(&api)
-            .await;
-
-        assert_eq!(resp.status(), StatusCode::CREATED);
-    }
-
-    #[tokio::test]
-    async fn test_post_conflict() {
-        let db = models::blank_db();
-        db.lock().await.push(todo1());
-        let api = filters::todos(db);
-
-        let resp = request()
-            .method("POST")
-            .path("/todos")
-            .json(&todo1())
-            .reply(&api)
-            .await;
-
-        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
-    }
-
-    #[tokio::test]
-    async fn test_put_unknown() {
-        let _ = pretty_env_logger::try_init();
-        let db = models::blank_db();
-        let api = filters::todos(db);
-
-        let resp = request()
-            .method("PUT")
-            .path("/todos/1")
-            .header("authorization", "Bearer admin")
-            .json(&todo1())
-            .reply(&api)
-            .await;
-
-        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
-    }
-
-    fn todo1() -> Todo {
-        Todo {
-            id: 1,
-            text: "test 1".into(),
-            completed: false,
-        }
-    }
-}
\ No newline at end of file
@@ -0,0 +1,247 @@
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+
+/// Responses smaller than this go out uncompressed; below about a kilobyte the gzip header and
+/// the extra CPU cost more than they save.
+pub const MIN_COMPRESS_LEN: usize = 1024;
+
+// Brotli's top quality levels are far too slow to run per request
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// The formats the list endpoint can produce, picked from the `Accept` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Json,
+    Csv,
+    MessagePack,
+}
+
+impl Representation {
+    // In order of preference for when the client likes several equally
+    const ALL: [Representation; 3] = [
+        Representation::Json,
+        Representation::Csv,
+        Representation::MessagePack,
+    ];
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Representation::Json => "application/json",
+            Representation::Csv => "text/csv; charset=utf-8",
+            Representation::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Representation::Json => &["application/json"],
+            Representation::Csv => &["text/csv"],
+            // There was never an officially registered type, so take all the common spellings
+            Representation::MessagePack => &[
+                "application/msgpack",
+                "application/x-msgpack",
+                "application/vnd.msgpack",
+            ],
+        }
+    }
+
+    /// Picks the best representation for an `Accept` header, honouring q-values and wildcards.
+    /// No header at all means JSON, while None means nothing the client accepts can be produced
+    /// and the caller should answer `406 Not Acceptable`.
+    pub fn from_accept(accept: Option<&str>) -> Option<Representation> {
+        let ranges = match accept {
+            Some(accept) => parse_weighted(accept),
+            None => return Some(Representation::Json),
+        };
+
+        best(&Representation::ALL, |repr| {
+            repr.media_types()
+                .iter()
+                .filter_map(|media_type| media_range_q(&ranges, media_type))
+                .max_by_key(|&(specificity, _)| specificity)
+                .map_or(0.0, |(_, q)| q)
+        })
+    }
+}
+
+/// Content codings we can apply to a response body, picked from `Accept-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Identity];
+
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// The value for the `Content-Encoding` header, None for an uncompressed body.
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            encoding => Some(encoding.token()),
+        }
+    }
+
+    /// Picks the best coding for an `Accept-Encoding` header, preferring brotli over gzip when
+    /// the client rates them the same.  Falls back to identity even if the client claims not to
+    /// accept it, which is what RFC 7231 suggests rather than failing the request.
+    pub fn from_accept_encoding(accept_encoding: Option<&str>) -> Encoding {
+        let codings = match accept_encoding {
+            Some(header) => parse_weighted(header),
+            None => return Encoding::Identity,
+        };
+
+        let wildcard = codings.iter().find(|(c, _)| c == "*").map(|&(_, q)| q);
+        best(&Encoding::ALL, |encoding| {
+            match codings.iter().find(|(c, _)| c == encoding.token()) {
+                Some(&(_, q)) => q,
+                // identity that isn't mentioned gets picked up by the fallback below, so it only
+                // beats a real coding when the client says it prefers an uncompressed body
+                None => wildcard.unwrap_or(0.0),
+            }
+        })
+        .unwrap_or(Encoding::Identity)
+    }
+
+    /// Compresses a body with this coding.
+    pub fn encode(self, body: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Identity => Ok(body),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&body)?;
+                encoder.finish()
+            }
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut out,
+                        4096,
+                        BROTLI_QUALITY,
+                        BROTLI_WINDOW,
+                    );
+                    encoder.write_all(&body)?;
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+// The candidate with the highest non-zero weight; ties go to whichever comes first
+fn best<T: Copy>(candidates: &[T], weight: impl Fn(T) -> f32) -> Option<T> {
+    let mut best = None;
+    let mut best_q = 0.0;
+    for &candidate in candidates {
+        let q = weight(candidate);
+        if q > best_q {
+            best = Some(candidate);
+            best_q = q;
+        }
+    }
+    best
+}
+
+// How well `media_type` is matched by the ranges in an Accept header.  The most specific matching
+// range wins, so `text/csv;q=0` still rules out CSV for a client that also sent `*/*`.
+fn media_range_q(ranges: &[(String, f32)], media_type: &str) -> Option<(u8, f32)> {
+    let main_type = media_type.split('/').next().unwrap_or("");
+
+    ranges
+        .iter()
+        .filter_map(|(range, q)| {
+            let specificity = if range == media_type {
+                2
+            } else if range.strip_suffix("/*") == Some(main_type) {
+                1
+            } else if range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, *q))
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+}
+
+// Splits a header like `gzip;q=0.8, br` into lowercased values and their weights.  Parameters
+// other than q are dropped since none of our formats care about them.
+fn parse_weighted(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+
+            let q = params
+                .filter_map(|param| {
+                    let (key, val) = param.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("q") {
+                        val.trim().parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+
+            Some((value, q.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_representation_from_accept() {
+        use Representation::*;
+
+        assert_eq!(Representation::from_accept(None), Some(Json));
+        assert_eq!(Representation::from_accept(Some("*/*")), Some(Json));
+        assert_eq!(Representation::from_accept(Some("text/*")), Some(Csv));
+        assert_eq!(
+            Representation::from_accept(Some("application/json;q=0.5, application/x-msgpack")),
+            Some(MessagePack)
+        );
+        assert_eq!(
+            Representation::from_accept(Some("*/*, application/json;q=0")),
+            Some(Csv)
+        );
+        assert_eq!(Representation::from_accept(Some("text/html")), None);
+    }
+
+    #[test]
+    fn picks_encoding_from_accept_encoding() {
+        use Encoding::*;
+
+        assert_eq!(Encoding::from_accept_encoding(None), Identity);
+        assert_eq!(Encoding::from_accept_encoding(Some("gzip, deflate")), Gzip);
+        assert_eq!(Encoding::from_accept_encoding(Some("gzip, br")), Brotli);
+        assert_eq!(
+            Encoding::from_accept_encoding(Some("br;q=0.2, gzip;q=0.9")),
+            Gzip
+        );
+        assert_eq!(Encoding::from_accept_encoding(Some("*")), Brotli);
+        assert_eq!(
+            Encoding::from_accept_encoding(Some("*;q=0, identity")),
+            Identity
+        );
+        assert_eq!(Encoding::from_accept_encoding(Some("deflate")), Identity);
+    }
+}
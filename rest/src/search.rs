@@ -0,0 +1,239 @@
+use std::collections::{BTreeMap, HashMap};
+
+// BM25 tuning, the usual defaults
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+// A query term that's only a prefix of a word counts for less than matching it outright, so
+// "plan" ranks "plan the sprint" above "planting bulbs".
+const PREFIX_WEIGHT: f64 = 0.5;
+
+/// An inverted index over todo text.  Every word points at the todos containing it along with how
+/// often it appears there, and the words are kept sorted so a prefix is just a range scan.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: BTreeMap<String, HashMap<u64, u32>>,
+    docs: HashMap<u64, Doc>,
+    total_len: u64,
+}
+
+// What the index knows about one todo, so removing it only has to touch its own words
+#[derive(Debug)]
+struct Doc {
+    // Number of words, for length normalisation
+    len: u32,
+    distinct_words: Vec<String>,
+}
+
+impl SearchIndex {
+    pub fn new() -> SearchIndex {
+        SearchIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    /// Adds a todo's text under `id`, replacing whatever was indexed for it before.
+    pub fn insert(&mut self, id: u64, text: &str) {
+        self.remove(id);
+
+        let words = tokenize(text);
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for word in &words {
+            *counts.entry(word.as_str()).or_default() += 1;
+        }
+        let mut distinct_words = Vec::with_capacity(counts.len());
+        for (word, count) in counts {
+            self.postings
+                .entry(word.to_string())
+                .or_default()
+                .insert(id, count);
+            distinct_words.push(word.to_string());
+        }
+
+        self.total_len += words.len() as u64;
+        self.docs.insert(
+            id,
+            Doc {
+                len: words.len() as u32,
+                distinct_words,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let doc = match self.docs.remove(&id) {
+            Some(doc) => doc,
+            None => return,
+        };
+        self.total_len -= u64::from(doc.len);
+
+        for word in doc.distinct_words {
+            if let Some(postings) = self.postings.get_mut(&word) {
+                postings.remove(&id);
+                if postings.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    /// Ids of the todos matching every term of `query`, best match first.  Each term matches
+    /// words it's a prefix of, so "rot on" finds "rotate on-call".  Ties go to the lower id so
+    /// results come back in a stable order.
+    pub fn search(&self, query: &str) -> Vec<u64> {
+        let terms = tokenize(query);
+        if terms.is_empty() || self.is_empty() {
+            return Vec::new();
+        }
+
+        let docs = self.len() as f64;
+        let avg_len = self.total_len as f64 / docs;
+        let mut scores: Option<HashMap<u64, f64>> = None;
+
+        for term in &terms {
+            let mut term_scores: HashMap<u64, f64> = HashMap::new();
+
+            for (word, postings) in self.postings.range(term.clone()..) {
+                if !word.starts_with(term.as_str()) {
+                    break;
+                }
+                let weight = if word == term { 1.0 } else { PREFIX_WEIGHT };
+                let df = postings.len() as f64;
+                let idf = (1.0 + (docs - df + 0.5) / (df + 0.5)).ln();
+
+                for (&id, &tf) in postings {
+                    let tf = f64::from(tf);
+                    let len = f64::from(self.docs[&id].len);
+                    let norm = tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * len / avg_len));
+                    *term_scores.entry(id).or_default() += weight * idf * norm;
+                }
+            }
+
+            // Every term has to match, so only keep todos this term found as well
+            scores = Some(match scores {
+                None => term_scores,
+                Some(mut scores) => {
+                    scores.retain(|id, score| match term_scores.get(id) {
+                        Some(term_score) => {
+                            *score += term_score;
+                            true
+                        }
+                        None => false,
+                    });
+                    scores
+                }
+            });
+        }
+
+        let mut ranked: Vec<(u64, f64)> = scores.unwrap_or_default().into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.into_iter().map(|(id, _)| id).collect()
+    }
+}
+
+/// The brute force version of `SearchIndex::search`: tokenises every todo on each query and keeps
+/// the ones where every term prefixes some word, in their original order.  Kept as the baseline
+/// the benchmarks compare the index against and the tests check it with; it's only public because
+/// the benchmarks are a separate crate, so it stays out of the docs.
+#[doc(hidden)]
+pub fn scan<'a>(todos: impl IntoIterator<Item = (u64, &'a str)>, query: &str) -> Vec<u64> {
+    let terms = tokenize(query);
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    todos
+        .into_iter()
+        .filter(|(_, text)| {
+            let words = tokenize(text);
+            terms
+                .iter()
+                .all(|term| words.iter().any(|word| word.starts_with(term.as_str())))
+        })
+        .map(|(id, _)| id)
+        .collect()
+}
+
+// Lowercased runs of letters and digits; everything else separates words
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(todos: &[(u64, &str)]) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for &(id, text) in todos {
+            index.insert(id, text);
+        }
+        index
+    }
+
+    #[test]
+    fn every_term_must_match_as_a_prefix() {
+        let index = index(&[
+            (1, "Rotate on-call"),
+            (2, "rotate the logs"),
+            (3, "call the plumber"),
+        ]);
+
+        assert_eq!(index.search("rot on"), vec![1]);
+        assert_eq!(index.search("ROTATE"), vec![1, 2]);
+        assert_eq!(index.search("the"), vec![2, 3]);
+        assert!(index.search("rotate plumber").is_empty());
+        assert!(index.search("  ,, ").is_empty());
+    }
+
+    #[test]
+    fn exact_and_rarer_matches_rank_higher() {
+        let index = index(&[
+            (1, "planting bulbs"),
+            (2, "plan the sprint"),
+            (3, "sprint sprint sprint retro"),
+            (4, "sprint review"),
+        ]);
+
+        assert_eq!(index.search("plan"), vec![2, 1]);
+        assert_eq!(index.search("sprint")[0], 3);
+    }
+
+    #[test]
+    fn updates_and_removals_are_reflected() {
+        let mut index = index(&[(1, "buy milk"), (2, "buy bread")]);
+
+        index.insert(1, "buy eggs");
+        assert!(index.search("milk").is_empty());
+        assert_eq!(index.search("eggs"), vec![1]);
+
+        index.remove(2);
+        assert_eq!(index.search("buy"), vec![1]);
+        assert_eq!(index.len(), 1);
+    }
+
+    #[test]
+    fn scan_agrees_with_the_index() {
+        let todos = [
+            (1, "Rotate on-call"),
+            (2, "rotate the logs"),
+            (3, "call the plumber"),
+        ];
+        let index = index(&todos);
+
+        for query in &["rot", "the", "call pl", "nothing"] {
+            let mut found = index.search(query);
+            found.sort_unstable();
+            assert_eq!(found, scan(todos.iter().copied(), query), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use warp::http::{Request, Response};
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Server};
+
+use crate::telemetry;
+
+// How long a client gets to finish its TLS handshake before it's dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait after accepting fails before trying again, as hyper's own server does.  It's
+// usually running out of file descriptors, which only clears up as other connections close.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serves `svc` (normally `warp::service` around the API filters) on `addr`, with every request
+/// traced.  We run hyper ourselves instead of going through `warp::serve` so that the tracing
+/// can wrap the filters as a whole.
+pub async fn run<S>(svc: S, addr: SocketAddr) -> Result<(), warp::hyper::Error>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let make_svc = make_service_fn(move |_conn| {
+        let svc = svc.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| telemetry::traced(svc.clone(), req))) }
+    });
+
+    Server::bind(&addr).serve(make_svc).await
+}
+
+/// Same as `run` but over TLS, for connections coming in on `listener`.  Each connection does its
+/// handshake on its own task, so a slow or broken client can't hold up the rest, and gets dropped
+/// if it hasn't finished within 10 seconds.  Never returns: like hyper's server, a failed accept
+/// is logged and retried after a pause.
+pub async fn run_tls<S>(svc: S, listener: TcpListener, tls: Arc<ServerConfig>)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let acceptor = TlsAcceptor::from(tls);
+
+    loop {
+        let (tcp, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!(target: "todos", "accept error: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let svc = svc.clone();
+
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log::debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    log::debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+            let conn = Http::new().http1_only(true).serve_connection(
+                stream,
+                service_fn(move |req| telemetry::traced(svc.clone(), req)),
+            );
+            if let Err(e) = conn.await {
+                log::debug!("connection from {} ended with an error: {}", peer, e);
+            }
+        });
+    }
+}
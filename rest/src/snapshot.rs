@@ -0,0 +1,227 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::clock::SharedClock;
+use crate::models::{Db, Todo, TodoStore};
+
+// Bumped if the layout ever changes, so an old server refuses a snapshot it can't read properly
+const VERSION: u32 = 1;
+
+/// Everything needed to rebuild the store, as written to disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    taken_at: DateTime<Utc>,
+    // Kept so ids of Todos deleted before the snapshot aren't handed out again after a restore
+    max_id: u64,
+    // In creation order, which restoring keeps
+    todos: Vec<Todo>,
+}
+
+/// Saves the whole store to one JSON file and loads it back, so a server with nothing but the
+/// in-memory Db can survive a restart.
+///
+/// Snapshots are written to a temporary file next to the real one and renamed over it, so a
+/// crash part way through leaves the previous snapshot intact rather than a truncated one.
+#[derive(Debug)]
+pub struct Snapshotter {
+    path: PathBuf,
+    // Only one snapshot gets written at a time, whether it came from the timer or the endpoint
+    writing: Mutex<()>,
+}
+
+impl Snapshotter {
+    pub fn new(path: impl Into<PathBuf>) -> Snapshotter {
+        Snapshotter {
+            path: path.into(),
+            writing: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the last snapshot, or None if there isn't one yet.
+    pub fn restore(&self) -> Result<Option<TodoStore>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let snapshot: Snapshot =
+            serde_json::from_reader(BufReader::new(file)).map_err(Error::Corrupt)?;
+        if snapshot.version != VERSION {
+            return Err(Error::UnknownVersion(snapshot.version));
+        }
+
+        let mut store = TodoStore::new();
+        for todo in snapshot.todos {
+            if let Err(todo) = store.insert(todo) {
+                return Err(Error::DuplicateId(todo.id));
+            }
+        }
+        store.reserve_ids(snapshot.max_id);
+        Ok(Some(store))
+    }
+
+    /// Writes the current contents of `db` out, returning how many Todos went in.  The store is
+    /// only locked for as long as it takes to copy it.
+    pub async fn take(&self, db: &Db, now: DateTime<Utc>) -> Result<usize, Error> {
+        let _writing = self.writing.lock().await;
+
+        let snapshot = {
+            let store = db.read().await;
+            Snapshot {
+                version: VERSION,
+                taken_at: now,
+                max_id: store.max_id(),
+                todos: store.iter().cloned().collect(),
+            }
+        };
+        let count = snapshot.todos.len();
+
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || write_atomically(&path, &snapshot))
+            .await
+            .map_err(|e| Error::Io(io::Error::other(e)))??;
+        Ok(count)
+    }
+
+    /// Takes a snapshot every `period` until the process exits.  Failures are logged and tried
+    /// again next time round rather than stopping the server.
+    pub async fn run_every(self: Arc<Self>, period: Duration, db: Db, clock: SharedClock) {
+        let mut ticks = tokio::time::interval(period);
+        // The first tick is immediate, and there's nothing new to save right after starting
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            match self.take(&db, clock.now()).await {
+                Ok(count) => log::debug!("snapshot of {} todos saved", count),
+                Err(e) => log::error!(target: "todos", "periodic snapshot failed: {}", e),
+            }
+        }
+    }
+}
+
+fn write_atomically(path: &Path, snapshot: &Snapshot) -> Result<(), Error> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(Error::Io)?;
+    }
+
+    let tmp = path.with_extension("json.tmp");
+    let mut file = BufWriter::new(File::create(&tmp).map_err(Error::Io)?);
+    serde_json::to_writer(&mut file, snapshot).map_err(Error::Corrupt)?;
+    file.flush().map_err(Error::Io)?;
+    // Make sure the data is on disk before the rename makes it the snapshot
+    file.get_ref().sync_all().map_err(Error::Io)?;
+    fs::rename(&tmp, path).map_err(Error::Io)?;
+
+    // And that the rename itself survives a power cut
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(Error::Io)?;
+    }
+    Ok(())
+}
+
+/// Problems saving or restoring a snapshot.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file isn't a snapshot, or got damaged.
+    Corrupt(serde_json::Error),
+    UnknownVersion(u32),
+    DuplicateId(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Corrupt(e) => write!(f, "not a valid snapshot: {}", e),
+            Error::UnknownVersion(version) => write!(f, "unknown snapshot version {}", version),
+            Error::DuplicateId(id) => write!(f, "snapshot has todo id {} twice", id),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock, models};
+
+    #[tokio::test]
+    async fn restores_what_was_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = Snapshotter::new(dir.path().join("nested").join("todos.json"));
+        assert!(snapshots.restore().unwrap().is_none());
+
+        let db = models::blank_db();
+        {
+            let mut store = db.write().await;
+            for id in [3, 1, 7] {
+                store.insert(Todo::new(id, format!("todo {}", id))).unwrap();
+            }
+            store.remove(7);
+        }
+        let count = snapshots.take(&db, Utc::now()).await.unwrap();
+        assert_eq!(count, 2);
+        assert!(!snapshots.path().with_extension("json.tmp").exists());
+
+        let restored = snapshots.restore().unwrap().unwrap();
+        let ids: Vec<u64> = restored.iter().map(|todo| todo.id).collect();
+        assert_eq!(ids, vec![3, 1]);
+        assert_eq!(restored.max_id(), 7);
+        assert_eq!(restored.search("todo").len(), 2);
+    }
+
+    #[test]
+    fn bad_snapshots_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = Snapshotter::new(dir.path().join("todos.json"));
+
+        fs::write(snapshots.path(), "{\"version\": 1, \"taken_at\"").unwrap();
+        assert!(matches!(snapshots.restore(), Err(Error::Corrupt(_))));
+
+        let snapshot = |version, ids: &[u64]| Snapshot {
+            version,
+            taken_at: Utc::now(),
+            max_id: 0,
+            todos: ids.iter().map(|&id| Todo::new(id, "again")).collect(),
+        };
+        write_atomically(snapshots.path(), &snapshot(2, &[1])).unwrap();
+        assert!(matches!(snapshots.restore(), Err(Error::UnknownVersion(2))));
+        write_atomically(snapshots.path(), &snapshot(VERSION, &[1, 1])).unwrap();
+        assert!(matches!(snapshots.restore(), Err(Error::DuplicateId(1))));
+    }
+
+    #[tokio::test]
+    async fn takes_snapshots_periodically() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshots = Arc::new(Snapshotter::new(dir.path().join("todos.json")));
+        let db = models::blank_db();
+        db.write().await.insert(Todo::new(1, "keep me")).unwrap();
+
+        let timer = tokio::spawn(snapshots.clone().run_every(
+            Duration::from_millis(10),
+            db,
+            clock::system_clock(),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        timer.abort();
+
+        assert_eq!(snapshots.restore().unwrap().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,213 @@
+use std::convert::Infallible;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+use warp::http::{HeaderValue, Request, Response};
+use warp::hyper::service::Service;
+use warp::hyper::Body;
+
+/// Correlation id header.  Taken from the request when the caller sent a usable one, otherwise
+/// generated, and echoed back on the response either way.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Anything longer is more likely garbage than a real id from an upstream proxy
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Sets up logging for the server.  `RUST_LOG` picks what gets logged as before, and with `json`
+/// every line comes out as a JSON object carrying the fields of the request span it belongs to.
+///
+/// The `log` macros used around the handlers are forwarded into tracing, so they land in the
+/// right request span too.
+pub fn init(json: bool) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    if json {
+        builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init();
+    } else {
+        builder.init();
+    }
+}
+
+/// Same as init but for tests, where several may try to set things up.
+#[cfg(test)]
+pub fn try_init() {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_test_writer()
+        .try_init();
+}
+
+/// Runs a single request through `svc` inside its own span, which records the request id, route,
+/// status, latency and (once the auth filter has run) the caller's identity, and logs a line when
+/// the request completes.  This takes over from `warp::log` for the access log.
+///
+/// It sits outside warp's filters rather than being one, since only here can we both put the span
+/// around the whole filter chain and get at the response on its way out.
+pub async fn traced<S>(mut svc: S, mut req: Request<Body>) -> Result<Response<Body>, Infallible>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let started = Instant::now();
+
+    let request_id = match req.headers().get(REQUEST_ID_HEADER) {
+        Some(id) if is_usable(id) => id.clone(),
+        _ => new_request_id(),
+    };
+    // The filters see the same id we're about to send back, whether it came in or was made here
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let span = tracing::info_span!(
+        target: "todos",
+        "request",
+        request_id = request_id.to_str().unwrap_or_default(),
+        method = %req.method(),
+        path = req.uri().path(),
+        route = Empty,
+        identity = Empty,
+        status = Empty,
+        latency_ms = Empty,
+    );
+
+    let mut resp = svc.call(req).instrument(span.clone()).await?;
+
+    let status = resp.status();
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    span.record("status", status.as_u16());
+    span.record("latency_ms", latency_ms);
+    span.in_scope(|| {
+        if status.is_server_error() {
+            tracing::warn!(target: "todos", "request failed");
+        } else {
+            tracing::info!(target: "todos", "request completed");
+        }
+    });
+
+    resp.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    Ok(resp)
+}
+
+/// Names the route a request matched in its span, e.g. `PUT /todos/:id`.  Goes after the path and
+/// method filters of each route so only the one that actually matched gets to set it.
+pub fn route(name: &'static str) -> impl warp::Filter<Extract = (), Error = Infallible> + Clone {
+    use warp::Filter;
+
+    warp::any()
+        .map(move || {
+            tracing::Span::current().record("route", name);
+        })
+        .untuple_one()
+}
+
+/// Records who made the request in its span, once they've been authenticated.
+pub fn record_identity(identity: &str) {
+    tracing::Span::current().record("identity", identity);
+}
+
+fn is_usable(id: &HeaderValue) -> bool {
+    let bytes = id.as_bytes();
+    !bytes.is_empty() && bytes.len() <= MAX_REQUEST_ID_LEN && bytes.iter().all(u8::is_ascii_graphic)
+}
+
+fn new_request_id() -> HeaderValue {
+    let id = uuid::Uuid::new_v4().to_string();
+    HeaderValue::from_str(&id).expect("a uuid is a valid header value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock, filters, idempotency, models};
+    use std::sync::Arc;
+
+    async fn call(req: Request<Body>) -> Response<Body> {
+        try_init();
+        let keys = Arc::new(idempotency::Store::new(chrono::Duration::hours(1)));
+        let api = filters::todos(models::blank_db(), clock::system_clock(), keys);
+        traced(warp::service(api), req).await.unwrap()
+    }
+
+    // Collects what a subscriber writes, so the tests can see what went into the span
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn records_route_status_latency_and_identity() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // Tokio tests run on the one thread, so this covers the whole request
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let body = r#"{"id":1,"text":"trace me","completed":false}"#;
+        let req = Request::post("/todos")
+            .header("Authorization", "my-token")
+            .header(REQUEST_ID_HEADER, "traced-1")
+            .header("Content-Length", body.len())
+            .body(Body::from(body))
+            .unwrap();
+        assert_eq!(call(req).await.status(), 201);
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let completed: serde_json::Value = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &serde_json::Value| line["fields"]["message"] == "request completed")
+            .unwrap_or_else(|| panic!("no completed line in {}", output));
+        let span = &completed["span"];
+        assert_eq!(span["request_id"], "traced-1");
+        assert_eq!(span["method"], "POST");
+        assert_eq!(span["path"], "/todos");
+        assert_eq!(span["route"], "POST /todos");
+        assert_eq!(span["identity"], "demo-user");
+        assert_eq!(span["status"], 201);
+        assert!(span["latency_ms"].as_f64().unwrap() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn propagates_a_usable_request_id() {
+        let req = Request::get("/todos")
+            .header(REQUEST_ID_HEADER, "upstream-1234")
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = call(req).await;
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], "upstream-1234");
+    }
+
+    #[tokio::test]
+    async fn generates_a_request_id_when_missing_or_unusable() {
+        let resp = call(Request::get("/todos").body(Body::empty()).unwrap()).await;
+        let generated = resp.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+
+        // Rejected requests get one too
+        let req = Request::get("/nowhere")
+            .header(REQUEST_ID_HEADER, "has spaces in it")
+            .body(Body::empty())
+            .unwrap();
+        let resp = call(req).await;
+        assert_eq!(resp.status(), 404);
+        assert_ne!(resp.headers()[REQUEST_ID_HEADER], "has spaces in it");
+    }
+}
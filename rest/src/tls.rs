@@ -0,0 +1,330 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use warp::http::{header, HeaderValue, Request, Response, StatusCode, Uri};
+use warp::hyper::Body;
+
+/// Where to find the certificate chain and private key, both PEM.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Hands out the server's certificate, and can swap it for whatever is on disk now without a
+/// restart.
+///
+/// warp's own TLS support (`warp::serve(..).tls()`) reads the files once when the server starts,
+/// and it would also go around the hyper server that traces every request, so we drive rustls
+/// ourselves instead.  It's the same library underneath.
+#[derive(Debug)]
+pub struct CertResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(config: TlsConfig) -> Result<Arc<CertResolver>, Error> {
+        let provider = Arc::new(ring::default_provider());
+        let current = load_certified_key(&config, &provider)?;
+        Ok(Arc::new(CertResolver {
+            config,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    /// Reads the certificate and key again.  If they're broken, say because only one has been
+    /// replaced so far, the old pair stays in use and the error comes back.  Connections already
+    /// open keep the certificate they started with.
+    pub fn reload(&self) -> Result<(), Error> {
+        let fresh = load_certified_key(&self.config, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(fresh);
+        Ok(())
+    }
+
+    /// The rustls config for the server, which asks this resolver for the certificate on every
+    /// handshake.
+    pub fn server_config(self: &Arc<Self>) -> Result<Arc<ServerConfig>, Error> {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(Error::Rustls)?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        // hyper is only set up for HTTP/1 here
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reloads the certificate every time the process gets a SIGHUP, which is what certbot and
+/// friends can be told to send after renewing.  Runs until the process exits.
+#[cfg(unix)]
+pub async fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            log::error!(
+                target: "todos",
+                "can't listen for SIGHUP, certificates won't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match resolver.reload() {
+            Ok(()) => log::info!(target: "todos", "reloaded TLS certificate"),
+            Err(e) => log::error!(target: "todos", "keeping the old TLS certificate: {}", e),
+        }
+    }
+}
+
+/// The answer the plain HTTP listener gives to everything: a permanent redirect to the same URL
+/// over HTTPS on `https_port`.  308 rather than 301 so clients repeat a POST or PUT as is instead
+/// of turning it into a GET.
+pub fn redirect_to_https(req: &Request<Body>, https_port: u16) -> Response<Body> {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<warp::http::uri::Authority>().ok());
+    let host = match host {
+        Some(host) => host,
+        None => {
+            let mut resp =
+                Response::new(Body::from("A Host header is needed to redirect to HTTPS"));
+            *resp.status_mut() = StatusCode::BAD_REQUEST;
+            return resp;
+        }
+    };
+
+    let authority = if https_port == 443 {
+        host.host().to_string()
+    } else {
+        format!("{}:{}", host.host(), https_port)
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    let location = Uri::builder()
+        .scheme("https")
+        .authority(authority.as_str())
+        .path_and_query(path)
+        .build()
+        .ok()
+        .and_then(|uri| HeaderValue::from_str(&uri.to_string()).ok());
+
+    let mut resp = Response::new(Body::empty());
+    match location {
+        Some(location) => {
+            *resp.status_mut() = StatusCode::PERMANENT_REDIRECT;
+            resp.headers_mut().insert(header::LOCATION, location);
+        }
+        None => *resp.status_mut() = StatusCode::BAD_REQUEST,
+    }
+    resp
+}
+
+fn load_certified_key(
+    config: &TlsConfig,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, Error> {
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::Io(config.cert_path.clone(), e))?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificate(config.cert_path.clone()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(&config.key_path)?)
+        .map_err(|e| Error::Io(config.key_path.clone(), e))?
+        .ok_or_else(|| Error::NoKey(config.key_path.clone()))?;
+
+    // Also checks the key belongs to the certificate
+    CertifiedKey::from_der(certs, key, provider).map_err(Error::Rustls)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| Error::Io(path.to_path_buf(), e))
+}
+
+/// Problems loading the certificate and key.
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    NoCertificate(PathBuf),
+    NoKey(PathBuf),
+    /// The key couldn't be used, or doesn't match the certificate.
+    Rustls(tokio_rustls::rustls::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "can't read {}: {}", path.display(), e),
+            Error::NoCertificate(path) => write!(f, "no PEM certificate in {}", path.display()),
+            Error::NoKey(path) => write!(f, "no PEM private key in {}", path.display()),
+            Error::Rustls(e) => write!(f, "bad certificate or key: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock, filters, idempotency, models, server};
+    use std::convert::TryFrom;
+    use std::fs;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    struct SelfSigned {
+        cert_pem: String,
+        key_pem: String,
+    }
+
+    fn self_signed() -> SelfSigned {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        SelfSigned {
+            cert_pem: generated.cert.pem(),
+            key_pem: generated.key_pair.serialize_pem(),
+        }
+    }
+
+    fn install(dir: &Path, pair: &SelfSigned) -> TlsConfig {
+        let config = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        fs::write(&config.cert_path, &pair.cert_pem).unwrap();
+        fs::write(&config.key_path, &pair.key_pem).unwrap();
+        config
+    }
+
+    // Makes a request over TLS to `port`, trusting only `trusted`, and returns the response's
+    // status line
+    async fn get(port: u16, trusted: &SelfSigned) -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut trusted.cert_pem.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut tls = TlsConnector::from(Arc::new(config))
+            .connect(name, tcp)
+            .await?;
+        tls.write_all(b"GET /todos HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut resp = String::new();
+        tls.read_to_string(&mut resp).await?;
+        Ok(resp.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn serves_over_tls_and_reloads_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = self_signed();
+        let resolver = CertResolver::load(install(dir.path(), &first)).unwrap();
+
+        let keys = Arc::new(idempotency::Store::new(chrono::Duration::hours(1)));
+        let api = filters::todos(models::blank_db(), clock::system_clock(), keys);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(server::run_tls(
+            warp::service(api),
+            listener,
+            resolver.server_config().unwrap(),
+        ));
+
+        assert_eq!(get(port, &first).await.unwrap(), "HTTP/1.1 200 OK");
+
+        // A renewed certificate is picked up by new connections once reloaded
+        let second = self_signed();
+        install(dir.path(), &second);
+        assert!(get(port, &second).await.is_err());
+        resolver.reload().unwrap();
+        assert_eq!(get(port, &second).await.unwrap(), "HTTP/1.1 200 OK");
+        assert!(get(port, &first).await.is_err());
+
+        // Half way through replacing the files, the key doesn't match and nothing changes
+        fs::write(dir.path().join("key.pem"), self_signed().key_pem).unwrap();
+        assert!(matches!(resolver.reload(), Err(Error::Rustls(_))));
+        assert_eq!(get(port, &second).await.unwrap(), "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn missing_or_empty_files_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = TlsConfig {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+        };
+        assert!(matches!(
+            CertResolver::load(config.clone()),
+            Err(Error::Io(..))
+        ));
+
+        fs::write(&config.cert_path, "").unwrap();
+        assert!(matches!(
+            CertResolver::load(config.clone()),
+            Err(Error::NoCertificate(_))
+        ));
+
+        fs::write(&config.cert_path, self_signed().cert_pem).unwrap();
+        fs::write(&config.key_path, "").unwrap();
+        assert!(matches!(CertResolver::load(config), Err(Error::NoKey(_))));
+    }
+
+    #[test]
+    fn redirects_to_the_same_url_over_https() {
+        let redirect = |host: Option<&str>, port| {
+            let mut req = Request::post("/todos?limit=2");
+            if let Some(host) = host {
+                req = req.header(header::HOST, host);
+            }
+            redirect_to_https(&req.body(Body::empty()).unwrap(), port)
+        };
+
+        let resp = redirect(Some("todos.example.com:8080"), 3030);
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            "https://todos.example.com:3030/todos?limit=2"
+        );
+
+        let resp = redirect(Some("todos.example.com"), 443);
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            "https://todos.example.com/todos?limit=2"
+        );
+
+        assert_eq!(redirect(None, 443).status(), StatusCode::BAD_REQUEST);
+    }
+}
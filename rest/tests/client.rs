@@ -0,0 +1,197 @@
+// These drive the real server over HTTP through todo_api::Client, which is why the server lives in
+// lib.rs; a binary-only crate would leave the tests nothing to start.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use rest::tls::{CertResolver, TlsConfig};
+use rest::{clock, filters, idempotency, models, server};
+use todo_api::{Client, Error, ListOptions, Todo};
+
+// Starts a server on an ephemeral port and hands back a client pointed at it
+async fn serve(db: models::Db) -> Client {
+    let keys = Arc::new(idempotency::Store::new(chrono::Duration::hours(24)));
+    let api = filters::todos(db, clock::system_clock(), keys);
+    let (addr, server): (SocketAddr, _) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    Client::new(&format!("http://{}", addr)).unwrap()
+}
+
+// Same as serve but over TLS with a fresh self-signed certificate, which the client is told to
+// trust.  Also hands back the server's URL.
+async fn serve_tls(db: models::Db, dir: &std::path::Path) -> (Client, String) {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let config = TlsConfig {
+        cert_path: dir.join("cert.pem"),
+        key_path: dir.join("key.pem"),
+    };
+    std::fs::write(&config.cert_path, generated.cert.pem()).unwrap();
+    std::fs::write(&config.key_path, generated.key_pair.serialize_pem()).unwrap();
+    let tls = CertResolver::load(config).unwrap().server_config().unwrap();
+
+    let keys = Arc::new(idempotency::Store::new(chrono::Duration::hours(24)));
+    let api = filters::todos(db, clock::system_clock(), keys);
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(server::run_tls(warp::service(api), listener, tls));
+
+    let ca = reqwest::Certificate::from_pem(generated.cert.pem().as_bytes()).unwrap();
+    let http = reqwest::Client::builder()
+        .add_root_certificate(ca)
+        .build()
+        .unwrap();
+    let url = format!("https://localhost:{}", port);
+    (Client::with_http_client(&url, http).unwrap(), url)
+}
+
+#[tokio::test]
+async fn create_list_update_delete() {
+    let client = serve(models::blank_db()).await.with_token("my-token");
+
+    client.create(&Todo::new(1, "write tests")).await.unwrap();
+    client.create(&Todo::new(2, "run tests")).await.unwrap();
+
+    let todos = client.list(&ListOptions::default()).await.unwrap();
+    assert_eq!(todos.len(), 2);
+    let page = client
+        .list(&ListOptions {
+            offset: Some(1),
+            limit: Some(5),
+        })
+        .await
+        .unwrap();
+    assert_eq!(page, vec![Todo::new(2, "run tests")]);
+
+    let mut done = Todo::new(1, "write tests");
+    done.completed = true;
+    assert_eq!(client.update(1, &done).await.unwrap(), None);
+
+    let found = client.search("tests", Some(1)).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert!(client.search("nothing", None).await.unwrap().is_empty());
+
+    client.delete(2).await.unwrap();
+    assert_eq!(
+        client.list(&ListOptions::default()).await.unwrap(),
+        vec![done]
+    );
+}
+
+#[tokio::test]
+async fn update_returns_next_occurrence() {
+    let client = serve(models::blank_db()).await;
+
+    let mut chore = Todo::new(1, "rotate on-call");
+    chore.recurrence = Some("daily".parse().unwrap());
+    client.create(&chore).await.unwrap();
+
+    chore.completed = true;
+    let next = client.update(1, &chore).await.unwrap().unwrap();
+    assert_eq!(next.id, 2);
+    assert_eq!(next.text, "rotate on-call");
+    assert!(!next.completed);
+}
+
+#[tokio::test]
+async fn errors_are_typed_by_status() {
+    let client = serve(models::blank_db()).await;
+    client.create(&Todo::new(1, "only once")).await.unwrap();
+
+    match client.create(&Todo::new(1, "only once")).await {
+        Err(Error::BadRequest(_)) => {}
+        other => panic!("expected BadRequest, got {:?}", other),
+    }
+    match client.update(7, &Todo::new(7, "missing")).await {
+        Err(Error::NotFound) => {}
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+    match client.delete(1).await {
+        Err(Error::MissingToken) => {}
+        other => panic!("expected MissingToken, got {:?}", other),
+    }
+    match client.clone().with_token("wrong").delete(1).await {
+        Err(e @ Error::Unauthorized(_)) => assert_eq!(e.status().unwrap().as_u16(), 401),
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn creates_send_the_token() {
+    let db = models::blank_db();
+    let client = serve(db.clone()).await;
+
+    // The server only rejects a token it was given, so a 401 means the header went out
+    let wrong = client.clone().with_token("wrong");
+    match wrong.create(&Todo::new(1, "sneak in")).await {
+        Err(Error::Unauthorized(_)) => {}
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+    match wrong
+        .create_idempotent(&Todo::new(1, "sneak in"), "k")
+        .await
+    {
+        Err(Error::Unauthorized(_)) => {}
+        other => panic!("expected Unauthorized, got {:?}", other),
+    }
+    assert!(db.read().await.is_empty());
+
+    let client = client.with_token("my-token");
+    client.create(&Todo::new(1, "signed")).await.unwrap();
+    client
+        .create_idempotent(&Todo::new(2, "signed twice"), "k")
+        .await
+        .unwrap();
+    assert_eq!(db.read().await.len(), 2);
+}
+
+#[tokio::test]
+async fn idempotent_create_replays_and_rejects_reuse() {
+    let db = models::blank_db();
+    let client = serve(db.clone()).await;
+
+    client
+        .create_idempotent(&Todo::new(1, "pay invoice"), "invoice-1")
+        .await
+        .unwrap();
+    // A retry after a timeout succeeds instead of tripping over the Todo it already made
+    client
+        .create_idempotent(&Todo::new(1, "pay invoice"), "invoice-1")
+        .await
+        .unwrap();
+    assert_eq!(db.read().await.len(), 1);
+
+    match client
+        .create_idempotent(&Todo::new(2, "pay other invoice"), "invoice-1")
+        .await
+    {
+        Err(Error::UnprocessableEntity(_)) => {}
+        other => panic!("expected UnprocessableEntity, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn works_over_tls() {
+    let dir = tempfile::tempdir().unwrap();
+    let (client, url) = serve_tls(models::blank_db(), dir.path()).await;
+    let client = client.with_token("my-token");
+
+    client.create(&Todo::new(1, "encrypt")).await.unwrap();
+    assert_eq!(
+        client.list(&ListOptions::default()).await.unwrap(),
+        vec![Todo::new(1, "encrypt")]
+    );
+    client.delete(1).await.unwrap();
+
+    // Without the certificate the handshake fails rather than quietly going ahead
+    match Client::new(&url)
+        .unwrap()
+        .list(&ListOptions::default())
+        .await
+    {
+        Err(Error::Http(e)) => assert!(e.is_connect()),
+        other => panic!("expected a connection error, got {:?}", other),
+    }
+}
@@ -0,0 +1,280 @@
+use crate::models::{ListOptions, Todo};
+use reqwest::{header, RequestBuilder, Response, StatusCode, Url};
+use std::fmt;
+
+/// An async client for the Todo server, one method per route.
+///
+/// ```no_run
+/// # async fn demo() -> Result<(), todo_api::Error> {
+/// use todo_api::{Client, ListOptions, Todo};
+///
+/// let client = Client::new("http://127.0.0.1:3030")?.with_token("my-token");
+/// client.create(&Todo::new(1, "rotate on-call")).await?;
+/// let todos = client.list(&ListOptions::default()).await?;
+/// client.delete(todos[0].id).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+    token: Option<String>,
+}
+
+impl Client {
+    /// Creates a client for the server at `base_url`, e.g. `http://127.0.0.1:3030`.  For an
+    /// `https://` server the certificate is checked against the usual public CAs; to trust a
+    /// private one, pass a reqwest client with it added to `with_http_client`.
+    pub fn new(base_url: &str) -> Result<Client, Error> {
+        Client::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// Same as `new` but with a preconfigured reqwest client, for setting timeouts, proxies and
+    /// the like.
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> Result<Client, Error> {
+        let mut base = Url::parse(base_url).map_err(|e| Error::InvalidUrl(e.to_string()))?;
+        if base.cannot_be_a_base() {
+            return Err(Error::InvalidUrl(format!(
+                "{} can't be a base URL",
+                base_url
+            )));
+        }
+        // Make sure joining "todos" appends to any path prefix rather than replacing it
+        if !base.path().ends_with('/') {
+            let path = format!("{}/", base.path());
+            base.set_path(&path);
+        }
+
+        Ok(Client {
+            http,
+            base,
+            token: None,
+        })
+    }
+
+    /// Authenticates the client; the token is sent on `delete`, which requires it, and on `create`
+    /// and `create_idempotent`, so new Todos are tied to the caller.  The server compares the whole `Authorization` header against the token, so it
+    /// goes out as is without a `Bearer` prefix.
+    pub fn with_token(mut self, token: impl Into<String>) -> Client {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Swaps the token on an existing client, or drops it with None.
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// GET /todos
+    pub async fn list(&self, opts: &ListOptions) -> Result<Vec<Todo>, Error> {
+        let resp = self
+            .http
+            .get(self.url("todos")?)
+            .query(opts)
+            .header(header::ACCEPT, "application/json")
+            .send()
+            .await?;
+
+        Ok(check(resp).await?.json().await?)
+    }
+
+    /// GET /todos/search, best matches first.
+    pub async fn search(&self, query: &str, limit: Option<usize>) -> Result<Vec<Todo>, Error> {
+        let mut req = self.http.get(self.url("todos/search")?).query(&[("q", query)]);
+        if let Some(limit) = limit {
+            req = req.query(&[("limit", limit)]);
+        }
+
+        Ok(check(req.send().await?).await?.json().await?)
+    }
+
+    /// POST /todos
+    pub async fn create(&self, todo: &Todo) -> Result<(), Error> {
+        let req = self.http.post(self.url("todos")?).json(todo);
+        let resp = self.authorized(req).send().await?;
+        check(resp).await?;
+        Ok(())
+    }
+
+    /// POST /todos with an `Idempotency-Key`, so the request can be retried after a timeout
+    /// without risking a duplicate.  Reusing a key with a different Todo is an
+    /// `UnprocessableEntity` error.
+    pub async fn create_idempotent(&self, todo: &Todo, key: &str) -> Result<(), Error> {
+        let req = self
+            .http
+            .post(self.url("todos")?)
+            .header("Idempotency-Key", key)
+            .json(todo);
+        let resp = self.authorized(req).send().await?;
+        check(resp).await?;
+        Ok(())
+    }
+
+    /// PUT /todos/:id
+    ///
+    /// When this completes a recurring Todo the server schedules the next occurrence, which is
+    /// handed back here.
+    pub async fn update(&self, id: u64, todo: &Todo) -> Result<Option<Todo>, Error> {
+        let resp = self
+            .http
+            .put(self.url(&format!("todos/{}", id))?)
+            .json(todo)
+            .send()
+            .await?;
+
+        let body = check(resp).await?.bytes().await?;
+        if body.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| Error::Decode(e.to_string()))
+    }
+
+    /// DELETE /todos/:id, which needs a token.
+    pub async fn delete(&self, id: u64) -> Result<(), Error> {
+        let token = self.token.as_deref().ok_or(Error::MissingToken)?;
+        let resp = self
+            .http
+            .delete(self.url(&format!("todos/{}", id))?)
+            .header(header::AUTHORIZATION, token)
+            .send()
+            .await?;
+        check(resp).await?;
+        Ok(())
+    }
+
+    // Adds the token when there is one, for routes that take it but don't insist on it
+    fn authorized(&self, req: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => req.header(header::AUTHORIZATION, token),
+            None => req,
+        }
+    }
+
+    fn url(&self, path: &str) -> Result<Url, Error> {
+        self.base
+            .join(path)
+            .map_err(|e| Error::InvalidUrl(e.to_string()))
+    }
+}
+
+// Turns any non-2xx response into the matching error, with whatever message the server gave
+async fn check(resp: Response) -> Result<Response, Error> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+
+    let message = resp.text().await.unwrap_or_default();
+    Err(match status {
+        StatusCode::BAD_REQUEST => Error::BadRequest(message),
+        StatusCode::UNAUTHORIZED => Error::Unauthorized(message),
+        StatusCode::NOT_FOUND => Error::NotFound,
+        StatusCode::NOT_ACCEPTABLE => Error::NotAcceptable(message),
+        StatusCode::CONFLICT => Error::Conflict(message),
+        StatusCode::UNPROCESSABLE_ENTITY => Error::UnprocessableEntity(message),
+        status => Error::Status(status, message),
+    })
+}
+
+/// Everything that can go wrong talking to the server.  The statuses the API actually uses get
+/// their own variant; anything else lands in `Status`.
+#[derive(Debug)]
+pub enum Error {
+    /// 400: the Todo was malformed, or one with that id already exists.
+    BadRequest(String),
+    /// 401: the token was rejected.
+    Unauthorized(String),
+    /// 404: no Todo with that id.
+    NotFound,
+    /// 406: the server can't produce the requested format.
+    NotAcceptable(String),
+    /// 409: a request with the same idempotency key is still being handled.
+    Conflict(String),
+    /// 422: the idempotency key was already used for a different request.
+    UnprocessableEntity(String),
+    /// Any other unsuccessful status.
+    Status(StatusCode, String),
+    /// The route needs a token and the client doesn't have one.
+    MissingToken,
+    InvalidUrl(String),
+    /// The server answered with something that isn't the expected JSON.
+    Decode(String),
+    /// Connection problems, timeouts and the like.
+    Http(reqwest::Error),
+}
+
+impl Error {
+    /// The HTTP status behind this error, if the server got as far as answering.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::BadRequest(_) => Some(StatusCode::BAD_REQUEST),
+            Error::Unauthorized(_) => Some(StatusCode::UNAUTHORIZED),
+            Error::NotFound => Some(StatusCode::NOT_FOUND),
+            Error::NotAcceptable(_) => Some(StatusCode::NOT_ACCEPTABLE),
+            Error::Conflict(_) => Some(StatusCode::CONFLICT),
+            Error::UnprocessableEntity(_) => Some(StatusCode::UNPROCESSABLE_ENTITY),
+            Error::Status(status, _) => Some(*status),
+            Error::Http(e) => e.status(),
+            Error::MissingToken | Error::InvalidUrl(_) | Error::Decode(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            Error::Unauthorized(msg) => write!(f, "not authorized: {}", msg),
+            Error::NotFound => write!(f, "todo not found"),
+            Error::NotAcceptable(msg) => write!(f, "not acceptable: {}", msg),
+            Error::Conflict(msg) => write!(f, "conflict: {}", msg),
+            Error::UnprocessableEntity(msg) => write!(f, "unprocessable entity: {}", msg),
+            Error::Status(status, msg) => write!(f, "server returned {}: {}", status, msg),
+            Error::MissingToken => write!(f, "this request needs a token, log in first"),
+            Error::InvalidUrl(msg) => write!(f, "invalid URL: {}", msg),
+            Error::Decode(msg) => write!(f, "couldn't decode response: {}", msg),
+            Error::Http(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        if e.is_decode() {
+            Error::Decode(e.to_string())
+        } else {
+            Error::Http(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_path_prefix_is_kept() {
+        let client = Client::new("http://localhost:3030/api").unwrap();
+        assert_eq!(
+            client.url("todos/3").unwrap().as_str(),
+            "http://localhost:3030/api/todos/3"
+        );
+        assert!(Client::new("mailto:someone@example.com").is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! The pieces of the Todo API that both the server and its clients need: the wire types and an
+//! async client for talking to a running server.
+
+pub mod client;
+pub mod recurrence;
+
+pub use client::{Client, Error};
+pub use models::{ListOptions, Todo};
+
+pub mod models {
+    use super::recurrence::Recurrence;
+    use chrono::NaiveDate;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+    pub struct Todo {
+        pub id: u64,
+        pub text: String,
+        pub completed: bool,
+        // Both optional so plain Todos look exactly like they always have on the wire
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub due: Option<NaiveDate>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub recurrence: Option<Recurrence>,
+    }
+
+    impl Todo {
+        /// A plain, not yet completed Todo.
+        pub fn new(id: u64, text: impl Into<String>) -> Todo {
+            Todo {
+                id,
+                text: text.into(),
+                completed: false,
+                due: None,
+                recurrence: None,
+            }
+        }
+    }
+
+    // The query parameters for list_todos.
+    #[derive(Debug, Default, Clone, Deserialize, Serialize)]
+    pub struct ListOptions {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub offset: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub limit: Option<usize>,
+    }
+}
@@ -0,0 +1,560 @@
+use chrono::{Datelike, Days, Months, NaiveDate, Weekday};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// How often a recurring todo comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+/// A schedule rule attached to a todo.  Over the wire this is a plain string, either one of the
+/// shorthands `daily`, `weekly` and `monthly`, or a subset of an iCalendar RRULE such as
+/// `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH`.  The supported RRULE parts are:
+///
+/// - `FREQ`: `DAILY`, `WEEKLY` or `MONTHLY` (required)
+/// - `INTERVAL`: every n days/weeks/months, defaults to 1
+/// - `BYDAY`: comma separated `MO`..`SU`, only for weekly rules
+/// - `BYMONTHDAY`: a single day 1-31, only for monthly rules; short months use their last day
+/// - `COUNT`: total number of occurrences, including the current one
+/// - `UNTIL`: last allowed date as `YYYYMMDD` (inclusive)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Recurrence {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub by_day: Vec<Weekday>,
+    pub by_month_day: Option<u32>,
+    pub count: Option<u32>,
+    pub until: Option<NaiveDate>,
+}
+
+impl Recurrence {
+    pub fn new(freq: Frequency) -> Recurrence {
+        Recurrence {
+            freq,
+            interval: 1,
+            by_day: Vec::new(),
+            by_month_day: None,
+            count: None,
+            until: None,
+        }
+    }
+
+    /// Works out the next occurrence for a todo that was due on `from` and got completed `today`.
+    ///
+    /// Occurrences that are already in the past are skipped (and count against `COUNT`), so
+    /// finishing a chore late doesn't spawn a pile of overdue copies.  Returns the new due date
+    /// along with the rule the spawned todo should carry, or None once the rule is exhausted or
+    /// the next date is past the end of the calendar.
+    pub fn advance(&self, from: NaiveDate, today: NaiveDate) -> Option<(NaiveDate, Recurrence)> {
+        // The first step is the odd one out: `from` needn't be on the schedule, and a monthly
+        // rule only settles on its day of the month here
+        let (mut due, mut rule) = self.step(from)?;
+        if due >= today {
+            return Some((due, rule));
+        }
+
+        // From there on the schedule repeats every interval, so whole intervals that are already
+        // past can be skipped in one go, however long ago the todo was due
+        let (skipped, skipped_rule) = rule.skip_to(due, today)?;
+        due = skipped;
+        rule = skipped_rule;
+
+        // Which leaves at most an interval's worth of occurrences to step through
+        loop {
+            let (next, next_rule) = rule.step(due)?;
+            if next >= today {
+                return Some((next, next_rule));
+            }
+            due = next;
+            rule = next_rule;
+        }
+    }
+
+    // Jumps from `due`, an occurrence, over all the whole intervals that end before `today`.
+    // Each one holds the same number of occurrences, which count against COUNT.
+    fn skip_to(&self, due: NaiveDate, today: NaiveDate) -> Option<(NaiveDate, Recurrence)> {
+        let interval = u64::from(self.interval);
+        let (skipped, intervals, per_interval) = match self.freq {
+            Frequency::Daily | Frequency::Weekly => {
+                let (days, per_interval) = match self.freq {
+                    Frequency::Daily => (interval, 1),
+                    _ if self.by_day.is_empty() => (interval * 7, 1),
+                    _ => {
+                        let mut days = self.by_day.clone();
+                        days.sort_unstable_by_key(|d| d.num_days_from_monday());
+                        days.dedup();
+                        (interval * 7, days.len() as u64)
+                    }
+                };
+                let behind = (today - due).num_days().max(1) as u64;
+                let intervals = (behind - 1) / days;
+                (
+                    due.checked_add_days(Days::new(intervals * days))?,
+                    intervals,
+                    per_interval,
+                )
+            }
+            Frequency::Monthly => {
+                let month =
+                    |date: NaiveDate| i64::from(date.year()) * 12 + i64::from(date.month0());
+                let behind = (month(today) - month(due)).max(1) as u64;
+                let intervals = (behind - 1) / interval;
+                let months = u32::try_from(intervals * interval).ok()?;
+                let target = due.with_day(1)?.checked_add_months(Months::new(months))?;
+                let anchor = self.by_month_day.unwrap_or_else(|| due.day());
+                (on_day_clamped(target, anchor)?, intervals, 1)
+            }
+        };
+
+        let mut rule = self.clone();
+        if let Some(count) = self.count {
+            let left = u64::from(count).checked_sub(intervals * per_interval)?;
+            if left == 0 {
+                return None;
+            }
+            rule.count = Some(left as u32);
+        }
+        Some((skipped, rule))
+    }
+
+    // A single step along the schedule with no regard for today's date
+    fn step(&self, from: NaiveDate) -> Option<(NaiveDate, Recurrence)> {
+        let mut rule = self.clone();
+
+        if let Some(count) = self.count {
+            if count <= 1 {
+                return None;
+            }
+            rule.count = Some(count - 1);
+        }
+
+        let next = match self.freq {
+            Frequency::Daily => from.checked_add_days(Days::new(u64::from(self.interval)))?,
+            Frequency::Weekly => self.next_weekly(from)?,
+            Frequency::Monthly => {
+                // Without an explicit day of the month we anchor on the first due date we saw,
+                // otherwise a todo due on the 31st would drift to the 28th after February and
+                // stay there.
+                let anchor = self.by_month_day.unwrap_or_else(|| from.day());
+                rule.by_month_day = Some(anchor);
+                self.next_monthly(from, anchor)?
+            }
+        };
+
+        match self.until {
+            Some(until) if next > until => None,
+            _ => Some((next, rule)),
+        }
+    }
+
+    fn next_weekly(&self, from: NaiveDate) -> Option<NaiveDate> {
+        let weeks = Days::new(u64::from(self.interval) * 7);
+        if self.by_day.is_empty() {
+            return from.checked_add_days(weeks);
+        }
+
+        let mut days: Vec<u32> = self
+            .by_day
+            .iter()
+            .map(|d| d.num_days_from_monday())
+            .collect();
+        days.sort_unstable();
+
+        // Another listed day later this week wins, otherwise jump `interval` weeks ahead
+        // (weeks start on Monday, same as the RRULE default) and take the first listed day.
+        let today = from.weekday().num_days_from_monday();
+        let monday = from.checked_sub_days(Days::new(u64::from(today)))?;
+        match days.iter().find(|&&d| d > today) {
+            Some(&d) => monday.checked_add_days(Days::new(u64::from(d))),
+            None => monday
+                .checked_add_days(weeks)?
+                .checked_add_days(Days::new(u64::from(days[0]))),
+        }
+    }
+
+    fn next_monthly(&self, from: NaiveDate, anchor: u32) -> Option<NaiveDate> {
+        let this_month = from.with_day(1)?;
+
+        // With BYMONTHDAY a todo due earlier in the month still has this month's occurrence ahead
+        let candidate = on_day_clamped(this_month, anchor)?;
+        if candidate > from {
+            return Some(candidate);
+        }
+
+        let target = this_month.checked_add_months(Months::new(self.interval))?;
+        on_day_clamped(target, anchor)
+    }
+}
+
+// The given day in the month starting at `first`, or the last day of that month if it's shorter
+fn on_day_clamped(first: NaiveDate, day: u32) -> Option<NaiveDate> {
+    let last = first.checked_add_months(Months::new(1))?.pred_opt()?.day();
+    first.with_day(day.min(last))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseRecurrenceError(String);
+
+impl fmt::Display for ParseRecurrenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid recurrence rule: {}", self.0)
+    }
+}
+
+impl Error for ParseRecurrenceError {}
+
+fn invalid<T>(msg: impl Into<String>) -> Result<T, ParseRecurrenceError> {
+    Err(ParseRecurrenceError(msg.into()))
+}
+
+impl FromStr for Recurrence {
+    type Err = ParseRecurrenceError;
+
+    fn from_str(s: &str) -> Result<Recurrence, ParseRecurrenceError> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "daily" => return Ok(Recurrence::new(Frequency::Daily)),
+            "weekly" => return Ok(Recurrence::new(Frequency::Weekly)),
+            "monthly" => return Ok(Recurrence::new(Frequency::Monthly)),
+            _ => {}
+        }
+
+        let body = s.strip_prefix("RRULE:").unwrap_or(s);
+        let mut freq = None;
+        let mut rule = Recurrence::new(Frequency::Daily);
+
+        for part in body.split(';').filter(|p| !p.is_empty()) {
+            let (key, value) = match part.split_once('=') {
+                Some(kv) => kv,
+                None => return invalid(format!("expected KEY=VALUE, got `{}`", part)),
+            };
+
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        _ => return invalid(format!("unsupported FREQ `{}`", value)),
+                    })
+                }
+                "INTERVAL" => match value.parse() {
+                    Ok(n) if n > 0 => rule.interval = n,
+                    _ => {
+                        return invalid(format!(
+                            "INTERVAL must be a positive number, got `{}`",
+                            value
+                        ))
+                    }
+                },
+                "BYDAY" => {
+                    rule.by_day = value
+                        .split(',')
+                        .map(parse_weekday)
+                        .collect::<Result<_, _>>()?;
+                }
+                "BYMONTHDAY" => match value.parse() {
+                    Ok(n) if (1..=31).contains(&n) => rule.by_month_day = Some(n),
+                    _ => {
+                        return invalid(format!(
+                            "BYMONTHDAY must be between 1 and 31, got `{}`",
+                            value
+                        ))
+                    }
+                },
+                "COUNT" => match value.parse() {
+                    Ok(n) if n > 0 => rule.count = Some(n),
+                    _ => {
+                        return invalid(format!("COUNT must be a positive number, got `{}`", value))
+                    }
+                },
+                "UNTIL" => match NaiveDate::parse_from_str(value, "%Y%m%d") {
+                    Ok(date) => rule.until = Some(date),
+                    Err(_) => {
+                        return invalid(format!("UNTIL must be a YYYYMMDD date, got `{}`", value))
+                    }
+                },
+                _ => return invalid(format!("unsupported rule part `{}`", key)),
+            }
+        }
+
+        rule.freq = match freq {
+            Some(freq) => freq,
+            None => return invalid("missing FREQ"),
+        };
+        if !rule.by_day.is_empty() && rule.freq != Frequency::Weekly {
+            return invalid("BYDAY is only supported for weekly rules");
+        }
+        if rule.by_month_day.is_some() && rule.freq != Frequency::Monthly {
+            return invalid("BYMONTHDAY is only supported for monthly rules");
+        }
+        if rule.count.is_some() && rule.until.is_some() {
+            return invalid("COUNT and UNTIL can't be used together");
+        }
+
+        Ok(rule)
+    }
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday, ParseRecurrenceError> {
+    Ok(match s.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return invalid(format!("unknown BYDAY value `{}`", s)),
+    })
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let freq = match self.freq {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+        };
+
+        // Plain rules go back out the same way they most likely came in
+        if *self == Recurrence::new(self.freq) {
+            return write!(f, "{}", freq.to_ascii_lowercase());
+        }
+
+        write!(f, "FREQ={}", freq)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<&str> = self.by_day.iter().map(|d| weekday_code(*d)).collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if let Some(day) = self.by_month_day {
+            write!(f, ";BYMONTHDAY={}", day)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", until.format("%Y%m%d"))?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Recurrence {
+    type Error = ParseRecurrenceError;
+
+    fn try_from(s: String) -> Result<Recurrence, ParseRecurrenceError> {
+        s.parse()
+    }
+}
+
+impl From<Recurrence> for String {
+    fn from(rule: Recurrence) -> String {
+        rule.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn rule(s: &str) -> Recurrence {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_shorthands_and_rrules() {
+        assert_eq!(rule("Weekly"), Recurrence::new(Frequency::Weekly));
+        assert_eq!(rule("RRULE:FREQ=DAILY"), Recurrence::new(Frequency::Daily));
+
+        let parsed = rule("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH;COUNT=4");
+        assert_eq!(parsed.freq, Frequency::Weekly);
+        assert_eq!(parsed.interval, 2);
+        assert_eq!(parsed.by_day, vec![Weekday::Mon, Weekday::Thu]);
+        assert_eq!(parsed.count, Some(4));
+        assert_eq!(
+            parsed.to_string(),
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH;COUNT=4"
+        );
+        assert_eq!(rule("monthly").to_string(), "monthly");
+    }
+
+    #[test]
+    fn rejects_unsupported_rules() {
+        for bad in &[
+            "yearly",
+            "INTERVAL=2",
+            "FREQ=HOURLY",
+            "FREQ=DAILY;INTERVAL=0",
+            "FREQ=DAILY;BYDAY=MO",
+            "FREQ=MONTHLY;BYMONTHDAY=32",
+            "FREQ=DAILY;COUNT=2;UNTIL=20300101",
+            "FREQ=DAILY;BYSETPOS=1",
+        ] {
+            assert!(
+                bad.parse::<Recurrence>().is_err(),
+                "{} should not parse",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn daily_and_weekly_steps() {
+        let (next, _) = rule("FREQ=DAILY;INTERVAL=3")
+            .advance(date(2021, 3, 1), date(2021, 3, 1))
+            .unwrap();
+        assert_eq!(next, date(2021, 3, 4));
+
+        // 2021-03-01 is a Monday
+        let mo_th = rule("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH");
+        let (next, _) = mo_th.advance(date(2021, 3, 1), date(2021, 3, 1)).unwrap();
+        assert_eq!(next, date(2021, 3, 4));
+        let (next, _) = mo_th.advance(date(2021, 3, 4), date(2021, 3, 4)).unwrap();
+        assert_eq!(next, date(2021, 3, 15));
+    }
+
+    #[test]
+    fn monthly_keeps_its_anchor_through_short_months() {
+        let monthly = rule("monthly");
+        let (feb, pinned) = monthly
+            .advance(date(2021, 1, 31), date(2021, 1, 31))
+            .unwrap();
+        assert_eq!(feb, date(2021, 2, 28));
+        let (mar, _) = pinned.advance(feb, feb).unwrap();
+        assert_eq!(mar, date(2021, 3, 31));
+
+        let mid_month = rule("FREQ=MONTHLY;BYMONTHDAY=15");
+        let (next, _) = mid_month
+            .advance(date(2021, 1, 3), date(2021, 1, 3))
+            .unwrap();
+        assert_eq!(next, date(2021, 1, 15));
+    }
+
+    #[test]
+    fn skips_missed_occurrences_and_stops_when_exhausted() {
+        let (next, rest) = rule("FREQ=DAILY;COUNT=5")
+            .advance(date(2021, 3, 1), date(2021, 3, 3))
+            .unwrap();
+        assert_eq!(next, date(2021, 3, 3));
+        assert_eq!(rest.count, Some(3));
+
+        assert_eq!(
+            rule("FREQ=DAILY;COUNT=1").advance(date(2021, 3, 1), date(2021, 3, 1)),
+            None
+        );
+        assert_eq!(
+            rule("FREQ=WEEKLY;UNTIL=20210305").advance(date(2021, 3, 1), date(2021, 3, 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn runs_out_of_calendar_without_panicking() {
+        let today = date(2021, 3, 1);
+        for huge in &[
+            "FREQ=DAILY;INTERVAL=4294967295",
+            "FREQ=WEEKLY;INTERVAL=4294967295",
+            "FREQ=WEEKLY;INTERVAL=4294967295;BYDAY=MO",
+            "FREQ=MONTHLY;INTERVAL=4294967295",
+        ] {
+            assert_eq!(rule(huge).advance(today, today), None, "{}", huge);
+        }
+        for plain in &["daily", "weekly", "FREQ=WEEKLY;BYDAY=SU", "monthly"] {
+            let end = NaiveDate::MAX;
+            assert_eq!(rule(plain).advance(end, end), None, "{}", plain);
+        }
+    }
+
+    #[test]
+    fn catches_up_from_long_ago_in_one_go() {
+        let today = date(2021, 3, 3);
+        let ancient = date(-200_000, 1, 1);
+        for (rule_text, expected) in &[
+            ("daily", today),
+            ("FREQ=WEEKLY;BYDAY=MO,TH", date(2021, 3, 4)),
+            ("FREQ=MONTHLY;BYMONTHDAY=31", date(2021, 3, 31)),
+        ] {
+            let (next, _) = rule(rule_text).advance(ancient, today).unwrap();
+            assert_eq!(next, *expected, "{}", rule_text);
+        }
+        // These keep to the weekday the todo was first due on
+        for rule_text in &["weekly", "FREQ=DAILY;INTERVAL=7"] {
+            let (next, _) = rule(rule_text).advance(ancient, today).unwrap();
+            assert!(next >= today && next < today + Duration::days(7));
+            assert_eq!(next.weekday(), ancient.weekday(), "{}", rule_text);
+        }
+
+        let (_, rest) = rule("FREQ=DAILY;COUNT=4294967295")
+            .advance(ancient, today)
+            .unwrap();
+        assert!(rest.count.unwrap() < 4_294_967_295 - 73_000_000);
+        assert_eq!(rule("FREQ=DAILY;COUNT=1000").advance(ancient, today), None);
+    }
+
+    #[test]
+    fn skipping_matches_stepping() {
+        // Walks one occurrence at a time, as advance did before it learned to skip
+        fn walk(rule: &Recurrence, from: NaiveDate, today: NaiveDate) -> Option<NaiveDate> {
+            let (mut due, mut rule) = (from, rule.clone());
+            loop {
+                let (next, next_rule) = rule.step(due)?;
+                if next >= today {
+                    return Some(next);
+                }
+                due = next;
+                rule = next_rule;
+            }
+        }
+
+        let today = date(2021, 3, 3);
+        for rule_text in &[
+            "FREQ=DAILY;INTERVAL=3;COUNT=200",
+            "FREQ=WEEKLY;INTERVAL=2;COUNT=60",
+            "FREQ=WEEKLY;INTERVAL=3;BYDAY=TU,SA,TU;COUNT=90",
+            "FREQ=WEEKLY;BYDAY=MO,WE,FR;UNTIL=20210302",
+            "FREQ=MONTHLY;INTERVAL=5;COUNT=6",
+            "FREQ=MONTHLY;BYMONTHDAY=30",
+            "monthly",
+        ] {
+            let recurrence = rule(rule_text);
+            for days_ago in (0..800).step_by(13) {
+                let from = today - Duration::days(days_ago);
+                assert_eq!(
+                    recurrence.advance(from, today).map(|(next, _)| next),
+                    walk(&recurrence, from, today),
+                    "{} from {}",
+                    rule_text,
+                    from
+                );
+            }
+        }
+    }
+}
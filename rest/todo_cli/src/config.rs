@@ -0,0 +1,82 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What the CLI remembers between runs, stored as TOML in the user's config directory
+/// (`~/.config/todo/config.toml` on Linux).
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Config {
+    pub server: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Config {
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("todo").join("config.toml"))
+    }
+
+    /// Loads the config at `path`, or an empty one if it hasn't been written yet.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the config out, creating the directory if needed.  The file holds a token so on
+    /// unix it's only readable by its owner.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents =
+            toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // Write then rename, so a crash halfway never leaves a truncated config behind
+        let tmp = path.with_extension("toml.tmp");
+        fs::write(&tmp, contents)?;
+        restrict_permissions(&tmp)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_config_is_empty_and_saved_config_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("config.toml");
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        let config = Config {
+            server: Some("http://todo.internal:3030".into()),
+            token: Some("my-token".into()),
+        };
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap(), config);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
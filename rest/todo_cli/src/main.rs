@@ -0,0 +1,346 @@
+use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use todo_api::recurrence::Recurrence;
+use todo_api::{Client, ListOptions, Todo};
+
+mod config;
+mod output;
+
+use config::Config;
+use output::Format;
+
+const DEFAULT_SERVER: &str = "http://127.0.0.1:3030";
+
+/// Manage todos on a rest Todo server from the terminal.
+#[derive(Debug, Parser)]
+#[command(name = "todo")]
+struct Cli {
+    /// Server to talk to, overriding the one saved by `todo login`
+    #[arg(long, env = "TODO_SERVER", global = true)]
+    server: Option<String>,
+
+    /// Where the server and token are saved
+    #[arg(long, env = "TODO_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// How to print results
+    #[arg(long, short, value_enum, default_value = "table", global = true)]
+    output: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List todos
+    List {
+        #[arg(long)]
+        offset: Option<usize>,
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Leave out completed todos
+        #[arg(long)]
+        pending: bool,
+    },
+    /// Add a todo
+    Add {
+        /// What needs doing
+        #[arg(required = true)]
+        text: Vec<String>,
+        /// Id for the new todo, defaults to one more than the highest in use
+        #[arg(long)]
+        id: Option<u64>,
+        /// Due date as YYYY-MM-DD
+        #[arg(long)]
+        due: Option<chrono::NaiveDate>,
+        /// Make it recurring: daily, weekly, monthly or an RRULE like FREQ=WEEKLY;BYDAY=MO
+        #[arg(long, value_name = "RULE")]
+        every: Option<Recurrence>,
+    },
+    /// Mark a todo as completed, scheduling the next one if it recurs
+    Done { id: u64 },
+    /// Change an existing todo
+    Edit {
+        id: u64,
+        #[arg(long)]
+        text: Option<String>,
+        #[arg(long, conflicts_with = "no_due")]
+        due: Option<chrono::NaiveDate>,
+        /// Remove the due date
+        #[arg(long)]
+        no_due: bool,
+        #[arg(long, value_name = "RULE", conflicts_with = "no_repeat")]
+        every: Option<Recurrence>,
+        /// Stop the todo recurring
+        #[arg(long)]
+        no_repeat: bool,
+        /// Mark it as not completed again
+        #[arg(long)]
+        undone: bool,
+    },
+    /// Delete a todo (needs a token, see `login`)
+    Rm { id: u64 },
+    /// Save a token, and optionally the server, for later commands
+    Login {
+        /// The token to save; read from stdin when left out
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    let config_path = match cli.config.clone().or_else(Config::default_path) {
+        Some(path) => path,
+        None => {
+            eprintln!("todo: can't work out a config directory, pass --config");
+            process::exit(2);
+        }
+    };
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(e) = run(cli, &config_path, &mut stdin.lock(), &mut stdout.lock()).await {
+        eprintln!("todo: {}", e);
+        process::exit(1);
+    }
+}
+
+// Everything main does once the arguments are parsed, with the input and output passed in so
+// tests can drive it
+async fn run(
+    cli: Cli,
+    config_path: &Path,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut config = Config::load(config_path)?;
+    let server = cli
+        .server
+        .clone()
+        .or_else(|| config.server.clone())
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+
+    let mut client = Client::new(&server)?;
+    client.set_token(config.token.clone());
+
+    match cli.command {
+        Command::List {
+            offset,
+            limit,
+            pending,
+        } => {
+            let mut todos = client.list(&ListOptions { offset, limit }).await?;
+            if pending {
+                todos.retain(|todo| !todo.completed);
+            }
+            output::print_todos(out, &todos, cli.output)?;
+        }
+        Command::Add {
+            text,
+            id,
+            due,
+            every,
+        } => {
+            let id = match id {
+                Some(id) => id,
+                None => next_id(&client).await?,
+            };
+            let todo = Todo {
+                due,
+                recurrence: every,
+                ..Todo::new(id, text.join(" "))
+            };
+            client.create(&todo).await?;
+            output::print_todo(out, &todo, cli.output)?;
+        }
+        Command::Done { id } => {
+            let mut todo = find(&client, id).await?;
+            todo.completed = true;
+            let next = client.update(id, &todo).await?;
+
+            let mut changed = vec![todo];
+            changed.extend(next);
+            output::print_todos(out, &changed, cli.output)?;
+        }
+        Command::Edit {
+            id,
+            text,
+            due,
+            no_due,
+            every,
+            no_repeat,
+            undone,
+        } => {
+            let mut todo = find(&client, id).await?;
+            if let Some(text) = text {
+                todo.text = text;
+            }
+            if due.is_some() || no_due {
+                todo.due = due;
+            }
+            if every.is_some() || no_repeat {
+                todo.recurrence = every;
+            }
+            if undone {
+                todo.completed = false;
+            }
+            client.update(id, &todo).await?;
+            output::print_todo(out, &todo, cli.output)?;
+        }
+        Command::Rm { id } => {
+            client.delete(id).await?;
+            if cli.output == Format::Table {
+                writeln!(out, "Deleted todo {}", id)?;
+            }
+        }
+        Command::Login { token } => {
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    eprint!("Token: ");
+                    let mut line = String::new();
+                    input.read_line(&mut line)?;
+                    line.trim().to_string()
+                }
+            };
+            if token.is_empty() {
+                return Err("no token given".into());
+            }
+
+            config.token = Some(token);
+            if cli.server.is_some() {
+                config.server = cli.server;
+            }
+            config.save(config_path)?;
+            writeln!(
+                out,
+                "Saved login for {} to {}",
+                server,
+                config_path.display()
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// There's no route for fetching a single todo, so look through the full list
+async fn find(client: &Client, id: u64) -> Result<Todo, todo_api::Error> {
+    client
+        .list(&ListOptions::default())
+        .await?
+        .into_iter()
+        .find(|todo| todo.id == id)
+        .ok_or(todo_api::Error::NotFound)
+}
+
+// The server leaves picking ids to clients
+async fn next_id(client: &Client) -> Result<u64, todo_api::Error> {
+    let todos = client.list(&ListOptions::default()).await?;
+    Ok(todos.iter().map(|todo| todo.id).max().unwrap_or(0) + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rest::{clock, filters, idempotency, models};
+    use std::sync::Arc;
+
+    struct Harness {
+        server: String,
+        config: tempfile::TempDir,
+    }
+
+    impl Harness {
+        async fn start() -> Harness {
+            let keys = Arc::new(idempotency::Store::new(chrono::Duration::hours(1)));
+            let api = filters::todos(models::blank_db(), clock::system_clock(), keys);
+            let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+
+            Harness {
+                server: format!("http://{}", addr),
+                config: tempfile::tempdir().unwrap(),
+            }
+        }
+
+        // Runs a command line the way main would, returning what it printed
+        async fn todo(&self, args: &[&str], stdin: &str) -> Result<String, Box<dyn Error>> {
+            let cli = Cli::try_parse_from(std::iter::once("todo").chain(args.iter().copied()))?;
+            let mut out = Vec::new();
+            let path = self.config.path().join("config.toml");
+            run(cli, &path, &mut stdin.as_bytes(), &mut out).await?;
+            Ok(String::from_utf8(out).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn manage_todos_end_to_end() {
+        let h = Harness::start().await;
+        let server = h.server.as_str();
+
+        h.todo(&["login", "--server", server], "my-token\n")
+            .await
+            .unwrap();
+
+        h.todo(&["add", "water", "the", "plants", "--every", "weekly"], "")
+            .await
+            .unwrap();
+        h.todo(&["add", "file taxes", "--due", "2021-04-15"], "")
+            .await
+            .unwrap();
+
+        let listed = h.todo(&["list", "-o", "json"], "").await.unwrap();
+        let todos: Vec<Todo> = serde_json::from_str(&listed).unwrap();
+        assert_eq!(todos.len(), 2);
+        assert_eq!(todos[0].text, "water the plants");
+        assert_eq!(todos[1].id, 2);
+
+        // Completing the recurring one prints it alongside its next occurrence
+        let done = h.todo(&["done", "1", "-o", "json"], "").await.unwrap();
+        let done: Vec<Todo> = serde_json::from_str(&done).unwrap();
+        assert!(done[0].completed);
+        assert_eq!(done[1].id, 3);
+
+        h.todo(&["edit", "2", "--text", "file taxes!", "--no-due"], "")
+            .await
+            .unwrap();
+        h.todo(&["rm", "1"], "").await.unwrap();
+
+        let pending = h.todo(&["list", "--pending"], "").await.unwrap();
+        assert!(pending.contains("file taxes!"));
+        assert!(!pending.contains("2021-04-15"));
+        assert_eq!(pending.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn rm_without_login_fails() {
+        let h = Harness::start().await;
+        let server = h.server.as_str();
+
+        h.todo(&["--server", server, "add", "keep me"], "")
+            .await
+            .unwrap();
+        let err = h
+            .todo(&["--server", server, "rm", "1"], "")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("log in"));
+
+        h.todo(&["--server", server, "login", "--token", "wrong"], "")
+            .await
+            .unwrap();
+        let err = h
+            .todo(&["--server", server, "rm", "1"], "")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not authorized"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{self, Write};
+use todo_api::Todo;
+
+/// How results get printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Aligned columns for people
+    Table,
+    /// The API's own JSON for scripts
+    Json,
+}
+
+pub fn print_todos(out: &mut dyn Write, todos: &[Todo], format: Format) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, todos)?;
+            writeln!(out)
+        }
+        Format::Table => print_table(out, todos),
+    }
+}
+
+pub fn print_todo(out: &mut dyn Write, todo: &Todo, format: Format) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, todo)?;
+            writeln!(out)
+        }
+        Format::Table => print_table(out, std::slice::from_ref(todo)),
+    }
+}
+
+const HEADERS: [&str; 5] = ["ID", "DONE", "DUE", "REPEAT", "TEXT"];
+
+fn print_table(out: &mut dyn Write, todos: &[Todo]) -> io::Result<()> {
+    let rows: Vec<[String; 5]> = todos
+        .iter()
+        .map(|todo| {
+            [
+                todo.id.to_string(),
+                if todo.completed { "x" } else { "" }.to_string(),
+                todo.due.map(|due| due.to_string()).unwrap_or_default(),
+                todo.recurrence
+                    .as_ref()
+                    .map(|r| r.to_string())
+                    .unwrap_or_default(),
+                todo.text.clone(),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADERS.map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    write_row(out, &HEADERS.map(String::from), &widths)?;
+    for row in &rows {
+        write_row(out, row, &widths)?;
+    }
+    Ok(())
+}
+
+fn write_row(out: &mut dyn Write, cells: &[String; 5], widths: &[usize; 5]) -> io::Result<()> {
+    let mut line = String::new();
+    for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+        if i > 0 {
+            line.push_str("  ");
+        }
+        line.push_str(cell);
+        // No padding after the last column, nobody wants trailing whitespace
+        if i < cells.len() - 1 {
+            line.extend(std::iter::repeat_n(' ', width - cell.chars().count()));
+        }
+    }
+    writeln!(out, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_columns_line_up() {
+        let mut chore = Todo::new(12, "rotate on-call");
+        chore.recurrence = Some("weekly".parse().unwrap());
+        chore.due = chrono::NaiveDate::from_ymd_opt(2021, 3, 1);
+        let mut done = Todo::new(3, "ship it");
+        done.completed = true;
+
+        let mut out = Vec::new();
+        print_todos(&mut out, &[chore, done], Format::Table).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ID  DONE  DUE         REPEAT  TEXT\n\
+             12        2021-03-01  weekly  rotate on-call\n\
+             3   x                         ship it\n"
+        );
+    }
+}
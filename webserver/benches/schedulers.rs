@@ -0,0 +1,172 @@
+// Compares the ThreadPool schedulers on throughput and on how long jobs wait to start, with
+// tiny jobs, larger ones, and tiny ones queued by other jobs.
+//
+//     cargo bench --bench schedulers
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+use webserver::{Scheduler, ThreadPool};
+
+const ROUNDS: usize = 3;
+
+#[derive(Clone, Copy)]
+enum Scenario {
+    // Jobs that do next to nothing, so the scheduler's overhead is all there is
+    Tiny,
+    // Jobs that each compute for a while
+    Large,
+    // Tiny jobs queued from inside other jobs, fanning out
+    Nested,
+}
+
+impl Scenario {
+    fn name(self) -> &'static str {
+        match self {
+            Scenario::Tiny => "tiny",
+            Scenario::Large => "large",
+            Scenario::Nested => "nested",
+        }
+    }
+
+    fn jobs(self) -> usize {
+        match self {
+            Scenario::Tiny => 100_000,
+            Scenario::Large => 5_000,
+            Scenario::Nested => FAN_OUT * 1_000,
+        }
+    }
+}
+
+const FAN_OUT: usize = 100;
+
+// Shared by every job in a run
+struct Run {
+    // Nanoseconds each job waited between being queued and starting
+    waits: Vec<AtomicU64>,
+    done: AtomicUsize,
+    main: Thread,
+}
+
+impl Run {
+    fn new(jobs: usize) -> Arc<Run> {
+        Arc::new(Run {
+            waits: (0..jobs).map(|_| AtomicU64::new(0)).collect(),
+            done: AtomicUsize::new(0),
+            main: thread::current(),
+        })
+    }
+
+    // Queues job `index`, which does `work` once it starts
+    fn queue(self: &Arc<Run>, pool: &ThreadPool, index: usize, work: fn()) {
+        let run = Arc::clone(self);
+        let queued = Instant::now();
+        pool.execute(move || {
+            let wait = queued.elapsed().as_nanos() as u64;
+            run.waits[index].store(wait, Ordering::Relaxed);
+            work();
+            if run.done.fetch_add(1, Ordering::AcqRel) + 1 == run.waits.len() {
+                run.main.unpark();
+            }
+        })
+        .unwrap();
+    }
+
+    fn wait(&self) {
+        while self.done.load(Ordering::Acquire) < self.waits.len() {
+            thread::park();
+        }
+    }
+}
+
+fn tiny() {
+    black_box(1 + 1);
+}
+
+fn large() {
+    let mut x = 1u64;
+    for i in 0..20_000 {
+        x = black_box(x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(i));
+    }
+}
+
+// Runs the scenario once, giving how long it took and every job's wait
+fn run_once(pool: &'static ThreadPool, scenario: Scenario) -> (Duration, Vec<u64>) {
+    let jobs = scenario.jobs();
+    let run = Run::new(jobs);
+    let start = Instant::now();
+    match scenario {
+        Scenario::Tiny => (0..jobs).for_each(|i| run.queue(pool, i, tiny)),
+        Scenario::Large => (0..jobs).for_each(|i| run.queue(pool, i, large)),
+        Scenario::Nested => {
+            for outer in 0..jobs / FAN_OUT {
+                let run = Arc::clone(&run);
+                pool.execute(move || {
+                    for inner in 0..FAN_OUT {
+                        run.queue(pool, outer * FAN_OUT + inner, tiny);
+                    }
+                })
+                .unwrap();
+            }
+        }
+    }
+    run.wait();
+    let took = start.elapsed();
+
+    let waits = run.waits.iter().map(|wait| wait.load(Ordering::Relaxed));
+    (took, waits.collect())
+}
+
+fn percentile(sorted: &[u64], percent: f64) -> Duration {
+    let index = ((sorted.len() - 1) as f64 * percent / 100.0).round() as usize;
+    Duration::from_nanos(sorted[index])
+}
+
+fn micros(duration: Duration) -> String {
+    format!("{:.1}µs", duration.as_secs_f64() * 1e6)
+}
+
+fn main() {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    println!("{} threads, best of {} rounds", threads, ROUNDS);
+    println!(
+        "{:<8} {:<14} {:>12} {:>10} {:>10} {:>10} {:>10}",
+        "jobs", "scheduler", "jobs/s", "p50", "p99", "p99.9", "max"
+    );
+
+    for scenario in [Scenario::Tiny, Scenario::Large, Scenario::Nested] {
+        for (name, scheduler) in [
+            ("shared queue", Scheduler::SharedQueue),
+            ("work stealing", Scheduler::WorkStealing),
+        ] {
+            // Leaked, as nested jobs need the pool to outlive them
+            let pool: &'static ThreadPool = Box::leak(Box::new(
+                ThreadPool::builder()
+                    .max_threads(threads)
+                    .min_threads(threads)
+                    .scheduler(scheduler)
+                    .build()
+                    .unwrap(),
+            ));
+
+            // The fastest round, as the others only tell us what else the machine was doing
+            let (took, mut waits) = (0..ROUNDS)
+                .map(|_| run_once(pool, scenario))
+                .min_by_key(|(took, _)| *took)
+                .unwrap();
+            waits.sort_unstable();
+            println!(
+                "{:<8} {:<14} {:>12.0} {:>10} {:>10} {:>10} {:>10}",
+                scenario.name(),
+                name,
+                scenario.jobs() as f64 / took.as_secs_f64(),
+                micros(percentile(&waits, 50.0)),
+                micros(percentile(&waits, 99.0)),
+                micros(percentile(&waits, 99.9)),
+                micros(percentile(&waits, 100.0)),
+            );
+        }
+    }
+}
@@ -1,52 +1,204 @@
-use std::fs;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::env;
+use std::path::PathBuf;
+use std::process;
 use std::thread;
 use std::time::Duration;
-use webserver::ThreadPool;
+use webserver::events::{Events, Level, Printer};
+use webserver::{KeepAlive, Request, Response, Router, Server, StaticFiles, StatusCode};
 
-fn main() {
-    println!("Beginning webserver");
-    let listener = TcpListener::bind("0.0.0.0:7878").unwrap();
-    let pool = ThreadPool::new(4);
+const USAGE: &str = "usage: main [--root DIR] [--listings] [--cache-control PREFIX=VALUE]... \
+                     [--idle-timeout SECS] [--max-requests N] [--threads N] \
+                     [--max-threads N] [--queue N] [--shutdown-timeout SECS] \
+                     [--log off|error|warn|info|debug]";
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+/// Serves the files under `--root`, `public` by default, with listings of directories that have
+/// no `index.html` if `--listings` is given.  Each `--cache-control` sets the `Cache-Control`
+/// header for files under a path prefix, e.g. `--cache-control /assets/=max-age=86400`.
+/// `--idle-timeout` and `--max-requests` limit how long a connection is kept open for.
+/// `--threads` connections are served at once, rising to `--max-threads` when busy, with
+/// `--queue` more, at least 1, waiting for a thread before the rest get a 503.
+/// `--shutdown-timeout` is how long requests get to finish after Ctrl-C or SIGTERM.  `--log` is
+/// how much to report on stderr, `info` by default.
+struct Config {
+    root: PathBuf,
+    listings: bool,
+    cache_control: Vec<(String, String)>,
+    keep_alive: KeepAlive,
+    threads: usize,
+    max_threads: usize,
+    queue: usize,
+    shutdown_timeout: Duration,
+    // None for off
+    log: Option<Level>,
+}
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            root: PathBuf::from("public"),
+            listings: false,
+            cache_control: Vec::new(),
+            keep_alive: KeepAlive::default(),
+            threads: 4,
+            max_threads: 32,
+            queue: 64,
+            shutdown_timeout: Duration::from_secs(10),
+            log: Some(Level::Info),
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--root" => {
+                    config.root = args.next().ok_or("--root needs a directory")?.into();
+                }
+                "--listings" => config.listings = true,
+                "--cache-control" => {
+                    let rule = args.next().ok_or("--cache-control needs PREFIX=VALUE")?;
+                    let (prefix, value) = rule
+                        .split_once('=')
+                        .ok_or_else(|| format!("--cache-control {} has no =", rule))?;
+                    config
+                        .cache_control
+                        .push((prefix.to_string(), value.to_string()));
+                }
+                "--idle-timeout" => {
+                    let secs = number(args.next(), "--idle-timeout needs a number of seconds")?;
+                    config.keep_alive.idle_timeout = Duration::from_secs(secs);
+                }
+                "--max-requests" => {
+                    let max = number(args.next(), "--max-requests needs a number")?;
+                    config.keep_alive.max_requests = max.max(1) as usize;
+                }
+                "--threads" => {
+                    let threads = number(args.next(), "--threads needs a number")?;
+                    config.threads = threads.max(1) as usize;
+                }
+                "--max-threads" => {
+                    let threads = number(args.next(), "--max-threads needs a number")?;
+                    config.max_threads = threads.max(1) as usize;
+                }
+                "--queue" => {
+                    let queue = number(args.next(), "--queue needs a number")?;
+                    config.queue = queue.max(1) as usize;
+                }
+                "--shutdown-timeout" => {
+                    let secs = number(args.next(), "--shutdown-timeout needs a number of seconds")?;
+                    config.shutdown_timeout = Duration::from_secs(secs);
+                }
+                "--log" => {
+                    config.log = match args.next().as_deref() {
+                        Some("off") => None,
+                        Some("error") => Some(Level::Error),
+                        Some("warn") => Some(Level::Warn),
+                        Some("info") => Some(Level::Info),
+                        Some("debug") => Some(Level::Debug),
+                        _ => return Err("--log needs off, error, warn, info or debug".into()),
+                    };
+                }
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        Ok(config)
     }
-
-    println!("Shutting down");
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+fn number(arg: Option<String>, error: &str) -> Result<u64, String> {
+    arg.and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| error.to_string())
+}
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+    let events = match config.log {
+        Some(level) => Events::new(Printer::new(level)),
+        None => Events::none(),
+    };
+    // Starting and stopping are logged at info like the server's own events, so `--log` quiets
+    // them too
+    let log = config.log;
+    let info = |message: &str| {
+        if log >= Some(Level::Info) {
+            eprintln!("[{}] {}", Level::Info, message);
+        }
+    };
+    let files = match StaticFiles::new(&config.root) {
+        Ok(files) => config.cache_control.into_iter().fold(
+            files
+                .with_listings(config.listings)
+                .with_events(events.clone()),
+            |files, (prefix, value)| files.with_cache_control(prefix, value),
+        ),
+        Err(e) => {
+            eprintln!("Can't serve {}: {}", config.root.display(), e);
+            process::exit(1);
+        }
+    };
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "sleep.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
+    info(&format!("serving {}", files.root().display()));
+    let server = match Server::bind("0.0.0.0:7878", router(files)) {
+        Ok(server) => server
+            .keep_alive(config.keep_alive)
+            .threads(config.threads)
+            .max_threads(config.max_threads)
+            .queue(config.queue)
+            .shutdown_timeout(config.shutdown_timeout)
+            .events(events),
+        Err(e) => {
+            eprintln!("Can't listen on port 7878: {}", e);
+            process::exit(1);
+        }
     };
 
-    let contents = fs::read_to_string(filename).unwrap();
+    // Ctrl-C or SIGTERM
+    let handle = server.shutdown_handle();
+    if let Err(e) = ctrlc::set_handler(move || handle.shutdown()) {
+        eprintln!("Can't handle signals: {}", e);
+        process::exit(1);
+    }
+
+    if let Err(e) = server.run() {
+        eprintln!("Server failed: {}", e);
+        process::exit(1);
+    }
+    info("shut down");
+}
 
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line,
-        contents.len(),
-        contents
-    );
+fn router(files: StaticFiles) -> Router {
+    let site = files.clone();
+    Router::new()
+        .get("/sleep", move |request, _| {
+            thread::sleep(Duration::from_secs(5));
+            files.serve(request, "sleep.html")
+        })
+        .get("/*path", move |request, params| {
+            let response = site.serve(request, params.get("path").unwrap_or_default());
+            if response.status == StatusCode::NotFound {
+                not_found_page(&site, request)
+            } else {
+                response
+            }
+        })
+}
+
+// The site's own 404.html if it has one
+fn not_found_page(files: &StaticFiles, request: &Request) -> Response {
+    // The conditional and range headers were about the missing file, not the 404 page, and
+    // mustn't turn the answer into a 304 or 206
+    let mut request = request.clone();
+    for header in ["If-None-Match", "If-Modified-Since", "Range", "If-Range"] {
+        request.headers.remove(header);
+    }
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    let mut response = files.serve(&request, "404.html");
+    if response.status == StatusCode::Ok {
+        response.status = StatusCode::NotFound;
+        // Only the page itself is cacheable, not the fact the path is missing
+        response.headers.remove("ETag");
+        response.headers.remove("Last-Modified");
+        response.headers.remove("Accept-Ranges");
+    }
+    response
 }
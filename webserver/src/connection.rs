@@ -0,0 +1,238 @@
+use std::io::{self, BufWriter, Read};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::events::{Event, Events};
+use crate::request::{Method, Parser, ReadError, Request, Version};
+use crate::response::{Response, StatusCode};
+use crate::router::Router;
+
+// How long to wait for the client to close its end after we've closed ours, and how much it
+// can send meanwhile
+const LINGER: Duration = Duration::from_secs(2);
+const MAX_LINGER_BYTES: usize = 1024 * 1024;
+
+// Reads give up this often to see whether the server is shutting down
+const POLL: Duration = Duration::from_millis(100);
+
+/// How long a connection is kept open for more requests.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// How long to wait for the client to send or read anything before giving up on it, both
+    /// between requests and part way through one.
+    pub idle_timeout: Duration,
+    /// Requests served on one connection before it's closed, so one client can't hold a worker
+    /// forever.
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// Answers requests on `stream` until the client closes it, asks to with `Connection: close`,
+/// goes quiet for longer than the idle timeout or reaches the request limit.
+///
+/// Pipelined requests, sent without waiting for the responses to earlier ones, are answered in
+/// the order they came.
+pub fn serve(stream: TcpStream, router: &Router, keep_alive: &KeepAlive) {
+    let stop = AtomicBool::new(false);
+    serve_until(stream, router, keep_alive, &stop, &Events::none());
+}
+
+/// Like `serve`, but also finishes early once `stop` is set: an idle connection is closed
+/// straight away, and one part way through a request gets its response and then is closed.
+/// Anything that goes wrong is reported to `events`.
+pub fn serve_until(
+    stream: TcpStream,
+    router: &Router,
+    keep_alive: &KeepAlive,
+    stop: &AtomicBool,
+    events: &Events,
+) {
+    if let Err(error) = stream
+        .set_read_timeout(Some(POLL.min(keep_alive.idle_timeout)))
+        .and_then(|_| stream.set_write_timeout(Some(keep_alive.idle_timeout)))
+    {
+        failed(events, "set connection timeouts", &error);
+        return;
+    }
+
+    // One parser for the whole connection, as it holds on to any bytes of the next request that
+    // came in with the last
+    let mut parser = Parser::new();
+    let mut reader = Activity {
+        stream: &stream,
+        last: Instant::now(),
+    };
+    let mut served = 0;
+    loop {
+        let request = match parser.read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            // Closed between requests
+            Ok(None) => return,
+            Err(ReadError::Io(e)) if is_timeout(&e) => {
+                if parser.is_idle() && stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                // The parser keeps what it's read so far, so it can just carry on
+                if reader.last.elapsed() < keep_alive.idle_timeout {
+                    continue;
+                }
+                // Quiet between requests is just the end of the connection, but part of a
+                // request and then nothing is worth telling the client about
+                if !parser.is_idle() {
+                    let response = Response::text(StatusCode::RequestTimeout, "request timed out");
+                    close(&stream, response, false, events);
+                }
+                return;
+            }
+            Err(ReadError::Io(error)) => {
+                failed(events, "read request", &error);
+                return;
+            }
+            Err(ReadError::Parse(error)) => {
+                // There's no telling where the next request would start
+                events.emit(Event::BadRequest { error: &error });
+                let response = Response::text(error.status(), error.to_string());
+                close(&stream, response, false, events);
+                return;
+            }
+        };
+        served += 1;
+
+        let response = router.handle(&request);
+        let head_only = request.method == Method::Head;
+        if served >= keep_alive.max_requests
+            || stop.load(Ordering::SeqCst)
+            || !wants_keep_alive(&request)
+            || response.headers.has_token("Connection", "close")
+        {
+            close(&stream, response, head_only, events);
+            return;
+        }
+
+        // Persistent is the default for HTTP/1.1, a 1.0 client has to be told
+        let response = match request.version {
+            Version::Http10 => response.header("Connection", "keep-alive"),
+            Version::Http11 => response,
+        };
+        if let Err(error) = write(&stream, &response, head_only) {
+            failed(events, "send response", &error);
+            return;
+        }
+        // The idle time starts again from when the response went out
+        reader.last = Instant::now();
+    }
+}
+
+/// Turns the client away with `response` and closes the connection, without reading its
+/// request or waiting for it to go.  For when there's nobody free to serve it, so it's meant to
+/// be quick enough to call from the thread accepting connections.
+pub fn refuse(stream: &TcpStream, response: Response, events: &Events) {
+    let response = response.header("Connection", "close");
+    // A response this small fits in the socket's buffer, so this only stalls on a broken socket
+    if let Err(error) = stream
+        .set_write_timeout(Some(POLL))
+        .and_then(|_| write(stream, &response, false))
+    {
+        failed(events, "send response", &error);
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    discard_pending(stream);
+}
+
+// Remembers when anything last arrived, since reads time out far more often than the client is
+// allowed to be idle
+struct Activity<'a> {
+    stream: &'a TcpStream,
+    last: Instant,
+}
+
+impl Read for Activity<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.last = Instant::now();
+        Ok(read)
+    }
+}
+
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+// Sends a last response saying the connection is closing, and closes it
+fn close(stream: &TcpStream, response: Response, head_only: bool, events: &Events) {
+    let response = response.header("Connection", "close");
+    // The client may well have gone already, and there's nobody else to tell
+    if let Err(error) = write(stream, &response, head_only) {
+        failed(events, "send response", &error);
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    linger(stream);
+}
+
+// Closing a socket with unread data in it makes the OS reset the connection, which can destroy
+// the response before the client has read it.  So anything else the client sent, pipelined
+// requests after this one for instance, is read and thrown away until it closes its end.
+fn linger(mut stream: &TcpStream) {
+    if stream.set_read_timeout(Some(LINGER)).is_err() {
+        return;
+    }
+    let mut buf = [0; 4096];
+    let mut discarded = 0;
+    while discarded < MAX_LINGER_BYTES {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => discarded += read,
+        }
+    }
+}
+
+// The non-blocking cousin of linger: throws away whatever the client has sent so far, which is
+// as much as can be done to stop a reset without waiting for it
+fn discard_pending(mut stream: &TcpStream) {
+    if stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut buf = [0; 4096];
+    let mut discarded = 0;
+    while discarded < MAX_LINGER_BYTES {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => discarded += read,
+        }
+    }
+}
+
+fn failed(events: &Events, action: &'static str, error: &io::Error) {
+    events.emit(Event::ConnectionFailed { action, error });
+}
+
+fn write(stream: &TcpStream, response: &Response, head_only: bool) -> io::Result<()> {
+    // Buffered so the head and a small body go out in one packet rather than two
+    let mut out = BufWriter::new(stream);
+    if head_only {
+        response.write_head(&mut out)?;
+    } else {
+        response.write_to(&mut out)?;
+    }
+    out.into_inner().map(|_| ()).map_err(|e| e.into_error())
+}
+
+// Unix reports a read timeout as WouldBlock, Windows as TimedOut
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
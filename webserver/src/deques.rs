@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::queue::{Popped, Pushed, QueuePolicy};
+
+/// A queue split into one deque per worker, for the work-stealing scheduler.  Each worker takes
+/// from the front of its own and, when that's empty, steals from the back of the others', so
+/// workers mostly lock different deques rather than all queueing on one lock.
+///
+/// Behaves like `Queue` from the outside: the same limit and policies, and `pop` only reports
+/// `Closed` once every deque is empty.
+pub(crate) struct Deques<T> {
+    deques: Box<[Mutex<VecDeque<T>>]>,
+    // Items across all the deques, reserved before an item goes in
+    len: AtomicUsize,
+    // Where the next push from outside the pool goes
+    next: AtomicUsize,
+    // Workers waiting for an item, so pushes only take the lock when someone needs
+    // waking
+    sleepers: AtomicUsize,
+    // Pushers waiting for room, likewise for pops
+    blocked: AtomicUsize,
+    // Fixed, so pushes don't need a lock to check them
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    closed: Mutex<bool>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T> Deques<T> {
+    pub(crate) fn new(count: usize, capacity: Option<usize>, policy: QueuePolicy) -> Deques<T> {
+        Deques {
+            deques: (0..count).map(|_| Mutex::new(VecDeque::new())).collect(),
+            len: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            capacity,
+            policy,
+            closed: Mutex::new(false),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Adds `item` to deque `local`, the pushing worker's own, or spreads items from outside the
+    /// pool across all of them.
+    pub(crate) fn push(&self, item: T, local: Option<usize>) -> Pushed<T> {
+        self.push_as(item, local, self.policy)
+    }
+
+    /// Pushes as if the policy were `Reject`, like `Queue::try_push`.
+    pub(crate) fn try_push(&self, item: T, local: Option<usize>) -> Pushed<T> {
+        self.push_as(item, local, QueuePolicy::Reject)
+    }
+
+    fn push_as(&self, item: T, local: Option<usize>, policy: QueuePolicy) -> Pushed<T> {
+        let mut displaced = None;
+        if let Some(capacity) = self.capacity {
+            while !self.reserve(capacity) {
+                match policy {
+                    QueuePolicy::Block => self.wait_for_room(capacity),
+                    QueuePolicy::Reject => return Pushed::Full(item),
+                    QueuePolicy::DropOldest => match self.take_oldest() {
+                        // Its place in the count passes to the new item
+                        Some(oldest) => {
+                            displaced = Some(oldest);
+                            break;
+                        }
+                        None if capacity == 0 => return Pushed::Full(item),
+                        // Taken by a worker meanwhile, so there may be room now
+                        None => continue,
+                    },
+                }
+            }
+        } else {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let index = local.unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed));
+        self.lock(index % self.deques.len()).push_back(item);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _closed = self.lock_closed();
+            self.not_empty.notify_one();
+        }
+
+        match displaced {
+            Some(oldest) => Pushed::Displaced(oldest),
+            None => Pushed::Queued,
+        }
+    }
+
+    // Counts an item in if there's room for it
+    fn reserve(&self, capacity: usize) -> bool {
+        self.len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                (len < capacity).then_some(len + 1)
+            })
+            .is_ok()
+    }
+
+    fn wait_for_room(&self, capacity: usize) {
+        let closed = self.lock_closed();
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        if self.len.load(Ordering::SeqCst) >= capacity {
+            drop(
+                self.not_full
+                    .wait(closed)
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn close(&self) {
+        *self.lock_closed() = true;
+        self.not_empty.notify_all();
+    }
+
+    /// Waits up to `timeout` for an item, taking from deque `local` first if given.
+    pub(crate) fn pop(&self, local: Option<usize>, timeout: Duration) -> Popped<T> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let own = local.and_then(|index| self.lock(index).pop_front());
+            let item = own.or_else(|| self.steal(local.map_or(0, |index| index + 1)));
+            if let Some(item) = item {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                if self.blocked.load(Ordering::SeqCst) > 0 {
+                    let _closed = self.lock_closed();
+                    self.not_full.notify_one();
+                }
+                return Popped::Item(item);
+            }
+
+            let closed = self.lock_closed();
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            if self.len.load(Ordering::SeqCst) > 0 {
+                // Reserved but not in a deque yet, or in one that was just searched; either way
+                // it's about to be found
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                drop(closed);
+                thread::yield_now();
+                continue;
+            }
+            if *closed {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return Popped::Closed;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return Popped::TimedOut;
+            }
+            drop(
+                self.not_empty
+                    .wait_timeout(closed, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // Takes the newest item from the first deque that has one, starting at `start`
+    fn steal(&self, start: usize) -> Option<T> {
+        let count = self.deques.len();
+        (0..count).find_map(|offset| self.lock((start + offset) % count).pop_back())
+    }
+
+    // The oldest item in the first deque that has one, which is as near the oldest overall as
+    // can be found without timestamping everything
+    fn take_oldest(&self) -> Option<T> {
+        (0..self.deques.len()).find_map(|index| self.lock(index).pop_front())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    fn lock(&self, index: usize) -> MutexGuard<'_, VecDeque<T>> {
+        self.deques[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Guards the closed flag, and is what workers and pushers wait on
+    fn lock_closed(&self) -> MutexGuard<'_, bool> {
+        self.closed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn pop<T>(deques: &Deques<T>, local: Option<usize>) -> T {
+        match deques.pop(local, WAIT) {
+            Popped::Item(item) => item,
+            _ => panic!("nothing to pop"),
+        }
+    }
+
+    #[test]
+    fn owner_takes_oldest_and_thieves_newest() {
+        let deques = Deques::new(2, None, QueuePolicy::Block);
+        for item in 1..=3 {
+            deques.push(item, Some(0));
+        }
+        assert_eq!(pop(&deques, Some(0)), 1);
+        assert_eq!(pop(&deques, Some(1)), 3);
+        assert_eq!(deques.len(), 1);
+    }
+
+    #[test]
+    fn spreads_pushes_from_outside() {
+        let deques = Deques::new(2, None, QueuePolicy::Block);
+        deques.push(1, None);
+        deques.push(2, None);
+        assert_eq!(pop(&deques, Some(1)), 2);
+        assert_eq!(pop(&deques, Some(1)), 1);
+    }
+
+    #[test]
+    fn limits_apply_across_deques() {
+        let deques = Deques::new(2, Some(2), QueuePolicy::Reject);
+        deques.push(1, Some(0));
+        deques.push(2, Some(1));
+        assert!(matches!(deques.push(3, Some(0)), Pushed::Full(3)));
+
+        let deques = Deques::new(2, Some(2), QueuePolicy::DropOldest);
+        deques.push(1, Some(0));
+        deques.push(2, Some(0));
+        assert!(matches!(deques.push(3, Some(1)), Pushed::Displaced(1)));
+        assert_eq!(deques.len(), 2);
+    }
+
+    #[test]
+    fn closes_once_empty() {
+        let deques = Deques::new(3, None, QueuePolicy::Block);
+        deques.push(1, Some(2));
+        deques.close();
+        assert_eq!(pop(&deques, Some(0)), 1);
+        assert!(matches!(deques.pop(Some(0), WAIT), Popped::Closed));
+        assert!(matches!(
+            Deques::<u8>::new(1, None, QueuePolicy::Block).pop(None, Duration::from_millis(10)),
+            Popped::TimedOut
+        ));
+    }
+}
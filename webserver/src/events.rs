@@ -0,0 +1,234 @@
+//! What the pool, server and connections report as they go, for logging or metrics.
+//!
+//! Nothing is reported unless a sink is given, with `Server::events` or
+//! `ThreadPoolBuilder::events` for instance:
+//!
+//! ```no_run
+//! use webserver::events::{Events, Level, Printer};
+//! use webserver::{Response, Router, Server, StatusCode};
+//!
+//! let router = Router::new().get("/", |_, _| Response::text(StatusCode::Ok, "hello"));
+//! let server = Server::bind("127.0.0.1:7878", router)
+//!     .unwrap()
+//!     .events(Events::new(Printer::new(Level::Info)));
+//! server.run().unwrap();
+//! ```
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::request::ParseError;
+
+/// How much an event matters, from most to least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    /// Happens for every job or worker, so only worth seeing when looking into something.
+    Debug,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        })
+    }
+}
+
+/// Something that happened.  Borrows what it describes, so a sink that wants to keep it has to
+/// copy out what it needs.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// A pool worker thread started.
+    WorkerStarted { worker: usize },
+    /// A worker above the pool's minimum size stopped after being idle.
+    WorkerRetired { worker: usize },
+    /// A worker stopped because the pool is shutting down.
+    WorkerStopped { worker: usize },
+    /// A worker's thread panicked, in the panic handler, and is being replaced.
+    WorkerDied { worker: usize },
+    /// A worker thread couldn't be started.
+    WorkerSpawnFailed { error: &'a io::Error },
+    /// A worker took a job that had been queued for `waited`.
+    JobStarted { worker: usize, waited: Duration },
+    /// A job ran for `took` without panicking.
+    JobFinished { worker: usize, took: Duration },
+    /// A job panicked with `message`.
+    JobPanicked { worker: usize, message: &'a str },
+    /// A job was dropped without running, to make room in a full `QueuePolicy::DropOldest`
+    /// queue.
+    JobDropped,
+    /// A pool started stopping its workers.
+    PoolShuttingDown,
+    /// A pool's shutdown deadline passed with `busy` workers still running jobs.
+    ShutdownOverdue { busy: usize },
+    /// The server stopped accepting connections.
+    ServerShuttingDown,
+    /// Accepting a connection failed.
+    AcceptFailed { error: &'a io::Error },
+    /// A connection was turned away with 503 as there was no room in the queue.
+    ConnectionRefused { peer: Option<SocketAddr> },
+    /// Something went wrong on a connection, usually the client going away.  `action` says
+    /// what was being done, e.g. "read request".
+    ConnectionFailed {
+        action: &'static str,
+        error: &'a io::Error,
+    },
+    /// A request couldn't be parsed, and was answered with an error status.
+    BadRequest { error: &'a ParseError },
+    /// A file that exists couldn't be served.  `action` is "open" or "list".
+    FileFailed {
+        action: &'static str,
+        path: &'a Path,
+        error: &'a io::Error,
+    },
+}
+
+impl Event<'_> {
+    pub fn level(&self) -> Level {
+        match self {
+            Event::WorkerDied { .. }
+            | Event::WorkerSpawnFailed { .. }
+            | Event::JobPanicked { .. }
+            | Event::FileFailed { .. } => Level::Error,
+            Event::JobDropped
+            | Event::ShutdownOverdue { .. }
+            | Event::AcceptFailed { .. }
+            | Event::ConnectionRefused { .. } => Level::Warn,
+            Event::PoolShuttingDown
+            | Event::ServerShuttingDown
+            | Event::ConnectionFailed { .. }
+            | Event::BadRequest { .. } => Level::Info,
+            Event::WorkerStarted { .. }
+            | Event::WorkerRetired { .. }
+            | Event::WorkerStopped { .. }
+            | Event::JobStarted { .. }
+            | Event::JobFinished { .. } => Level::Debug,
+        }
+    }
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::WorkerStarted { worker } => write!(f, "worker {} started", worker),
+            Event::WorkerRetired { worker } => write!(f, "worker {} was idle; stopping", worker),
+            Event::WorkerStopped { worker } => write!(f, "worker {} was told to stop", worker),
+            Event::WorkerDied { worker } => {
+                write!(f, "worker {} died; starting a new one", worker)
+            }
+            Event::WorkerSpawnFailed { error } => {
+                write!(f, "failed to start a worker thread: {}", error)
+            }
+            Event::JobStarted { worker, waited } => {
+                write!(f, "worker {} got a job queued {:?} ago", worker, waited)
+            }
+            Event::JobFinished { worker, took } => {
+                write!(f, "worker {} finished a job in {:?}", worker, took)
+            }
+            Event::JobPanicked { worker, message } => {
+                write!(f, "worker {} panicked running a job: {}", worker, message)
+            }
+            Event::JobDropped => write!(f, "job queue full; dropped the oldest job"),
+            Event::PoolShuttingDown => write!(f, "shutting down all workers"),
+            Event::ShutdownOverdue { busy } => {
+                write!(f, "{} workers still busy at the shutdown deadline", busy)
+            }
+            Event::ServerShuttingDown => write!(f, "shutting down"),
+            Event::AcceptFailed { error } => write!(f, "failed to accept connection: {}", error),
+            Event::ConnectionRefused { peer: Some(peer) } => {
+                write!(f, "too busy; turned {} away", peer)
+            }
+            Event::ConnectionRefused { peer: None } => write!(f, "too busy; turned a client away"),
+            Event::ConnectionFailed { action, error } => {
+                write!(f, "failed to {}: {}", action, error)
+            }
+            Event::BadRequest { error } => write!(f, "bad request: {}", error),
+            Event::FileFailed {
+                action,
+                path,
+                error,
+            } => write!(f, "failed to {} {}: {}", action, path.display(), error),
+        }
+    }
+}
+
+/// Receives events.  Called on whichever thread the event happened on, so it shouldn't take
+/// long.
+pub trait EventSink: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F> EventSink for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Writes events at `level` or more important to stderr, one a line.
+#[derive(Debug, Clone, Copy)]
+pub struct Printer {
+    level: Level,
+}
+
+impl Printer {
+    pub fn new(level: Level) -> Printer {
+        Printer { level }
+    }
+}
+
+impl EventSink for Printer {
+    fn event(&self, event: &Event) {
+        let level = event.level();
+        if level <= self.level {
+            eprintln!("[{}] {}", level, event);
+        }
+    }
+}
+
+/// Where to send events: a sink, or nowhere, which is the default.  Cheap to clone, with every
+/// clone sharing the one sink.
+#[derive(Clone, Default)]
+pub struct Events {
+    sink: Option<Arc<dyn EventSink>>,
+}
+
+impl Events {
+    pub fn new(sink: impl EventSink + 'static) -> Events {
+        Events {
+            sink: Some(Arc::new(sink)),
+        }
+    }
+
+    pub fn none() -> Events {
+        Events::default()
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Some(sink) = &self.sink {
+            sink.event(&event);
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.sink {
+            Some(_) => "Events(sink)",
+            None => "Events(none)",
+        })
+    }
+}
@@ -0,0 +1,184 @@
+//! The date format HTTP uses in headers such as `Last-Modified`, e.g.
+//! `Sun, 06 Nov 1994 08:49:37 GMT`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats `time` as an IMF-fixdate, the form servers are meant to send.  Anything before 1970
+/// comes out as the epoch, and fractions of a second are dropped.
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let in_day = secs % 86_400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        in_day / 3600,
+        in_day / 60 % 60,
+        in_day % 60
+    )
+}
+
+/// Parses any of the three formats a client may send: IMF-fixdate, the obsolete RFC 850 form
+/// (`Sunday, 06-Nov-94 08:49:37 GMT`) and asctime (`Sun Nov  6 08:49:37 1994`).  The weekday
+/// isn't checked, as the spec allows.
+pub fn parse(date: &str) -> Option<SystemTime> {
+    let date = date.trim();
+    let (day, month, year, time) = if let Some((_, rest)) = date.split_once(", ") {
+        let fields: Vec<&str> = rest.split(' ').collect();
+        match fields[..] {
+            // IMF-fixdate
+            [day, month, year, time, "GMT"] if year.len() == 4 => (day, month, year, time),
+            // RFC 850, two digit year
+            [date, time, "GMT"] => {
+                let mut parts = date.split('-');
+                match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                    (Some(day), Some(month), Some(year), None) if year.len() == 2 => {
+                        (day, month, year, time)
+                    }
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    } else {
+        // asctime, where a single digit day is padded with a space
+        let fields: Vec<&str> = date.split_whitespace().collect();
+        match fields[..] {
+            [_, month, day, time, year] if year.len() == 4 => (day, month, year, time),
+            _ => return None,
+        }
+    };
+
+    let day: u32 = number(day)?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u32 + 1;
+    let mut year: i64 = number(year)?;
+    if year < 100 {
+        // RFC 850's two digit years, taking 70 and up as the 1900s since nothing HTTP predates 1970
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+
+    let mut time = time.split(':');
+    let hour: u64 = number(time.next()?)?;
+    let minute: u64 = number(time.next()?)?;
+    // Up to 60 for a leap second
+    let second: u64 = number(time.next()?)?;
+    if time.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    if day == 0 || day > days_in_month(year, month) || year < 1970 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day) as u64;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Digits only, so "+1" or " 1" don't sneak through
+fn number<T: std::str::FromStr>(digits: &str) -> Option<T> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Howard Hinnant's algorithms for converting between days since 1970-01-01 and a proleptic
+// Gregorian date, from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: u64 = 784_111_777;
+
+    #[test]
+    fn formats_imf_fixdate() {
+        let time = UNIX_EPOCH + Duration::from_secs(EXAMPLE);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            format(UNIX_EPOCH + Duration::from_millis(951_782_400_999)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn parses_all_three_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(EXAMPLE));
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), expected);
+    }
+
+    #[test]
+    fn round_trips() {
+        for secs in [0, 68_169_599, 951_782_400, 1_700_000_000, 4_102_444_800] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse(&format(time)), Some(time), "{}", secs);
+        }
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        for date in [
+            "",
+            "yesterday",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 29 Feb 1900 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov +994 08:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+        ] {
+            assert_eq!(parse(date), None, "{:?}", date);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::pool::panic_message;
+
+/// Waits for the result of a job started with `ThreadPool::spawn`.
+///
+/// Dropping the handle doesn't stop the job; its result is just thrown away.
+pub struct JobHandle<T> {
+    slot: Arc<Slot<T>>,
+}
+
+/// Why a spawned job has no result.
+#[derive(Debug)]
+pub enum JoinError {
+    /// It panicked, with this payload.
+    Panicked(Box<dyn Any + Send>),
+    /// It was dropped before it ran, by a `QueuePolicy::DropOldest` queue for instance.
+    Dropped,
+}
+
+impl JoinError {
+    /// The panic payload, for `std::panic::resume_unwind` say, if the job panicked.
+    pub fn into_panic(self) -> Option<Box<dyn Any + Send>> {
+        match self {
+            JoinError::Panicked(payload) => Some(payload),
+            JoinError::Dropped => None,
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => {
+                write!(f, "job panicked: {}", panic_message(&**payload))
+            }
+            JoinError::Dropped => write!(f, "job was dropped before it ran"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+struct Slot<T> {
+    outcome: Mutex<Outcome<T>>,
+    finished: Condvar,
+}
+
+enum Outcome<T> {
+    Running,
+    Finished(Result<T, JoinError>),
+    Taken,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish and gives what it returned.
+    ///
+    /// Calling this from another job on the same pool can deadlock, if every worker ends up
+    /// waiting on a job that's still queued behind them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the result was already taken by `try_join` or `join_timeout`.
+    pub fn join(self) -> Result<T, JoinError> {
+        let mut outcome = self.slot.lock();
+        while let Outcome::Running = *outcome {
+            outcome = self
+                .slot
+                .finished
+                .wait(outcome)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        take(&mut outcome)
+    }
+
+    /// Gives the result if the job has finished, or None if it's still queued or running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the result was already taken.
+    pub fn try_join(&mut self) -> Option<Result<T, JoinError>> {
+        let mut outcome = self.slot.lock();
+        match *outcome {
+            Outcome::Running => None,
+            _ => Some(take(&mut outcome)),
+        }
+    }
+
+    /// Like `join`, but gives up with None after `timeout`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the result was already taken.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<Result<T, JoinError>> {
+        let deadline = Instant::now() + timeout;
+        let mut outcome = self.slot.lock();
+        while let Outcome::Running = *outcome {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            outcome = self
+                .slot
+                .finished
+                .wait_timeout(outcome, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        Some(take(&mut outcome))
+    }
+
+    /// True once the job has finished, or been dropped, and `join` won't block.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.slot.lock(), Outcome::Running)
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JobHandle")
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+fn take<T>(outcome: &mut Outcome<T>) -> Result<T, JoinError> {
+    match std::mem::replace(outcome, Outcome::Taken) {
+        Outcome::Finished(result) => result,
+        Outcome::Running => unreachable!("job still running"),
+        Outcome::Taken => panic!("job result already taken"),
+    }
+}
+
+impl<T> Slot<T> {
+    fn lock(&self) -> MutexGuard<'_, Outcome<T>> {
+        self.outcome.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn finish(&self, result: Result<T, JoinError>) {
+        let mut outcome = self.lock();
+        if let Outcome::Running = *outcome {
+            *outcome = Outcome::Finished(result);
+            self.finished.notify_all();
+        }
+    }
+}
+
+// Travels with the job, so a job that's dropped without running still tells its handle
+struct Completion<T> {
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.slot.finish(Err(JoinError::Dropped));
+    }
+}
+
+/// Wraps `f` in a job that hands its result, or its panic, to the returned handle.
+pub(crate) fn job<F, T>(f: F) -> (impl FnOnce() + Send + 'static, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let slot = Arc::new(Slot {
+        outcome: Mutex::new(Outcome::Running),
+        finished: Condvar::new(),
+    });
+    let completion = Completion {
+        slot: Arc::clone(&slot),
+    };
+
+    let run = move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::Panicked);
+        completion.slot.finish(result);
+    };
+    (run, JobHandle { slot })
+}
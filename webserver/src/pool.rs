@@ -0,0 +1,855 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::deques::Deques;
+use crate::events::{Event, Events};
+use crate::job::{self, JobHandle};
+use crate::queue::{ExecuteError, Popped, Pushed, Queue, QueuePolicy};
+use crate::scope::{self, Scope};
+
+struct Job {
+    run: Box<dyn FnOnce() + Send + 'static>,
+    queued: Instant,
+}
+
+/// How a pool hands jobs out to its workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheduler {
+    /// One queue that every worker takes from.  Jobs start strictly in the order they were
+    /// queued, but every worker needs the same lock to get one.
+    SharedQueue,
+    /// A queue for each worker, with workers that run out stealing from the others.  Jobs
+    /// queued by a job go on its worker's own queue.  Much less waiting on locks when there
+    /// are lots of small jobs, at the cost of only roughly keeping their order.
+    WorkStealing,
+}
+
+/// A job that panicked, as passed to the pool's panic handler.  The worker carries on with the
+/// next job regardless.
+#[derive(Debug, Clone)]
+pub struct JobPanic {
+    pub worker: usize,
+    /// What the job panicked with, if it was a string as it almost always is.
+    pub message: String,
+}
+
+type PanicHandler = Box<dyn Fn(&JobPanic) + Send + Sync>;
+
+/// What a pool's workers are up to at one moment, and what they've done since it started, from
+/// `ThreadPool::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Worker threads alive, busy or not.
+    pub threads: usize,
+    /// Workers running a job.
+    pub active: usize,
+    /// Workers waiting for a job.
+    pub idle: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Jobs taken by `execute`.
+    pub submitted: u64,
+    /// Jobs `execute` turned away as the queue was full.
+    pub rejected: u64,
+    /// Jobs thrown away without running to make room in the queue.
+    pub dropped: u64,
+    /// Jobs that ran without panicking.  Jobs from `spawn` and `scope` catch their own panics,
+    /// so count as completed either way.
+    pub completed: u64,
+    /// Jobs that panicked.
+    pub panicked: u64,
+    /// Time jobs spent queued before they started, all told and at most.
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    /// Time jobs spent running, all told and at most.
+    pub total_run: Duration,
+    pub max_run: Duration,
+}
+
+impl PoolStats {
+    /// Average time from a job being queued to starting, or zero if none have finished.
+    pub fn mean_wait(&self) -> Duration {
+        self.mean(self.total_wait)
+    }
+
+    /// Average time a job ran for, or zero if none have finished.
+    pub fn mean_run(&self) -> Duration {
+        self.mean(self.total_run)
+    }
+
+    fn mean(&self, total: Duration) -> Duration {
+        match self.completed + self.panicked {
+            0 => Duration::ZERO,
+            finished => Duration::from_nanos((total.as_nanos() / u128::from(finished)) as u64),
+        }
+    }
+}
+
+// Running totals behind PoolStats
+#[derive(Default)]
+struct Counters {
+    submitted: AtomicU64,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    // In nanoseconds
+    total_wait: AtomicU64,
+    max_wait: AtomicU64,
+    total_run: AtomicU64,
+    max_run: AtomicU64,
+}
+
+impl Counters {
+    fn finished(&self, waited: Duration, took: Duration, panicked: bool) {
+        let counter = if panicked {
+            &self.panicked
+        } else {
+            &self.completed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let waited = waited.as_nanos() as u64;
+        self.total_wait.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait.fetch_max(waited, Ordering::Relaxed);
+        let took = took.as_nanos() as u64;
+        self.total_run.fetch_add(took, Ordering::Relaxed);
+        self.max_run.fetch_max(took, Ordering::Relaxed);
+    }
+}
+
+/// Sets up a `ThreadPool`.  The pool starts with `min_threads` workers and adds more, up to
+/// `max_threads`, whenever a job is queued with none of them free.  Workers beyond the minimum
+/// stop again after `keep_alive` with nothing to do.
+///
+/// ```
+/// use std::time::Duration;
+/// use webserver::ThreadPool;
+///
+/// let pool = ThreadPool::builder()
+///     .min_threads(2)
+///     .max_threads(16)
+///     .keep_alive(Duration::from_secs(30))
+///     .thread_name("cruncher")
+///     .build()
+///     .unwrap();
+/// # drop(pool);
+/// ```
+pub struct ThreadPoolBuilder {
+    min: usize,
+    max: usize,
+    keep_alive: Duration,
+    name: Option<String>,
+    stack_size: Option<usize>,
+    queue: Option<(usize, QueuePolicy)>,
+    scheduler: Scheduler,
+    on_panic: Option<PanicHandler>,
+    events: Events,
+}
+
+impl ThreadPoolBuilder {
+    fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            min: 4,
+            max: 4,
+            keep_alive: Duration::from_secs(60),
+            name: None,
+            stack_size: None,
+            queue: None,
+            scheduler: Scheduler::SharedQueue,
+            on_panic: None,
+            events: Events::none(),
+        }
+    }
+
+    /// Workers started straight away and kept however quiet it gets.  4 unless set, and raises
+    /// the maximum to match if it's below.
+    pub fn min_threads(mut self, min: usize) -> ThreadPoolBuilder {
+        self.min = min;
+        self.max = self.max.max(min);
+        self
+    }
+
+    /// The most workers there can be at once.  4 unless set, and lowers the minimum to match if
+    /// it's above.
+    pub fn max_threads(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max = max;
+        self.min = self.min.min(max);
+        self
+    }
+
+    /// How long a worker beyond the minimum waits for a job before stopping.  A minute unless
+    /// set.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> ThreadPoolBuilder {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Names worker threads `{prefix}-{id}`, which shows up in panic messages and debuggers.
+    /// They're left unnamed unless set.
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.name = Some(prefix.into());
+        self
+    }
+
+    /// Stack size for worker threads in bytes.  The standard library's default unless set.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Limits how many jobs can be waiting for a free worker to `capacity`, with `policy`
+    /// saying what `execute` does once that many are.  Without this the queue can grow until
+    /// memory runs out.
+    ///
+    /// At least 1, and 0 is taken as 1: every job waits in the queue, however briefly, before a
+    /// worker picks it up, so with no room at all nothing could ever be run.
+    pub fn queue_limit(mut self, capacity: usize, policy: QueuePolicy) -> ThreadPoolBuilder {
+        self.queue = Some((capacity.max(1), policy));
+        self
+    }
+
+    /// `Scheduler::SharedQueue` unless set.
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ThreadPoolBuilder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Calls `handler` whenever a job panics, as well as reporting it as an event.
+    pub fn panic_handler<F>(mut self, handler: F) -> ThreadPoolBuilder
+    where
+        F: Fn(&JobPanic) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Box::new(handler));
+        self
+    }
+
+    /// Where workers report starting and stopping, jobs panicking and so on.  Nowhere unless
+    /// set.
+    pub fn events(mut self, events: Events) -> ThreadPoolBuilder {
+        self.events = events;
+        self
+    }
+
+    /// Starts the pool's first `min_threads` workers.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a thread can't be started, with too large a stack size for instance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the maximum number of threads is zero.
+    pub fn build(self) -> io::Result<ThreadPool> {
+        assert!(self.max > 0, "a thread pool needs at least one thread");
+
+        let (capacity, policy) = match self.queue {
+            Some((capacity, policy)) => (Some(capacity), policy),
+            None => (None, QueuePolicy::Block),
+        };
+        let jobs = match self.scheduler {
+            Scheduler::SharedQueue => {
+                let queue = Queue::new();
+                queue.set_limit(capacity, policy);
+                Jobs::Shared(queue)
+            }
+            Scheduler::WorkStealing => Jobs::Stealing(Deques::new(self.max, capacity, policy)),
+        };
+        let shared = Arc::new(Shared {
+            jobs,
+            on_panic: self.on_panic,
+            events: self.events,
+            counters: Counters::default(),
+            min: self.min,
+            max: self.max,
+            keep_alive: self.keep_alive,
+            name: self.name,
+            stack_size: self.stack_size,
+            workers: Mutex::new(Workers {
+                running: HashMap::new(),
+                stopped: Vec::new(),
+                free_slots: (0..self.max).rev().collect(),
+                next_id: 0,
+            }),
+            active: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+        });
+
+        let pool = ThreadPool {
+            shared: Arc::clone(&shared),
+            shut_down: false,
+        };
+        let started = {
+            let mut workers = shared.lock_workers();
+            // Dropping the pool on the way out stops any already started
+            (0..shared.min)
+                .map(|_| spawn_worker(&shared, &mut workers))
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        for worker in started {
+            shared.events.emit(Event::WorkerStarted { worker });
+        }
+        Ok(pool)
+    }
+}
+
+// The queued jobs, however they're scheduled
+enum Jobs {
+    Shared(Queue<Job>),
+    Stealing(Deques<Job>),
+}
+
+impl Jobs {
+    // `slot` is the pushing or popping worker's, if it's one of this pool's
+    fn push(&self, job: Job, slot: Option<usize>) -> Pushed<Job> {
+        match self {
+            Jobs::Shared(queue) => queue.push(job),
+            Jobs::Stealing(deques) => deques.push(job, slot),
+        }
+    }
+
+    fn try_push(&self, job: Job, slot: Option<usize>) -> Pushed<Job> {
+        match self {
+            Jobs::Shared(queue) => queue.try_push(job),
+            Jobs::Stealing(deques) => deques.try_push(job, slot),
+        }
+    }
+
+    fn pop(&self, slot: usize, timeout: Duration) -> Popped<Job> {
+        match self {
+            Jobs::Shared(queue) => queue.pop(timeout),
+            Jobs::Stealing(deques) => deques.pop(Some(slot), timeout),
+        }
+    }
+
+    fn close(&self) {
+        match self {
+            Jobs::Shared(queue) => queue.close(),
+            Jobs::Stealing(deques) => deques.close(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Jobs::Shared(queue) => queue.len(),
+            Jobs::Stealing(deques) => deques.len(),
+        }
+    }
+}
+
+thread_local! {
+    // The pool, by the address of its Shared, and slot of the worker running on this thread
+    static CURRENT: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+// What every worker needs, including any started later
+struct Shared {
+    jobs: Jobs,
+    on_panic: Option<PanicHandler>,
+    events: Events,
+    counters: Counters,
+    min: usize,
+    max: usize,
+    keep_alive: Duration,
+    name: Option<String>,
+    stack_size: Option<usize>,
+    workers: Mutex<Workers>,
+    active: AtomicUsize,
+    idle: AtomicUsize,
+}
+
+struct Workers {
+    // By id, with the slot each worker has
+    running: HashMap<usize, (usize, JoinHandle<()>)>,
+    // Threads that have stopped, or are about to, and still need joining
+    stopped: Vec<JoinHandle<()>>,
+    // Slots are numbered below the maximum number of threads, so the work-stealing scheduler
+    // can give each worker a deque
+    free_slots: Vec<usize>,
+    next_id: usize,
+}
+
+impl Shared {
+    // A poisoned lock only means a thread panicked while it had it, and the map itself is fine
+    fn lock_workers(&self) -> MutexGuard<'_, Workers> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Reports how starting a worker went, once the workers are unlocked again: a sink is free to
+    // ask for the pool's stats, which need the lock
+    fn spawned(&self, spawned: io::Result<usize>) {
+        match spawned {
+            Ok(worker) => self.events.emit(Event::WorkerStarted { worker }),
+            Err(error) => self.events.emit(Event::WorkerSpawnFailed { error: &error }),
+        }
+    }
+
+    // Moves a worker that's on its way out to the stopped list, unless that would leave fewer
+    // than the minimum and `keep_minimum` is set
+    fn retire(&self, id: usize, keep_minimum: bool) -> bool {
+        let mut workers = self.lock_workers();
+        if keep_minimum && workers.running.len() <= self.min {
+            return false;
+        }
+        if let Some((slot, thread)) = workers.running.remove(&id) {
+            workers.free_slots.push(slot);
+            workers.stopped.push(thread);
+        }
+        true
+    }
+
+    // The slot of the worker running on this thread, if it's one of ours
+    fn current_slot(self: &Arc<Shared>) -> Option<usize> {
+        let pool = Arc::as_ptr(self) as usize;
+        CURRENT
+            .with(Cell::get)
+            .filter(|&(current, _)| current == pool)
+            .map(|(_, slot)| slot)
+    }
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    shut_down: bool,
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::builder()
+            .min_threads(size)
+            .max_threads(size)
+            .build()
+            .expect("failed to start worker thread")
+    }
+
+    /// Create a new ThreadPool that calls `handler` whenever a job panics.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is zero.
+    pub fn with_panic_handler<F>(size: usize, handler: F) -> ThreadPool
+    where
+        F: Fn(&JobPanic) + Send + Sync + 'static,
+    {
+        ThreadPool::builder()
+            .min_threads(size)
+            .max_threads(size)
+            .panic_handler(handler)
+            .build()
+            .expect("failed to start worker thread")
+    }
+
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    /// Queues `f` to run on the next free worker, starting another worker for it if there's
+    /// none free and the pool isn't at its maximum size.
+    ///
+    /// Fails only if the queue is full and its policy is `QueuePolicy::Reject`, in which case
+    /// `f` is dropped without running.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.push(Box::new(f), false) {
+            Ok(())
+        } else {
+            self.shared
+                .counters
+                .rejected
+                .fetch_add(1, Ordering::Relaxed);
+            Err(ExecuteError::QueueFull)
+        }
+    }
+
+    /// Queues `f` only if there's room for it right now, whatever the queue's policy, so it
+    /// never waits or pushes another job out.  Returns false, dropping `f`, if there's not.
+    pub(crate) fn try_execute<F>(&self, f: F) -> bool
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.push(Box::new(f), true)
+    }
+
+    fn push(&self, run: Box<dyn FnOnce() + Send + 'static>, only_if_room: bool) -> bool {
+        self.grow();
+        let job = Job {
+            run,
+            queued: Instant::now(),
+        };
+
+        let (jobs, slot) = (&self.shared.jobs, self.shared.current_slot());
+        let pushed = if only_if_room {
+            jobs.try_push(job, slot)
+        } else {
+            jobs.push(job, slot)
+        };
+        let counters = &self.shared.counters;
+        match pushed {
+            Pushed::Queued => {
+                counters.submitted.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Pushed::Displaced(_) => {
+                counters.submitted.fetch_add(1, Ordering::Relaxed);
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                self.shared.events.emit(Event::JobDropped);
+                true
+            }
+            Pushed::Full(_) => false,
+        }
+    }
+
+    /// Queues `f` like `execute`, and gives a handle to wait for what it returns.
+    ///
+    /// A panic in `f` comes back through the handle rather than going to the panic handler.
+    ///
+    /// ```
+    /// use webserver::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let sum = pool.spawn(|| (1..=100).sum::<u32>()).unwrap();
+    /// assert_eq!(sum.join().unwrap(), 5050);
+    /// ```
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = job::job(f);
+        self.execute(job)?;
+        Ok(handle)
+    }
+
+    /// Calls `f` with a `Scope` for spawning jobs that borrow from the caller's stack, and
+    /// returns once they've all finished.  The calling thread helps run them meanwhile.
+    ///
+    /// Panics if `f` or any of the jobs did, once they've all finished.
+    ///
+    /// ```
+    /// use webserver::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let mut halves = [vec![1, 2], vec![3, 4]];
+    /// pool.scope(|scope| {
+    ///     for half in halves.iter_mut() {
+    ///         scope.spawn(move || half.iter_mut().for_each(|n| *n *= 10));
+    ///     }
+    /// });
+    /// assert_eq!(halves, [vec![10, 20], vec![30, 40]]);
+    /// ```
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        scope::run(self, f)
+    }
+
+    /// Applies `f` to every item in parallel, giving the results in the same order.
+    ///
+    /// ```
+    /// use webserver::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let words = ["pool", "of", "threads"];
+    /// assert_eq!(pool.par_map(&words, |word| word.len()), [4, 2, 7]);
+    /// ```
+    pub fn par_map<T, U, F>(&self, items: &[T], f: F) -> Vec<U>
+    where
+        T: Sync,
+        U: Send,
+        F: Fn(&T) -> U + Sync,
+    {
+        let mut results: Vec<Option<U>> = items.iter().map(|_| None).collect();
+        let size = self.chunk_size(items.len());
+        let f = &f;
+        self.scope(|scope| {
+            for (items, results) in items.chunks(size).zip(results.chunks_mut(size)) {
+                scope.spawn(move || {
+                    for (item, result) in items.iter().zip(results) {
+                        *result = Some(f(item));
+                    }
+                });
+            }
+        });
+        results
+            .into_iter()
+            .map(|result| result.expect("scope finished every chunk"))
+            .collect()
+    }
+
+    /// Calls `f` on every item in parallel.
+    pub fn par_for_each<T, F>(&self, items: &mut [T], f: F)
+    where
+        T: Send,
+        F: Fn(&mut T) + Sync,
+    {
+        let size = self.chunk_size(items.len());
+        let f = &f;
+        self.scope(|scope| {
+            for chunk in items.chunks_mut(size) {
+                scope.spawn(move || chunk.iter_mut().for_each(f));
+            }
+        });
+    }
+
+    // A few chunks per thread, so one that happens to be slow doesn't leave the rest idle
+    fn chunk_size(&self, len: usize) -> usize {
+        len.div_ceil(self.shared.max * 4).max(1)
+    }
+
+    /// How many jobs are waiting for a worker, not counting those being run.
+    pub fn queued(&self) -> usize {
+        self.shared.jobs.len()
+    }
+
+    /// How many workers there are and what they're doing, and how many jobs they've run and
+    /// how long those took.  The counts are read one after another while the workers carry
+    /// on, so may not quite add up.
+    pub fn stats(&self) -> PoolStats {
+        let counters = &self.shared.counters;
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let time = |counter: &AtomicU64| Duration::from_nanos(counter.load(Ordering::Relaxed));
+        PoolStats {
+            threads: self.shared.lock_workers().running.len(),
+            active: self.shared.active.load(Ordering::SeqCst),
+            idle: self.shared.idle.load(Ordering::SeqCst),
+            queued: self.shared.jobs.len(),
+            submitted: count(&counters.submitted),
+            rejected: count(&counters.rejected),
+            dropped: count(&counters.dropped),
+            completed: count(&counters.completed),
+            panicked: count(&counters.panicked),
+            total_wait: time(&counters.total_wait),
+            max_wait: time(&counters.max_wait),
+            total_run: time(&counters.total_run),
+            max_run: time(&counters.max_run),
+        }
+    }
+
+    // Adds a worker if the job about to be queued would otherwise have to wait
+    fn grow(&self) {
+        let shared = &self.shared;
+        if shared.jobs.len() < shared.idle.load(Ordering::SeqCst) {
+            return;
+        }
+        let spawned = {
+            let mut workers = shared.lock_workers();
+            if workers.running.len() >= shared.max {
+                return;
+            }
+            spawn_worker(shared, &mut workers)
+        };
+        // The job still gets queued for the workers there are if this failed
+        shared.spawned(spawned);
+    }
+
+    /// Stops the pool once the jobs already given to it have run, waiting at most `timeout` for
+    /// them to finish.
+    ///
+    /// Returns false if some were still running at the deadline.  Their threads are left to
+    /// finish in the background, since there's no way to stop a thread from outside.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        // Workers stop once they've emptied the queue, so the jobs in it get run first
+        self.shared.events.emit(Event::PoolShuttingDown);
+        self.shared.jobs.close();
+        self.shut_down = true;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut guard = self.shared.lock_workers();
+            let workers = &mut *guard;
+            let threads = workers.running.drain().map(|(_, (_, thread))| thread);
+            workers.stopped.extend(threads);
+            // Joining is only safe for threads that have finished, as it can't be given a
+            // deadline
+            let (finished, busy) = workers
+                .stopped
+                .drain(..)
+                .partition::<Vec<_>, _>(|thread| thread.is_finished());
+            workers.stopped = busy;
+            for thread in finished {
+                let _ = thread.join();
+            }
+
+            let running = workers.stopped.len();
+            if running == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                drop(guard);
+                let overdue = Event::ShutdownOverdue { busy: running };
+                self.shared.events.emit(overdue);
+                return false;
+            }
+            drop(guard);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Nothing left to stop after shutdown
+        if self.shut_down {
+            return;
+        }
+
+        self.shared.events.emit(Event::PoolShuttingDown);
+        self.shared.jobs.close();
+
+        // A worker that dies now is replaced, and the replacement needs joining too, so keep
+        // going until there are none left
+        loop {
+            let threads: Vec<_> = {
+                let mut guard = self.shared.lock_workers();
+                let workers = &mut *guard;
+                let running = workers.running.drain().map(|(_, (_, thread))| thread);
+                workers.stopped.drain(..).chain(running).collect()
+            };
+            if threads.is_empty() {
+                break;
+            }
+            for thread in threads {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+// Starts a worker and records it in `workers`, which the caller keeps locked until then so a
+// thread that stops straight away can't look for itself before it's there.  Gives back the new
+// worker's id.
+fn spawn_worker(shared: &Arc<Shared>, workers: &mut Workers) -> io::Result<usize> {
+    // Threads that have stopped since last time, which can be joined without waiting
+    let (finished, stopping) = workers
+        .stopped
+        .drain(..)
+        .partition::<Vec<_>, _>(|thread| thread.is_finished());
+    workers.stopped = stopping;
+    for thread in finished {
+        let _ = thread.join();
+    }
+
+    let slot = workers
+        .free_slots
+        .pop()
+        .ok_or_else(|| io::Error::other("thread pool is at its maximum size"))?;
+    let id = workers.next_id;
+    let mut builder = thread::Builder::new();
+    if let Some(prefix) = &shared.name {
+        builder = builder.name(format!("{}-{}", prefix, id));
+    }
+    if let Some(bytes) = shared.stack_size {
+        builder = builder.stack_size(bytes);
+    }
+    let worker = Arc::clone(shared);
+    let thread = match builder.spawn(move || run_worker(id, slot, worker)) {
+        Ok(thread) => thread,
+        Err(e) => {
+            workers.free_slots.push(slot);
+            return Err(e);
+        }
+    };
+
+    workers.next_id += 1;
+    workers.running.insert(id, (slot, thread));
+    Ok(id)
+}
+
+fn run_worker(id: usize, slot: usize, shared: Arc<Shared>) {
+    let _respawn = Respawn {
+        id,
+        shared: Arc::clone(&shared),
+    };
+    CURRENT.with(|current| current.set(Some((Arc::as_ptr(&shared) as usize, slot))));
+    loop {
+        shared.idle.fetch_add(1, Ordering::SeqCst);
+        let popped = shared.jobs.pop(slot, shared.keep_alive);
+        shared.idle.fetch_sub(1, Ordering::SeqCst);
+
+        match popped {
+            Popped::Item(job) => {
+                let started = Instant::now();
+                let waited = started.duration_since(job.queued);
+                shared.events.emit(Event::JobStarted { worker: id, waited });
+
+                shared.active.fetch_add(1, Ordering::SeqCst);
+                let result = panic::catch_unwind(AssertUnwindSafe(job.run));
+                shared.active.fetch_sub(1, Ordering::SeqCst);
+                let took = started.elapsed();
+                shared.counters.finished(waited, took, result.is_err());
+
+                match result {
+                    Ok(()) => shared.events.emit(Event::JobFinished { worker: id, took }),
+                    Err(payload) => {
+                        let message = panic_message(&*payload);
+                        shared.events.emit(Event::JobPanicked {
+                            worker: id,
+                            message: &message,
+                        });
+                        if let Some(on_panic) = &shared.on_panic {
+                            on_panic(&JobPanic {
+                                worker: id,
+                                message,
+                            });
+                        }
+                    }
+                }
+            }
+            Popped::TimedOut => {
+                if shared.retire(id, true) {
+                    shared.events.emit(Event::WorkerRetired { worker: id });
+                    break;
+                }
+            }
+            Popped::Closed => {
+                shared.events.emit(Event::WorkerStopped { worker: id });
+                break;
+            }
+        }
+    }
+}
+
+// Jobs can't take a worker down, but a panicking panic handler can.  This starts a new thread
+// in its place as the old one unwinds, so the pool never shrinks below what it was.
+struct Respawn {
+    id: usize,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Respawn {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let shared = &self.shared;
+            shared.events.emit(Event::WorkerDied { worker: self.id });
+            shared.retire(self.id, false);
+            let spawned = spawn_worker(shared, &mut shared.lock_workers());
+            shared.spawned(spawned);
+        }
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// What `ThreadPool::execute` does when the queue of waiting jobs is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for a worker to take a job off the queue, which slows whoever is adding jobs down
+    /// to the pace the pool can manage.
+    Block,
+    /// Fail with `ExecuteError::QueueFull`, leaving the caller to decide what to do.
+    Reject,
+    /// Throw away the job that's been waiting longest to make room.  It's dropped without
+    /// running.
+    DropOldest,
+}
+
+/// Why `ThreadPool::execute` didn't take a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    /// The queue was at capacity and the policy is `Reject`.
+    QueueFull,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull => write!(f, "thread pool queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+/// A FIFO queue shared between the pool and its workers.  Unlike a channel it can be bounded
+/// and have its oldest entry taken back out by the sending side.
+pub(crate) struct Queue<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    closed: bool,
+}
+
+/// How a push went.
+pub(crate) enum Pushed<T> {
+    Queued,
+    /// Queued, and this is what was thrown out to make room for it.
+    Displaced(T),
+    /// Not queued; here it is back.
+    Full(T),
+}
+
+/// How a pop went.
+pub(crate) enum Popped<T> {
+    Item(T),
+    TimedOut,
+    /// Closed, and everything pushed before that has been popped.
+    Closed,
+}
+
+impl<T> Queue<T> {
+    pub(crate) fn new() -> Queue<T> {
+        Queue {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                capacity: None,
+                policy: QueuePolicy::Block,
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Sets the most items that can wait at once, or None for no limit, and what happens when
+    /// it's reached.
+    pub(crate) fn set_limit(&self, capacity: Option<usize>, policy: QueuePolicy) {
+        let mut state = self.lock();
+        state.capacity = capacity;
+        state.policy = policy;
+        drop(state);
+        // Anyone blocked may have room now
+        self.not_full.notify_all();
+    }
+
+    pub(crate) fn push(&self, item: T) -> Pushed<T> {
+        self.push_as(item, None)
+    }
+
+    /// Pushes as if the policy were `Reject`, whatever it is: never waits for room or throws
+    /// anything out to make it.
+    pub(crate) fn try_push(&self, item: T) -> Pushed<T> {
+        self.push_as(item, Some(QueuePolicy::Reject))
+    }
+
+    fn push_as(&self, item: T, policy: Option<QueuePolicy>) -> Pushed<T> {
+        let mut state = self.lock();
+        let policy = policy.unwrap_or(state.policy);
+        let mut displaced = None;
+        while state.is_full() {
+            match policy {
+                QueuePolicy::Block => {
+                    state = self
+                        .not_full
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                QueuePolicy::Reject => return Pushed::Full(item),
+                QueuePolicy::DropOldest => match state.items.pop_front() {
+                    Some(oldest) => displaced = Some(oldest),
+                    // A capacity of zero can never be satisfied by making room
+                    None => return Pushed::Full(item),
+                },
+            }
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.not_empty.notify_one();
+
+        match displaced {
+            Some(oldest) => Pushed::Displaced(oldest),
+            None => Pushed::Queued,
+        }
+    }
+
+    /// Wakes everyone waiting to pop, who get what's left and then `Popped::Closed`.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+    }
+
+    /// Waits up to `timeout` for the next item.
+    pub(crate) fn pop(&self, timeout: Duration) -> Popped<T> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                drop(state);
+                self.not_full.notify_one();
+                return Popped::Item(item);
+            }
+            if state.closed {
+                return Popped::Closed;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Popped::TimedOut;
+            }
+            state = self
+                .not_empty
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    // The queue itself is always consistent, whatever a panicking thread was doing with it
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> State<T> {
+    fn is_full(&self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.items.len() >= capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn queued<T>(pushed: Pushed<T>) -> bool {
+        matches!(pushed, Pushed::Queued)
+    }
+
+    fn pop<T>(queue: &Queue<T>) -> T {
+        match queue.pop(WAIT) {
+            Popped::Item(item) => item,
+            _ => panic!("nothing to pop"),
+        }
+    }
+
+    #[test]
+    fn reject_hands_the_item_back() {
+        let queue = Queue::new();
+        queue.set_limit(Some(2), QueuePolicy::Reject);
+        assert!(queued(queue.push(1)));
+        assert!(queued(queue.push(2)));
+        assert!(matches!(queue.push(3), Pushed::Full(3)));
+        assert_eq!(pop(&queue), 1);
+        assert!(queued(queue.push(4)));
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let queue = Queue::new();
+        queue.set_limit(Some(2), QueuePolicy::DropOldest);
+        queue.push(1);
+        queue.push(2);
+        assert!(matches!(queue.push(3), Pushed::Displaced(1)));
+        assert_eq!((pop(&queue), pop(&queue)), (2, 3));
+
+        queue.set_limit(Some(0), QueuePolicy::DropOldest);
+        assert!(matches!(queue.push(5), Pushed::Full(5)));
+    }
+
+    #[test]
+    fn try_push_never_waits_or_displaces() {
+        let queue = Queue::new();
+        for policy in [QueuePolicy::Block, QueuePolicy::DropOldest] {
+            queue.set_limit(Some(1), policy);
+            assert!(queued(queue.try_push(1)));
+            assert!(matches!(queue.try_push(2), Pushed::Full(2)));
+            assert_eq!(pop(&queue), 1);
+        }
+    }
+
+    #[test]
+    fn close_lets_the_rest_be_popped_first() {
+        let queue = Queue::new();
+        queue.push(1);
+        queue.close();
+        assert_eq!(pop(&queue), 1);
+        assert!(matches!(queue.pop(WAIT), Popped::Closed));
+        assert!(matches!(
+            Queue::<u8>::new().pop(Duration::from_millis(10)),
+            Popped::TimedOut
+        ));
+    }
+}
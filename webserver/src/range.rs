@@ -0,0 +1,184 @@
+//! The `Range` request header, for fetching part of a file such as the rest of an interrupted
+//! download.
+
+use std::fmt;
+
+// More ranges than this in one request is far beyond what a real client sends, and answering
+// thousands of tiny overlapping ones is a cheap way to make a server do a lot of work
+const MAX_RANGES: usize = 32;
+
+/// An inclusive span of bytes, as `Content-Range` writes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Never true, since a range always covers at least one byte.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The `Content-Range` value for this range of a `total` byte file.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// What a `Range` header asks for out of a file of a given length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges {
+    /// Malformed, in a unit other than bytes, or asking for too many ranges.  The spec says to
+    /// answer as if there was no header, with the whole file.
+    Ignore,
+    /// The parts of the file to send, in the order asked for and clipped to its length.
+    Satisfiable(Vec<ByteRange>),
+    /// Well formed but none of it lies within the file, which is a 416.
+    Unsatisfiable,
+}
+
+/// Works out what `header`, the value of a `Range` header, asks for out of `len` bytes.
+pub fn parse(header: &str, len: u64) -> Ranges {
+    let specs = match header.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Ignore,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim) {
+        // The list syntax allows empty elements, as in "bytes=0-1,,5-6"
+        if spec.is_empty() {
+            continue;
+        }
+        count += 1;
+        if count > MAX_RANGES {
+            return Ranges::Ignore;
+        }
+
+        let (first, last) = match spec.split_once('-') {
+            Some(pair) => pair,
+            None => return Ranges::Ignore,
+        };
+        let range = match (number(first), number(last)) {
+            // "-500", the last 500 bytes
+            (None, Some(suffix)) if first.is_empty() => {
+                if suffix == 0 || len == 0 {
+                    None
+                } else {
+                    Some(ByteRange {
+                        start: len.saturating_sub(suffix),
+                        end: len - 1,
+                    })
+                }
+            }
+            // "9500-", from there to the end
+            (Some(start), None) if last.is_empty() => range_from(start, u64::MAX, len),
+            (Some(start), Some(end)) if start <= end => range_from(start, end, len),
+            _ => return Ranges::Ignore,
+        };
+        ranges.extend(range);
+    }
+
+    if count == 0 {
+        Ranges::Ignore
+    } else if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Satisfiable(ranges)
+    }
+}
+
+// `start` to `end` clipped to the file, or None if it starts past the end
+fn range_from(start: u64, end: u64, len: u64) -> Option<ByteRange> {
+    if start >= len {
+        None
+    } else {
+        Some(ByteRange {
+            start,
+            end: end.min(len - 1),
+        })
+    }
+}
+
+fn number(digits: &str) -> Option<u64> {
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(pairs: &[(u64, u64)]) -> Ranges {
+        Ranges::Satisfiable(
+            pairs
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_each_form() {
+        assert_eq!(parse("bytes=0-499", 10_000), ranges(&[(0, 499)]));
+        assert_eq!(parse("bytes=9500-", 10_000), ranges(&[(9500, 9999)]));
+        assert_eq!(parse("bytes=-500", 10_000), ranges(&[(9500, 9999)]));
+        assert_eq!(
+            parse("Bytes = 500-999, -1,,0-0", 10_000),
+            ranges(&[(500, 999), (9999, 9999), (0, 0)])
+        );
+    }
+
+    #[test]
+    fn clips_to_the_file() {
+        assert_eq!(parse("bytes=5-1000", 10), ranges(&[(5, 9)]));
+        assert_eq!(parse("bytes=-1000", 10), ranges(&[(0, 9)]));
+        // The out of range part is dropped, the rest still served
+        assert_eq!(parse("bytes=20-30,0-1", 10), ranges(&[(0, 1)]));
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert_eq!(parse("bytes=10-", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=10-20,30-", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-0", 10), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=0-", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_anything_malformed() {
+        for header in [
+            "",
+            "bytes",
+            "bytes=",
+            "items=0-1",
+            "bytes=1",
+            "bytes=5-1",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=0-1,oops",
+            "bytes=99999999999999999999-",
+        ] {
+            assert_eq!(parse(header, 100), Ranges::Ignore, "{:?}", header);
+        }
+
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&many, 100), Ranges::Ignore);
+    }
+}
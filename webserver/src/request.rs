@@ -0,0 +1,694 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::headers::Headers;
+use crate::response::StatusCode;
+
+// Chunk size lines are a handful of hex digits; anything this long is an attack or garbage
+const MAX_CHUNK_LINE: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    /// Any other method.  It's still a valid request, the router just won't know what to do
+    /// with it.
+    Other(String),
+}
+
+impl Method {
+    fn from_token(token: &str) -> Method {
+        match token {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            other => Method::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+/// A complete HTTP request, body and all.  Chunked bodies have already been decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as sent, e.g. `/search?q=rust`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The target without its query string.
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    /// Whatever comes after the `?` in the target, if there is one.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+}
+
+/// How much a client is allowed to send.  Going over a limit fails the request with 431 or 413
+/// rather than letting one client eat all the memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Bytes in the request line and headers together, and separately in chunked trailers.
+    pub max_head: usize,
+    pub max_headers: usize,
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_head: 8 * 1024,
+            max_headers: 100,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+/// Why a request couldn't be parsed.  Each maps onto the status the server should answer with,
+/// after which the connection has to be closed since there's no telling where the next request
+/// would start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Malformed in some way; the message says how.
+    BadRequest(&'static str),
+    /// The connection closed part way through a request.
+    UnexpectedEof,
+    HeadersTooLarge,
+    BodyTooLarge,
+    /// A transfer coding other than chunked.
+    UnsupportedTransferCoding,
+    UnsupportedVersion,
+}
+
+impl ParseError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::BadRequest(_) | ParseError::UnexpectedEof => StatusCode::BadRequest,
+            ParseError::BodyTooLarge => StatusCode::PayloadTooLarge,
+            ParseError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedTransferCoding => StatusCode::NotImplemented,
+            ParseError::UnsupportedVersion => StatusCode::HttpVersionNotSupported,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(why) => write!(f, "bad request: {}", why),
+            ParseError::UnexpectedEof => write!(f, "connection closed mid request"),
+            ParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::UnsupportedTransferCoding => write!(f, "unsupported transfer coding"),
+            ParseError::UnsupportedVersion => write!(f, "unsupported HTTP version"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// What can go wrong reading a request off a connection.
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> ReadError {
+        ReadError::Parse(e)
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{}", e),
+            ReadError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Turns bytes into requests however they happen to arrive: a request split over many reads,
+/// or several in one read.  Feed it whatever comes off the socket and call `parse` until it hands
+/// back a request; anything after the end of that request stays buffered for the next one.
+#[derive(Debug)]
+pub struct Parser {
+    buf: Vec<u8>,
+    limits: Limits,
+    state: State,
+}
+
+#[derive(Debug)]
+enum State {
+    // Looking for the end of the request line and headers.
+    Head(HeadScan),
+    Body { request: Request, framing: Framing },
+}
+
+// How far through the head we've looked, so each call only looks at bytes it hasn't seen
+#[derive(Debug, Default)]
+struct HeadScan {
+    // Where the line we haven't seen the end of yet starts
+    line_start: usize,
+    // Everything before this has been searched for a newline already
+    scanned: usize,
+}
+
+#[derive(Debug)]
+enum Framing {
+    Length(usize),
+    ChunkSize,
+    ChunkData(usize),
+    ChunkEnd,
+    Trailers { seen: usize },
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Parser {
+        Parser {
+            buf: Vec::new(),
+            limits,
+            state: State::Head(HeadScan::default()),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// True when nothing of a next request has arrived yet, so the connection can close cleanly.
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, State::Head(_)) && self.buf.is_empty()
+    }
+
+    /// The next complete request, or None if more bytes are needed first.
+    pub fn parse(&mut self) -> Result<Option<Request>, ParseError> {
+        loop {
+            match &mut self.state {
+                State::Head(scan) => {
+                    let end = match find_head_end(&mut self.buf, scan) {
+                        Some(end) => end,
+                        None if self.buf.len() > self.limits.max_head => {
+                            return Err(ParseError::HeadersTooLarge)
+                        }
+                        None => return Ok(None),
+                    };
+                    if end > self.limits.max_head {
+                        return Err(ParseError::HeadersTooLarge);
+                    }
+
+                    let request = parse_head(&self.buf[..end], &self.limits)?;
+                    self.buf.drain(..end);
+                    let framing = framing(&request, &self.limits)?;
+                    self.state = State::Body { request, framing };
+                }
+                State::Body { request, framing } => {
+                    if !parse_body(&mut self.buf, request, framing, &self.limits)? {
+                        return Ok(None);
+                    }
+                    let done = std::mem::replace(&mut self.state, State::Head(HeadScan::default()));
+                    if let State::Body { request, .. } = done {
+                        return Ok(Some(request));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads from `reader` until there's a whole request, or returns None if it closed without
+    /// starting another one.
+    pub fn read_request(&mut self, reader: &mut impl Read) -> Result<Option<Request>, ReadError> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(request) = self.parse()? {
+                return Ok(Some(request));
+            }
+            let read = match reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ReadError::Io(e)),
+            };
+            if read == 0 {
+                return if self.is_idle() {
+                    Ok(None)
+                } else {
+                    Err(ParseError::UnexpectedEof.into())
+                };
+            }
+            self.feed(&chunk[..read]);
+        }
+    }
+}
+
+// Where the blank line ending the head finishes, if it's arrived.  Empty lines before the
+// request line are dropped, as RFC 9112 asks, since some clients send an extra CRLF after a body.
+// Bare LF line endings are accepted as well as CRLF.
+fn find_head_end(buf: &mut Vec<u8>, scan: &mut HeadScan) -> Option<usize> {
+    loop {
+        let from = scan.line_start.max(scan.scanned);
+        let newline = match buf[from..].iter().position(|&b| b == b'\n') {
+            Some(offset) => from + offset,
+            None => {
+                scan.scanned = buf.len();
+                return None;
+            }
+        };
+        let line = &buf[scan.line_start..newline];
+        if line.is_empty() || line == b"\r" {
+            if scan.line_start == 0 {
+                buf.drain(..=newline);
+                scan.scanned = 0;
+                continue;
+            }
+            *scan = HeadScan::default();
+            return Some(newline + 1);
+        }
+        scan.line_start = newline + 1;
+    }
+}
+
+fn lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    head.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+}
+
+fn parse_head(head: &[u8], limits: &Limits) -> Result<Request, ParseError> {
+    let mut lines = lines(head);
+
+    let request_line = lines
+        .next()
+        .ok_or(ParseError::BadRequest("no request line"))?;
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::BadRequest("malformed request line")),
+    };
+
+    if method.is_empty() || !method.iter().all(|&b| is_token(b)) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    if target.is_empty() || !target.iter().all(|&b| b.is_ascii_graphic()) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    let version = match version {
+        b"HTTP/1.1" => Version::Http11,
+        b"HTTP/1.0" => Version::Http10,
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+            if major.is_ascii_digit() && minor.is_ascii_digit() =>
+        {
+            return Err(ParseError::UnsupportedVersion)
+        }
+        _ => return Err(ParseError::BadRequest("invalid HTTP version")),
+    };
+
+    let mut headers = Headers::new();
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_header(line)?;
+        headers.append(name, value);
+    }
+
+    // Everything valid is ASCII by now, so these can't fail
+    let method = Method::from_token(std::str::from_utf8(method).unwrap());
+    let target = String::from_utf8(target.to_vec()).unwrap();
+
+    // HTTP/1.1 requires exactly one Host, and it's easy to slip something past a proxy otherwise
+    let hosts = headers.get_all("host").count();
+    if hosts > 1 || (hosts == 0 && version == Version::Http11) {
+        return Err(ParseError::BadRequest("need exactly one Host header"));
+    }
+
+    Ok(Request {
+        method,
+        target,
+        version,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+fn parse_header(line: &[u8]) -> Result<(String, String), ParseError> {
+    if line[0] == b' ' || line[0] == b'\t' {
+        return Err(ParseError::BadRequest("obsolete header line folding"));
+    }
+    let colon = line
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or(ParseError::BadRequest("header without a colon"))?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+
+    // Includes whitespace before the colon, which RFC 9112 says to reject
+    if name.is_empty() || !name.iter().all(|&b| is_token(b)) {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    if value.iter().any(|&b| (b < 0x20 && b != b'\t') || b == 0x7f) {
+        return Err(ParseError::BadRequest("control character in header value"));
+    }
+
+    let value = trim_whitespace(value);
+    Ok((
+        String::from_utf8(name.to_vec()).unwrap(),
+        String::from_utf8_lossy(value).into_owned(),
+    ))
+}
+
+// How the length of the body is given, per RFC 9112 section 6.3
+fn framing(request: &Request, limits: &Limits) -> Result<Framing, ParseError> {
+    let headers = &request.headers;
+
+    if headers.contains("transfer-encoding") {
+        // Both at once is the classic request smuggling setup
+        if headers.contains("content-length") {
+            return Err(ParseError::BadRequest(
+                "both Content-Length and Transfer-Encoding",
+            ));
+        }
+        let codings: Vec<&str> = headers
+            .get_all("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        return match codings.as_slice() {
+            [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(Framing::ChunkSize),
+            [.., last] if !last.eq_ignore_ascii_case("chunked") => Err(ParseError::BadRequest(
+                "chunked isn't the final transfer coding",
+            )),
+            _ => Err(ParseError::UnsupportedTransferCoding),
+        };
+    }
+
+    let mut length = None;
+    for value in headers
+        .get_all("content-length")
+        .flat_map(|value| value.split(','))
+    {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        // Too big to even parse is certainly too big to accept
+        let parsed: usize = value.parse().map_err(|_| ParseError::BodyTooLarge)?;
+        if length.is_some() && length != Some(parsed) {
+            return Err(ParseError::BadRequest("conflicting Content-Length values"));
+        }
+        length = Some(parsed);
+    }
+
+    let length = length.unwrap_or(0);
+    if length > limits.max_body {
+        return Err(ParseError::BodyTooLarge);
+    }
+    Ok(Framing::Length(length))
+}
+
+// Moves as much of the body as has arrived from `buf` into the request, returning whether it's
+// complete
+fn parse_body(
+    buf: &mut Vec<u8>,
+    request: &mut Request,
+    framing: &mut Framing,
+    limits: &Limits,
+) -> Result<bool, ParseError> {
+    loop {
+        match framing {
+            Framing::Length(remaining) => {
+                let take = (*remaining).min(buf.len());
+                request.body.extend(buf.drain(..take));
+                *remaining -= take;
+                return Ok(*remaining == 0);
+            }
+            Framing::ChunkSize => {
+                let line = match take_line(buf, MAX_CHUNK_LINE)? {
+                    Some(line) => line,
+                    None => return Ok(false),
+                };
+                let size = parse_chunk_size(&line)?;
+                if size > limits.max_body - request.body.len() {
+                    return Err(ParseError::BodyTooLarge);
+                }
+                *framing = if size == 0 {
+                    Framing::Trailers { seen: 0 }
+                } else {
+                    Framing::ChunkData(size)
+                };
+            }
+            Framing::ChunkData(remaining) => {
+                let take = (*remaining).min(buf.len());
+                request.body.extend(buf.drain(..take));
+                *remaining -= take;
+                if *remaining > 0 {
+                    return Ok(false);
+                }
+                *framing = Framing::ChunkEnd;
+            }
+            Framing::ChunkEnd => {
+                if buf.starts_with(b"\r\n") {
+                    buf.drain(..2);
+                } else if buf.starts_with(b"\n") {
+                    buf.drain(..1);
+                } else if buf.is_empty() || buf == b"\r" {
+                    return Ok(false);
+                } else {
+                    return Err(ParseError::BadRequest("chunk not followed by CRLF"));
+                }
+                *framing = Framing::ChunkSize;
+            }
+            Framing::Trailers { seen } => {
+                // Trailer fields aren't used for anything, but they still can't be unlimited
+                let line = match take_line(buf, limits.max_head.saturating_sub(*seen))? {
+                    Some(line) => line,
+                    None => return Ok(false),
+                };
+                if line.is_empty() {
+                    return Ok(true);
+                }
+                *seen += line.len() + 2;
+            }
+        }
+    }
+}
+
+// The next line from `buf` without its line ending, or None if it hasn't all arrived
+fn take_line(buf: &mut Vec<u8>, max: usize) -> Result<Option<Vec<u8>>, ParseError> {
+    let newline = match buf.iter().take(max + 1).position(|&b| b == b'\n') {
+        Some(newline) => newline,
+        None if buf.len() > max => return Err(ParseError::HeadersTooLarge),
+        None => return Ok(None),
+    };
+    let mut line: Vec<u8> = buf.drain(..=newline).collect();
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, ParseError> {
+    // Extensions after a semicolon are allowed and ignored
+    let size = line.split(|&b| b == b';').next().unwrap_or_default();
+    let size = trim_whitespace(size);
+    if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(ParseError::BadRequest("invalid chunk size"));
+    }
+    let size = std::str::from_utf8(size).unwrap();
+    usize::from_str_radix(size, 16).map_err(|_| ParseError::BodyTooLarge)
+}
+
+fn trim_whitespace(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
+    bytes
+}
+
+// RFC 9110 tchar
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Result<Option<Request>, ParseError> {
+        let mut parser = Parser::new();
+        parser.feed(bytes);
+        parser.parse()
+    }
+
+    #[test]
+    fn parses_a_simple_get() {
+        let request =
+            parse_all(b"GET /search?q=rust HTTP/1.1\r\nHost: localhost\r\nAccept:  */* \r\n\r\n")
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), Some("q=rust"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.header("ACCEPT"), Some("*/*"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_whole_request() {
+        let mut parser = Parser::new();
+        let request = b"POST /submit HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello";
+
+        for &b in &request[..request.len() - 1] {
+            parser.feed(&[b]);
+            assert_eq!(parser.parse(), Ok(None));
+        }
+        parser.feed(b"o");
+        assert_eq!(parser.parse().unwrap().unwrap().body, b"hello");
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let request = parse_all(
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nExpires: never\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(request.body, b"hello, world");
+    }
+
+    #[test]
+    fn keeps_the_next_request_buffered() {
+        let mut parser = Parser::new();
+        parser.feed(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\nGET /c");
+
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/a");
+        assert_eq!(parser.parse().unwrap().unwrap().target, "/b");
+        assert_eq!(parser.parse(), Ok(None));
+        assert!(!parser.is_idle());
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits {
+            max_head: 64,
+            max_headers: 2,
+            max_body: 4,
+        };
+        let parse = |bytes: &[u8]| {
+            let mut parser = Parser::with_limits(limits);
+            parser.feed(bytes);
+            parser.parse()
+        };
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n", "a".repeat(100));
+        assert_eq!(
+            parse(long_target.as_bytes()),
+            Err(ParseError::HeadersTooLarge)
+        );
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost: x\r\nA: 1\r\nB: 2\r\n\r\n"),
+            Err(ParseError::HeadersTooLarge)
+        );
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\n"),
+            Err(ParseError::BodyTooLarge)
+        );
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\n"),
+            Err(ParseError::BodyTooLarge)
+        );
+    }
+
+    #[test]
+    fn read_request_tells_a_clean_close_from_a_truncated_request() {
+        let mut parser = Parser::new();
+        let mut input: &[u8] = b"GET / HTTP/1.0\r\n\r\n";
+        assert!(parser.read_request(&mut input).unwrap().is_some());
+        assert!(parser.read_request(&mut input).unwrap().is_none());
+
+        let mut input: &[u8] = b"GET / HTTP/1.1\r\nHost";
+        assert!(matches!(
+            Parser::new().read_request(&mut input),
+            Err(ReadError::Parse(ParseError::UnexpectedEof))
+        ));
+    }
+}
@@ -0,0 +1,405 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use crate::headers::Headers;
+
+/// The status codes the server knows how to send.  The discriminant is the code itself, so
+/// `status as u16` works as well as `as_u16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    Continue = 100,
+    Ok = 200,
+    Created = 201,
+    Accepted = 202,
+    NoContent = 204,
+    PartialContent = 206,
+    MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+    HttpVersionNotSupported = 505,
+}
+
+impl StatusCode {
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// The standard reason phrase, e.g. "Not Found".
+    pub fn reason(self) -> &'static str {
+        match self {
+            StatusCode::Continue => "Continue",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PreconditionFailed => "Precondition Failed",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    pub fn is_server_error(self) -> bool {
+        self.as_u16() >= 500
+    }
+
+    // 1xx, 204 and 304 responses never have a body, not even an empty one with a length
+    fn allows_body(self) -> bool {
+        self.as_u16() >= 200 && self != StatusCode::NoContent && self != StatusCode::NotModified
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason())
+    }
+}
+
+// How much of a file is read into memory at once while sending it
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What follows a response's headers.  Files are streamed from disk a chunk at a time when the
+/// response is written, so sending one takes the same memory whatever its size.
+#[derive(Debug, Clone)]
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Written one after another, e.g. the parts of a multipart/byteranges response.
+    Parts(Vec<Part>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Part {
+    Bytes(Vec<u8>),
+    /// `len` bytes of `file` starting at `offset`.  The file is shared so several ranges of it
+    /// can go in one body.
+    File {
+        file: Arc<File>,
+        offset: u64,
+        len: u64,
+    },
+}
+
+impl Body {
+    /// A whole file, which must be `len` bytes long.
+    pub fn file(file: File, len: u64) -> Body {
+        Body::Parts(vec![Part::File {
+            file: Arc::new(file),
+            offset: 0,
+            len,
+        }])
+    }
+
+    /// Its length in bytes, as sent in `Content-Length`.
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::Parts(parts) => parts.iter().map(Part::len).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The whole body in memory, reading any files.  Mostly useful in tests.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Body::Bytes(bytes) => out.write_all(bytes),
+            Body::Parts(parts) => parts.iter().try_for_each(|part| part.write_to(out)),
+        }
+    }
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Bytes(bytes) => bytes.len() as u64,
+            Part::File { len, .. } => *len,
+        }
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let (file, offset, len) = match self {
+            Part::Bytes(bytes) => return out.write_all(bytes),
+            Part::File { file, offset, len } => (file, *offset, *len),
+        };
+
+        let mut file = &**file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len.min(CHUNK_SIZE as u64) as usize];
+        let mut left = len;
+        while left > 0 {
+            let want = left.min(buf.len() as u64) as usize;
+            let n = file.read(&mut buf[..want])?;
+            if n == 0 {
+                // Shrunk since the headers went out, and there's no way to take back the
+                // Content-Length now, so the connection has to fail
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file got shorter while being sent",
+                ));
+            }
+            out.write_all(&buf[..n])?;
+            left -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::Bytes(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+/// An HTTP response, built up with chained calls and then written out with `write_to`:
+///
+/// ```
+/// use webserver::{Response, StatusCode};
+///
+/// let mut out = Vec::new();
+/// Response::new(StatusCode::Ok)
+///     .header("Content-Type", "text/plain")
+///     .body("hello")
+///     .write_to(&mut out)
+///     .unwrap();
+///
+/// assert_eq!(
+///     out,
+///     b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello"
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::default(),
+        }
+    }
+
+    /// A response with an HTML body.
+    pub fn html(status: StatusCode, html: impl Into<Body>) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(html)
+    }
+
+    /// A plain text response, handy for errors.
+    pub fn text(status: StatusCode, text: impl Into<Body>) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(text)
+    }
+
+    /// Sets a header, replacing any value it already had.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.set(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Writes the whole response.  `Content-Length` is filled in from the body unless it's
+    /// already set.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        self.write_head(out)?;
+        if self.status.allows_body() {
+            self.body.write_to(out)?;
+        }
+        out.flush()
+    }
+
+    /// Writes just the status line and headers, for answering a HEAD request.  The headers are
+    /// the same as for the full response, `Content-Length` included.
+    pub fn write_head(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in self.headers.iter() {
+            check_header(name, value)?;
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if self.status.allows_body() && !self.headers.contains("content-length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        out.write_all(head.as_bytes())
+    }
+}
+
+// A newline in a header would let whoever controls the value add headers or a whole response
+// of their own
+fn check_header(name: &str, value: &str) -> io::Result<()> {
+    let name_ok = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    let value_ok = value
+        .bytes()
+        .all(|b| b == b'\t' || (b >= 0x20 && b != 0x7f));
+
+    if name_ok && value_ok {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid header {:?}: {:?}", name, value),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(response: &Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_status_headers_and_body() {
+        let response = Response::html(StatusCode::NotFound, "<h1>gone</h1>")
+            .header("Connection", "close")
+            .header("connection", "keep-alive");
+
+        assert_eq!(
+            written(&response),
+            "HTTP/1.1 404 Not Found\r\n\
+             Content-Type: text/html; charset=utf-8\r\n\
+             connection: keep-alive\r\n\
+             Content-Length: 13\r\n\
+             \r\n\
+             <h1>gone</h1>"
+        );
+    }
+
+    #[test]
+    fn bodiless_statuses_have_no_length_or_body() {
+        let response = Response::new(StatusCode::NotModified).body("ignored");
+        assert_eq!(written(&response), "HTTP/1.1 304 Not Modified\r\n\r\n");
+    }
+
+    #[test]
+    fn head_keeps_the_length_but_not_the_body() {
+        let response = Response::new(StatusCode::Ok).body(vec![0u8, 159, 146, 150]);
+
+        let mut full = Vec::new();
+        response.write_to(&mut full).unwrap();
+        assert!(full.ends_with(b"Content-Length: 4\r\n\r\n\x00\x9f\x92\x96"));
+
+        let mut head = Vec::new();
+        response.write_head(&mut head).unwrap();
+        assert_eq!(head, &full[..full.len() - 4]);
+    }
+
+    #[test]
+    fn refuses_header_injection() {
+        let response = Response::new(StatusCode::Found).header("Location", "/\r\nSet-Cookie: x=1");
+        let err = response.write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let response = Response::new(StatusCode::Ok).header("Bad Name", "x");
+        assert!(response.write_to(&mut Vec::new()).is_err());
+    }
+}
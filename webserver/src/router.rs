@@ -0,0 +1,325 @@
+use std::fmt;
+
+use crate::request::{Method, Request};
+use crate::response::{Response, StatusCode};
+
+/// What a route runs.  Handlers are shared between the pool's threads, hence Send + Sync.
+pub type Handler = Box<dyn Fn(&Request, &Params) -> Response + Send + Sync>;
+
+/// The values a request's path gave the `:name` and `*name` segments of the route it matched,
+/// percent-decoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, exactly one segment.
+    Param(String),
+    /// `*name`, everything that's left, which has to be at least one segment.  Only allowed last.
+    Wildcard(String),
+}
+
+impl Segment {
+    // Lower is more specific, and wins
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+
+    // Two routes for the same method whose patterns are the same shape could never be told
+    // apart, whatever their parameters are called
+    fn same_shape(&self, other: &Segment) -> bool {
+        match (self, other) {
+            (Segment::Literal(a), Segment::Literal(b)) => a == b,
+            _ => self.rank() == other.rank(),
+        }
+    }
+}
+
+/// A parsed route pattern such as `/users/:id` or `/static/*path`.
+#[derive(Clone, PartialEq, Eq)]
+struct Pattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(source: &str) -> Pattern {
+        assert!(
+            source.starts_with('/'),
+            "route pattern {:?} must start with /",
+            source
+        );
+
+        let pieces: Vec<&str> = source[1..].split('/').collect();
+        let mut segments = Vec::with_capacity(pieces.len());
+        for (i, piece) in pieces.iter().enumerate() {
+            let segment = if let Some(name) = piece.strip_prefix(':') {
+                assert!(!name.is_empty(), "unnamed parameter in {:?}", source);
+                Segment::Param(name.to_string())
+            } else if let Some(name) = piece.strip_prefix('*') {
+                assert!(
+                    i == pieces.len() - 1,
+                    "wildcard must be the last segment of {:?}",
+                    source
+                );
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(piece.to_string())
+            };
+            segments.push(segment);
+        }
+
+        Pattern {
+            source: source.to_string(),
+            segments,
+        }
+    }
+
+    fn matches(&self, path: &[String]) -> Option<Params> {
+        let mut params = Params::default();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    if i >= path.len() {
+                        return None;
+                    }
+                    params.pairs.push((name.clone(), path[i..].join("/")));
+                    return Some(params);
+                }
+                _ if i >= path.len() => return None,
+                Segment::Literal(literal) if *literal != path[i] => return None,
+                Segment::Literal(_) => {}
+                Segment::Param(name) => params.pairs.push((name.clone(), path[i].clone())),
+            }
+        }
+
+        if self.segments.len() == path.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    fn ranks(&self) -> impl Iterator<Item = u8> + '_ {
+        self.segments.iter().map(Segment::rank)
+    }
+
+    fn same_shape(&self, other: &Pattern) -> bool {
+        self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(&other.segments)
+                .all(|(a, b)| a.same_shape(b))
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+struct Route {
+    method: Method,
+    pattern: Pattern,
+    handler: Handler,
+}
+
+/// Picks a handler for each request by method and path.
+///
+/// Patterns are made of `/` separated segments, each either literal text, a `:name` parameter
+/// matching any one segment, or, last of all, a `*name` wildcard matching the rest of the path.
+/// When more than one pattern matches, the most specific wins regardless of the order they were
+/// added in: segments are compared left to right, and a literal beats a parameter which beats a
+/// wildcard.  So for `/users/me`, `/users/me` wins over `/users/:id`, which wins over `/users/*rest`.
+///
+/// Only routes for the request's method take part.  If the path matches some route but none for
+/// that method the answer is 405 with an `Allow` header, and if it matches nothing it's 404.  A
+/// HEAD request falls back to the GET route for its path when there isn't a HEAD one.
+///
+/// ```
+/// use webserver::{Method, Response, Router, StatusCode};
+///
+/// let router = Router::new()
+///     .get("/users/:id", |_, params| {
+///         Response::text(StatusCode::Ok, format!("user {}", params.get("id").unwrap()))
+///     })
+///     .route(Method::Delete, "/users/:id", |_, _| Response::new(StatusCode::NoContent));
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_, _| Response::text(StatusCode::NotFound, "not found")),
+        }
+    }
+
+    /// Adds a route.  Panics if the pattern is malformed or can't be told apart from one already
+    /// added for the same method, since either is a mistake in the code setting up the router.
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        let pattern = Pattern::parse(pattern);
+        if let Some(clash) = self
+            .routes
+            .iter()
+            .find(|route| route.method == method && route.pattern.same_shape(&pattern))
+        {
+            panic!(
+                "{} {:?} clashes with {} {:?}",
+                method, pattern, clash.method, clash.pattern
+            );
+        }
+
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// Replaces the default plain text 404.
+    pub fn not_found<F>(mut self, handler: F) -> Router
+    where
+        F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Runs whichever handler the request routes to.
+    pub fn handle(&self, request: &Request) -> Response {
+        let path = split_path(request.path());
+
+        if let Some((route, params)) = self.best(&request.method, &path) {
+            return (route.handler)(request, &params);
+        }
+        if request.method == Method::Head {
+            if let Some((route, params)) = self.best(&Method::Get, &path) {
+                return (route.handler)(request, &params);
+            }
+        }
+
+        let mut allowed: Vec<&Method> = Vec::new();
+        for route in &self.routes {
+            if route.pattern.matches(&path).is_some() && !allowed.contains(&&route.method) {
+                allowed.push(&route.method);
+            }
+        }
+        if allowed.is_empty() {
+            return (self.not_found)(request, &Params::default());
+        }
+        if allowed.contains(&&Method::Get) && !allowed.contains(&&Method::Head) {
+            allowed.push(&Method::Head);
+        }
+
+        let mut allow: Vec<&str> = allowed.iter().map(|method| method.as_str()).collect();
+        allow.sort_unstable();
+        Response::text(StatusCode::MethodNotAllowed, "method not allowed")
+            .header("Allow", allow.join(", "))
+    }
+
+    fn best(&self, method: &Method, path: &[String]) -> Option<(&Route, Params)> {
+        self.routes
+            .iter()
+            .filter(|route| route.method == *method)
+            .filter_map(|route| route.pattern.matches(path).map(|params| (route, params)))
+            .min_by(|(a, _), (b, _)| a.pattern.ranks().cmp(b.pattern.ranks()))
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.routes
+                    .iter()
+                    .map(|route| format!("{} {:?}", route.method, route.pattern)),
+            )
+            .finish()
+    }
+}
+
+// The segments of a path after the leading `/`, each percent-decoded.  `/` itself is one empty
+// segment, so it only matches the pattern `/`.
+fn split_path(path: &str) -> Vec<String> {
+    path.strip_prefix('/')
+        .unwrap_or(path)
+        .split('/')
+        .map(percent_decode)
+        .collect()
+}
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,142 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::pool::ThreadPool;
+
+type Job<'scope> = Box<dyn FnOnce() + Send + 'scope>;
+
+/// Lets jobs borrow from the stack of the thread that calls `ThreadPool::scope`, which waits
+/// for them all before returning.
+///
+/// Scoped jobs are kept apart from the pool's queue.  The pool is only asked to run them if
+/// there's room in its queue, without waiting for room or pushing other jobs out whatever its
+/// `QueuePolicy`, and the thread waiting on the scope runs them too.  So a scope works even if
+/// the pool's workers are all busy, or the scope is used from inside one of its jobs.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<State>,
+    // Invariant in both, as std::thread::Scope is, so neither can be shortened to let a job
+    // borrow something that doesn't live long enough
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct State {
+    work: Mutex<Work>,
+    finished: Condvar,
+}
+
+struct Work {
+    // Jobs with their lifetimes erased; see `Scope::spawn`
+    queued: VecDeque<Job<'static>>,
+    // Spawned and not yet finished, queued or running
+    unfinished: usize,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Runs `f` on the pool, or on the thread waiting for the scope, before the scope ends.
+    ///
+    /// If it panics, the other jobs still run, and then `ThreadPool::scope` panics with the
+    /// first panic once they're done.
+    pub fn spawn<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let job: Job<'scope> = Box::new(f);
+        // SAFETY: the job can only be run while the scope is unfinished, since `run` waits until
+        // it's been taken off the queue and has finished, whether it ran or panicked, before it
+        // returns.  So nothing it borrows for 'scope is gone before it's done with it.
+        let job = unsafe { mem::transmute::<Job<'scope>, Job<'static>>(job) };
+        {
+            let mut work = self.state.lock();
+            work.queued.push_back(job);
+            work.unfinished += 1;
+        }
+
+        // Asks for one job to be run, whichever is next by then.  If the pool's queue has no
+        // room, the waiting thread runs the job instead.
+        let state = Arc::clone(&self.state);
+        self.pool.try_execute(move || {
+            state.run_one();
+        });
+    }
+}
+
+impl State {
+    fn lock(&self) -> MutexGuard<'_, Work> {
+        self.work.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Runs the next queued job if there is one
+    fn run_one(&self) -> bool {
+        let job = match self.lock().queued.pop_front() {
+            Some(job) => job,
+            None => return false,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(job));
+
+        let mut work = self.lock();
+        if let Err(payload) = result {
+            work.panic.get_or_insert(payload);
+        }
+        work.unfinished -= 1;
+        if work.unfinished == 0 {
+            self.finished.notify_all();
+        }
+        true
+    }
+
+    // Helps run the queued jobs, and then waits for those running elsewhere
+    fn wait(&self) {
+        while self.run_one() {}
+        let mut work = self.lock();
+        while work.unfinished > 0 {
+            // More may have been spawned by the jobs still running
+            if !work.queued.is_empty() {
+                drop(work);
+                self.run_one();
+                work = self.lock();
+                continue;
+            }
+            work = self
+                .finished
+                .wait(work)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Calls `f` with a new scope on `pool`, and waits for every job spawned on it.
+pub(crate) fn run<'env, F, T>(pool: &ThreadPool, f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        pool,
+        state: Arc::new(State {
+            work: Mutex::new(Work {
+                queued: VecDeque::new(),
+                unfinished: 0,
+                panic: None,
+            }),
+            finished: Condvar::new(),
+        }),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+
+    // Jobs can borrow what `f` can, so they have to be finished even if it panics
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    scope.state.wait();
+
+    let job_panic = scope.state.lock().panic.take();
+    match (result, job_panic) {
+        (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+        (Ok(value), None) => value,
+    }
+}
@@ -0,0 +1,207 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::connection::{self, KeepAlive};
+use crate::events::{Event, Events};
+use crate::response::{Response, StatusCode};
+use crate::router::Router;
+use crate::{QueuePolicy, ThreadPool};
+
+/// Accepts connections and serves them with a `Router` on a pool of threads, until told to stop
+/// through a `ShutdownHandle`.
+///
+/// ```no_run
+/// use webserver::{Response, Router, Server, StatusCode};
+///
+/// let router = Router::new().get("/", |_, _| Response::text(StatusCode::Ok, "hello"));
+/// let server = Server::bind("127.0.0.1:7878", router).unwrap();
+/// let handle = server.shutdown_handle();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+///     handle.shutdown();
+/// });
+/// server.run().unwrap();
+/// ```
+pub struct Server {
+    listener: TcpListener,
+    router: Arc<Router>,
+    threads: usize,
+    max_threads: usize,
+    queue: usize,
+    keep_alive: KeepAlive,
+    shutdown_timeout: Duration,
+    events: Events,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, router: Router) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            router: Arc::new(router),
+            threads: 4,
+            max_threads: 4,
+            queue: 64,
+            keep_alive: KeepAlive::default(),
+            shutdown_timeout: Duration::from_secs(10),
+            events: Events::none(),
+            stopping: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// How many connections can be served at once.  4 unless set, which also sets
+    /// `max_threads` to match.
+    pub fn threads(mut self, threads: usize) -> Server {
+        self.threads = threads;
+        self.max_threads = threads;
+        self
+    }
+
+    /// Lets the pool start more threads, up to `max_threads`, when connections are waiting for
+    /// one.  They stop again after a minute with nothing to do.  The same as `threads` unless
+    /// set.
+    pub fn max_threads(mut self, max_threads: usize) -> Server {
+        self.max_threads = max_threads;
+        self
+    }
+
+    /// How many accepted connections can wait for a thread to serve them.  Any more are turned
+    /// away with 503 Service Unavailable.  64 unless set.
+    ///
+    /// At least 1, and 0 is taken as 1: even with a thread free, a connection passes through
+    /// the queue on its way to it, so with no room at all every one would be turned away.
+    pub fn queue(mut self, queue: usize) -> Server {
+        self.queue = queue.max(1);
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Server {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// How long requests already being served get to finish once shutdown starts.  10 seconds
+    /// unless set.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Where the server, its thread pool and its connections report what they're doing and
+    /// what goes wrong.  Nowhere unless set.
+    pub fn events(mut self, events: Events) -> Server {
+        self.events = events;
+        self
+    }
+
+    /// Where it's listening, which is the way to find the port after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stopping: Arc::clone(&self.stopping),
+            addr: self.listener.local_addr().ok(),
+        }
+    }
+
+    /// Serves until `shutdown` is called on one of its handles.  Then it stops accepting, lets
+    /// the requests in progress finish within the shutdown timeout, closes idle connections and
+    /// returns.
+    pub fn run(self) -> io::Result<()> {
+        let pool = ThreadPool::builder()
+            .min_threads(self.threads)
+            .max_threads(self.max_threads.max(self.threads))
+            .thread_name("worker")
+            .queue_limit(self.queue, QueuePolicy::Reject)
+            .events(self.events.clone())
+            .build()?;
+
+        for stream in self.listener.incoming() {
+            if self.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    // Usually the client giving up before it was accepted, or running out of
+                    // file descriptors, which a moment's pause may give time to clear
+                    self.events.emit(Event::AcceptFailed { error: &error });
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            };
+
+            // The job takes the stream with it, and is gone if the pool won't take it
+            let spare = match stream.try_clone() {
+                Ok(spare) => spare,
+                Err(error) => {
+                    let action = "clone connection";
+                    self.events.emit(Event::ConnectionFailed {
+                        action,
+                        error: &error,
+                    });
+                    continue;
+                }
+            };
+            let router = Arc::clone(&self.router);
+            let keep_alive = self.keep_alive;
+            let stopping = Arc::clone(&self.stopping);
+            let events = self.events.clone();
+            let queued = pool.execute(move || {
+                connection::serve_until(stream, &router, &keep_alive, &stopping, &events);
+            });
+            if queued.is_err() {
+                let peer = spare.peer_addr().ok();
+                self.events.emit(Event::ConnectionRefused { peer });
+                let response = Response::text(StatusCode::ServiceUnavailable, "server busy")
+                    .header("Retry-After", "1");
+                connection::refuse(&spare, response, &self.events);
+            }
+        }
+
+        // New connections are refused from here on
+        drop(self.listener);
+        self.events.emit(Event::ServerShuttingDown);
+        // The pool reports any requests it gave up waiting for
+        pool.shutdown(self.shutdown_timeout);
+        Ok(())
+    }
+}
+
+/// Stops a running `Server`.  Can be cloned and sent to other threads, a signal handler's
+/// for instance.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    stopping: Arc<AtomicBool>,
+    addr: Option<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    /// Starts the shutdown and returns straight away; `Server::run` returns once it's done.
+    pub fn shutdown(&self) {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        // The server is most likely blocked waiting for a connection, so give it one to wake it
+        if let Some(mut addr) = self.addr {
+            if addr.ip().is_unspecified() {
+                match addr {
+                    SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                    SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+                }
+            }
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::{Event, Events};
+use crate::httpdate;
+use crate::range::{self, ByteRange, Ranges};
+use crate::request::Request;
+use crate::response::{Body, Part, Response, StatusCode};
+use crate::router::Params;
+
+/// Serves the files under a document root.
+///
+/// Paths can't reach outside the root: `..` segments are refused outright, and since symlinks
+/// are resolved before checking, neither can a link inside the root pointing somewhere else.  A
+/// directory is answered with its `index.html`, or failing that a listing if those are turned
+/// on.
+///
+/// Files are sent with `Last-Modified` and `ETag` headers taken from their metadata, and a
+/// request whose `If-None-Match` or `If-Modified-Since` shows it already has the current version
+/// gets a bodiless 304 instead.  `Cache-Control` can be set per path prefix with
+/// `with_cache_control`.
+///
+/// Files are streamed rather than read into memory, and `Range` requests get just the parts
+/// asked for, several at once as multipart/byteranges.
+///
+/// Mount it on a wildcard route, whose `path` parameter says which file to serve:
+///
+/// ```no_run
+/// use webserver::{Router, StaticFiles};
+///
+/// let files = StaticFiles::new("public").unwrap().with_listings(true);
+/// let router = Router::new().get("/*path", files.handler());
+/// ```
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    listings: bool,
+    // (path prefix, Cache-Control value), the longest matching prefix wins
+    cache_control: Vec<(String, String)>,
+    events: Events,
+}
+
+impl StaticFiles {
+    /// Fails if `root` doesn't exist or isn't a directory.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<StaticFiles> {
+        // Canonical so it can be compared against the canonical paths of what's requested
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            listings: false,
+            cache_control: Vec::new(),
+            events: Events::none(),
+        })
+    }
+
+    /// Whether directories without an `index.html` get a listing of their contents, rather than
+    /// a 403.  Off by default.
+    pub fn with_listings(mut self, listings: bool) -> StaticFiles {
+        self.listings = listings;
+        self
+    }
+
+    /// Sends `Cache-Control: value` with files whose path below the root starts with `prefix`,
+    /// e.g. `with_cache_control("/assets/", "public, max-age=31536000, immutable")`.  Where
+    /// several prefixes match, the longest wins, so `"/"` can be used for a default.
+    pub fn with_cache_control(
+        mut self,
+        prefix: impl Into<String>,
+        value: impl Into<String>,
+    ) -> StaticFiles {
+        let prefix = prefix.into();
+        self.cache_control
+            .retain(|(existing, _)| *existing != prefix);
+        self.cache_control.push((prefix, value.into()));
+        self
+    }
+
+    /// Where to report files that exist but can't be read.  Nowhere unless set.
+    pub fn with_events(mut self, events: Events) -> StaticFiles {
+        self.events = events;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// A router handler serving the file named by the route's `path` parameter.
+    pub fn handler(self) -> impl Fn(&Request, &Params) -> Response + Send + Sync {
+        move |request, params| self.serve(request, params.get("path").unwrap_or_default())
+    }
+
+    /// Answers `request` with the file at `relative`, a `/` separated path below the root that's
+    /// already been percent-decoded.
+    pub fn serve(&self, request: &Request, relative: &str) -> Response {
+        let path = match self.resolve(relative) {
+            Ok(path) => path,
+            Err(status) => return Response::text(status, status.reason()),
+        };
+
+        let cache_control = self.cache_control_for(relative);
+        if !path.is_dir() {
+            return self.file(request, &path, cache_control);
+        }
+
+        // Relative links in the page only work if the directory's URL ends in a slash
+        if !request.path().ends_with('/') {
+            // Collapsing leading slashes stops `//example.com` becoming a redirect off site
+            let mut location = format!("/{}/", request.path().trim_start_matches('/'));
+            if let Some(query) = request.query() {
+                location = format!("{}?{}", location, query);
+            }
+            return Response::new(StatusCode::MovedPermanently).header("Location", location);
+        }
+
+        match self.inside_root(&path.join("index.html")) {
+            Ok(index) if index.is_file() => self.file(request, &index, cache_control),
+            Err(status) if status != StatusCode::NotFound => {
+                Response::text(status, status.reason())
+            }
+            _ if self.listings => listing(&path, request.path(), &self.events),
+            _ => Response::text(StatusCode::Forbidden, "Forbidden"),
+        }
+    }
+
+    // The canonical path for `relative`, or the status to answer with if there isn't one
+    fn resolve(&self, relative: &str) -> Result<PathBuf, StatusCode> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(StatusCode::Forbidden),
+                // A decoded %5C or %00 has no business in a file name either
+                _ if segment.contains('\\') || segment.contains('\0') => {
+                    return Err(StatusCode::Forbidden)
+                }
+                _ => path.push(segment),
+            }
+        }
+        self.inside_root(&path)
+    }
+
+    fn inside_root(&self, path: &Path) -> Result<PathBuf, StatusCode> {
+        let canonical = path.canonicalize().map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => StatusCode::Forbidden,
+            _ => StatusCode::NotFound,
+        })?;
+        if canonical.starts_with(&self.root) {
+            Ok(canonical)
+        } else {
+            Err(StatusCode::Forbidden)
+        }
+    }
+
+    fn cache_control_for(&self, relative: &str) -> Option<&str> {
+        let mut path = String::new();
+        for segment in relative.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            path.push('/');
+            path.push_str(segment);
+        }
+        if path.is_empty() || relative.ends_with('/') {
+            path.push('/');
+        }
+
+        self.cache_control
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn file(&self, request: &Request, path: &Path, cache_control: Option<&str>) -> Response {
+        // Everything comes from the one open handle, so the headers describe the same file as
+        // the body even if it's replaced part way through
+        let (file, metadata) = match File::open(path).and_then(|file| {
+            let metadata = file.metadata()?;
+            Ok((file, metadata))
+        }) {
+            Ok(opened) => opened,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return Response::text(StatusCode::Forbidden, "Forbidden")
+            }
+            Err(error) => {
+                self.events.emit(Event::FileFailed {
+                    action: "open",
+                    path,
+                    error: &error,
+                });
+                return Response::text(StatusCode::InternalServerError, "Internal Server Error");
+            }
+        };
+        let validators = Validators::new(&metadata);
+        let len = metadata.len();
+        let content_type = mime_type(path);
+
+        let mut response = if validators.fresh(request) {
+            Response::new(StatusCode::NotModified)
+        } else {
+            let ranges = match request.header("Range") {
+                Some(range) if validators.if_range(request) => range::parse(range, len),
+                _ => Ranges::Ignore,
+            };
+            match ranges {
+                Ranges::Ignore => Response::new(StatusCode::Ok)
+                    .header("Content-Type", content_type)
+                    .body(Body::file(file, len)),
+                Ranges::Satisfiable(ranges) => partial(file, len, content_type, &ranges),
+                Ranges::Unsatisfiable => {
+                    Response::text(StatusCode::RangeNotSatisfiable, "Range Not Satisfiable")
+                        .header("Content-Range", format!("bytes */{}", len))
+                }
+            }
+        };
+
+        if response.status != StatusCode::RangeNotSatisfiable {
+            response = response
+                .header("Last-Modified", httpdate::format(validators.modified))
+                .header("ETag", validators.etag);
+        }
+        if response.status != StatusCode::NotModified {
+            response = response.header("Accept-Ranges", "bytes");
+        }
+        if let Some(value) = cache_control {
+            response = response.header("Cache-Control", value);
+        }
+        response
+    }
+}
+
+// A 206 with the requested parts of `file`, as a multipart/byteranges body if there's more than
+// one
+fn partial(file: File, len: u64, content_type: &str, ranges: &[ByteRange]) -> Response {
+    let file = Arc::new(file);
+    let part = |range: &ByteRange| Part::File {
+        file: Arc::clone(&file),
+        offset: range.start,
+        len: range.len(),
+    };
+
+    if let [range] = ranges {
+        return Response::new(StatusCode::PartialContent)
+            .header("Content-Type", content_type)
+            .header("Content-Range", range.content_range(len))
+            .body(Body::Parts(vec![part(range)]));
+    }
+
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+    for range in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            content_type,
+            range.content_range(len)
+        );
+        parts.push(Part::Bytes(head.into_bytes()));
+        parts.push(part(range));
+    }
+    parts.push(Part::Bytes(
+        format!("\r\n--{}--\r\n", boundary).into_bytes(),
+    ));
+
+    Response::new(StatusCode::PartialContent)
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .body(Body::Parts(parts))
+}
+
+/// What a client can send back to ask whether its copy of a file is still current.
+struct Validators {
+    // To the second, as that's all an HTTP date can say
+    modified: SystemTime,
+    etag: String,
+}
+
+impl Validators {
+    fn new(metadata: &fs::Metadata) -> Validators {
+        let since_epoch = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Validators {
+            modified: UNIX_EPOCH + std::time::Duration::from_secs(since_epoch.as_secs()),
+            // Nanoseconds so that two writes in the same second still change it
+            etag: format!("\"{:x}-{:x}\"", since_epoch.as_nanos(), metadata.len()),
+        }
+    }
+
+    /// Whether the request's conditional headers say it already has this version.
+    /// `If-None-Match` takes precedence, and `If-Modified-Since` is only looked at without it.
+    fn fresh(&self, request: &Request) -> bool {
+        if request.headers.contains("If-None-Match") {
+            // Weak comparison is the right one for GET and HEAD
+            return request
+                .headers
+                .get_all("If-None-Match")
+                .flat_map(|value| value.split(','))
+                .map(|tag| tag.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+
+        match request
+            .header("If-Modified-Since")
+            .and_then(httpdate::parse)
+        {
+            Some(since) => self.modified <= since,
+            None => false,
+        }
+    }
+
+    /// Whether a `Range` header should be honoured.  With `If-Range` it only is if the client's
+    /// partial copy is of this version, otherwise it gets the whole file to start again with.
+    fn if_range(&self, request: &Request) -> bool {
+        match request.header("If-Range").map(str::trim) {
+            None => true,
+            // Strong comparison, so a weak tag never matches
+            Some(tag) if tag.starts_with('"') => tag == self.etag,
+            Some(date) => httpdate::parse(date) == Some(self.modified),
+        }
+    }
+}
+
+/// The Content-Type for a file, going by its extension.  Anything unknown is sent as
+/// `application/octet-stream`, which browsers download rather than guess at.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn listing(dir: &Path, url_path: &str, events: &Events) -> Response {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            events.emit(Event::FileFailed {
+                action: "list",
+                path: dir,
+                error: &error,
+            });
+            return Response::text(StatusCode::InternalServerError, "Internal Server Error");
+        }
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            name
+        })
+        .collect();
+    names.sort();
+
+    let title = html_escape(url_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title
+    );
+    if url_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in names {
+        html.push_str(&format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            percent_encode(&name),
+            html_escape(&name)
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    Response::html(StatusCode::Ok, html)
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Enough to make a file name safe as a relative link; `/` is kept for the one on directories
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -0,0 +1,240 @@
+// Talks to connection::serve over a real socket, sending requests back to back on one connection
+// the way a browser or a pipelining client would.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use webserver::connection;
+use webserver::{KeepAlive, Response, Router, StatusCode};
+
+struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+#[derive(Debug)]
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Serves one connection with `keep_alive`, echoing the path of each request back
+fn connect(keep_alive: KeepAlive) -> Client {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let router = Router::new()
+            .get("/*path", |request, _| {
+                Response::text(StatusCode::Ok, request.path().to_string())
+            })
+            .get("/close", |_, _| {
+                Response::text(StatusCode::Ok, "bye").header("Connection", "close")
+            });
+        let (stream, _) = listener.accept().unwrap();
+        connection::serve(stream, &router, &keep_alive);
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    Client {
+        writer: stream.try_clone().unwrap(),
+        reader: BufReader::new(stream),
+    }
+}
+
+impl Client {
+    fn send(&mut self, raw: &str) {
+        self.writer.write_all(raw.as_bytes()).unwrap();
+    }
+
+    // Reads one response, using Content-Length to find its end unless it answers a HEAD
+    fn reply(&mut self, head: bool) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line
+            .split(' ')
+            .nth(1)
+            .unwrap_or_else(|| panic!("no status line in {:?}", line))
+            .parse()
+            .unwrap();
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.push((name.to_string(), value.to_string()));
+        }
+
+        let mut reply = Reply {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        if !head {
+            let len: usize = reply.header("Content-Length").unwrap().parse().unwrap();
+            reply.body = vec![0; len];
+            self.reader.read_exact(&mut reply.body).unwrap();
+        }
+        reply
+    }
+
+    fn get(&mut self, path: &str) -> Reply {
+        self.send(&format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path));
+        self.reply(false)
+    }
+
+    // True if the server has closed the connection, having sent nothing more
+    fn closed(&mut self) -> bool {
+        let mut rest = Vec::new();
+        self.reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(String::from_utf8_lossy(&rest), "");
+        true
+    }
+}
+
+#[test]
+fn serves_several_requests_on_one_connection() {
+    let mut client = connect(KeepAlive::default());
+    for path in ["/a", "/b", "/c"] {
+        let reply = client.get(path);
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body, path.as_bytes());
+        assert_eq!(reply.header("Connection"), None);
+    }
+}
+
+#[test]
+fn answers_pipelined_requests_in_order() {
+    let mut client = connect(KeepAlive::default());
+    client.send(
+        "GET /one HTTP/1.1\r\nHost: test\r\n\r\n\
+         HEAD /two HTTP/1.1\r\nHost: test\r\n\r\n\
+         POST /three HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\r\nhello\
+         GET /four HTTP/1.1\r\nHost: test\r\n\r\n",
+    );
+
+    let one = client.reply(false);
+    assert_eq!(one.body, b"/one");
+    let two = client.reply(true);
+    assert_eq!(two.status, 200);
+    assert_eq!(two.header("Content-Length"), Some("4"));
+    let three = client.reply(false);
+    assert_eq!(three.status, 405);
+    assert_eq!(client.reply(false).body, b"/four");
+
+    // And the connection is still good afterwards
+    assert_eq!(client.get("/five").body, b"/five");
+}
+
+#[test]
+fn connection_close_from_the_client() {
+    let mut client = connect(KeepAlive::default());
+    assert_eq!(client.get("/first").status, 200);
+    client.send(
+        "GET /last HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n\
+         GET /ignored HTTP/1.1\r\nHost: test\r\n\r\n",
+    );
+
+    let reply = client.reply(false);
+    assert_eq!(reply.body, b"/last");
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(client.closed());
+}
+
+#[test]
+fn connection_close_from_a_handler() {
+    let mut client = connect(KeepAlive::default());
+    let reply = client.get("/close");
+    assert_eq!(reply.body, b"bye");
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(client.closed());
+}
+
+#[test]
+fn http_1_0_needs_to_ask_for_keep_alive() {
+    let mut client = connect(KeepAlive::default());
+    client.send("GET /kept HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    let reply = client.reply(false);
+    assert_eq!(reply.header("Connection"), Some("keep-alive"));
+
+    client.send("GET /dropped HTTP/1.0\r\n\r\n");
+    let reply = client.reply(false);
+    assert_eq!(reply.body, b"/dropped");
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(client.closed());
+}
+
+#[test]
+fn closes_after_max_requests() {
+    let mut client = connect(KeepAlive {
+        max_requests: 3,
+        ..KeepAlive::default()
+    });
+    assert_eq!(client.get("/1").header("Connection"), None);
+    assert_eq!(client.get("/2").header("Connection"), None);
+    let reply = client.get("/3");
+    assert_eq!(reply.body, b"/3");
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(client.closed());
+}
+
+#[test]
+fn closes_idle_connections() {
+    let mut client = connect(KeepAlive {
+        idle_timeout: Duration::from_millis(200),
+        ..KeepAlive::default()
+    });
+    assert_eq!(client.get("/1").status, 200);
+
+    let start = Instant::now();
+    assert!(client.closed());
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn half_sent_requests_time_out_with_408() {
+    let mut client = connect(KeepAlive {
+        idle_timeout: Duration::from_millis(200),
+        ..KeepAlive::default()
+    });
+    client.send("GET /slow HTTP/1.1\r\nHost: te");
+
+    let reply = client.reply(false);
+    assert_eq!(reply.status, 408);
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(client.closed());
+}
+
+#[test]
+fn bad_requests_end_the_connection() {
+    let mut client = connect(KeepAlive::default());
+    client.send(
+        "GET /fine HTTP/1.1\r\nHost: test\r\n\r\n\
+         GET /broken HTTP/1.1\r\n\r\n\
+         GET /never HTTP/1.1\r\nHost: test\r\n\r\n",
+    );
+
+    assert_eq!(client.reply(false).body, b"/fine");
+    let reply = client.reply(false);
+    assert_eq!(reply.status, 400);
+    assert_eq!(reply.header("Connection"), Some("close"));
+    assert!(client.closed());
+}
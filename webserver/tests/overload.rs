@@ -0,0 +1,97 @@
+// Runs a real Server with one thread and a short queue, and keeps connecting until it has to
+// turn clients away, and checks a queue set to 0 still lets clients through.
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use webserver::{Response, Router, Server, StatusCode};
+
+fn read_all(stream: &mut TcpStream) -> String {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn answers_503_when_the_queue_is_full() {
+    let router = Router::new().get("/slow", |_, _| {
+        thread::sleep(Duration::from_millis(500));
+        Response::text(StatusCode::Ok, "finally")
+    });
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .threads(1)
+        .queue(1);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    let connect = || {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    };
+    // One being served and one waiting its turn
+    let mut served = connect();
+    write!(
+        served,
+        "GET /slow HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let mut waiting = connect();
+    write!(
+        waiting,
+        "GET /slow HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // Sends nothing, as the answer comes before the server would read anything
+    let mut refused = connect();
+    let response = read_all(&mut refused);
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{}",
+        response
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{}", response);
+    assert!(response.contains("Connection: close\r\n"), "{}", response);
+
+    // Each dropped once read, since the server lingers until the client closes its end
+    assert!(read_all(&mut served).ends_with("finally"));
+    drop(served);
+    assert!(read_all(&mut waiting).ends_with("finally"));
+    drop(waiting);
+
+    handle.shutdown();
+    running.join().unwrap();
+}
+
+#[test]
+fn a_queue_of_zero_still_serves() {
+    let router = Router::new().get("/", |_, _| Response::text(StatusCode::Ok, "hello"));
+    let server = Server::bind("127.0.0.1:0", router).unwrap().queue(0);
+    let addr = server.local_addr().unwrap();
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let response = read_all(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    drop(stream);
+
+    handle.shutdown();
+    running.join().unwrap();
+}
@@ -0,0 +1,161 @@
+// Runs the request parser over the files in tests/corpus, each named for the status it should
+// produce (`ok-*` for a request that parses), then over randomly mangled copies of them.
+//
+// Every input is also fed in pieces of every size, since a parser that only works when a request
+// arrives in one read is the bug this is meant to catch.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use webserver::request::{ParseError, Parser, ReadError};
+use webserver::Request;
+
+// Hands out the input a few bytes at a time, the way a slow connection would
+struct Trickle<'a> {
+    data: &'a [u8],
+    step: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.step.min(self.data.len()).min(buf.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+// Every request in `input` up to the first error, plus that error if there was one
+fn parse(input: &[u8], step: usize) -> (Vec<Request>, Option<ParseError>) {
+    let mut parser = Parser::new();
+    let mut reader = Trickle { data: input, step };
+    let mut requests = Vec::new();
+    loop {
+        match parser.read_request(&mut reader) {
+            Ok(Some(request)) => requests.push(request),
+            Ok(None) => return (requests, None),
+            Err(ReadError::Parse(e)) => return (requests, Some(e)),
+            Err(ReadError::Io(e)) => panic!("reading from a slice failed: {}", e),
+        }
+    }
+}
+
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("corpus");
+    let mut files: Vec<(String, Vec<u8>)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    assert!(files.len() > 30, "the corpus has gone missing");
+    files
+}
+
+#[test]
+fn corpus_gives_the_expected_status() {
+    for (name, input) in corpus() {
+        let expected = name.split('-').next().unwrap();
+        let (requests, error) = parse(&input, input.len());
+
+        match (expected, error) {
+            ("ok", None) => assert_eq!(requests.len(), 1, "{}", name),
+            ("ok", Some(e)) => panic!("{} failed to parse: {}", name, e),
+            (status, Some(e)) => {
+                assert_eq!(e.status().as_u16().to_string(), status, "{}: {}", name, e)
+            }
+            (status, None) => panic!("{} parsed but should be a {}", name, status),
+        }
+    }
+}
+
+#[test]
+fn corpus_parses_the_same_whatever_the_read_size() {
+    for (name, input) in corpus() {
+        let whole = parse(&input, input.len());
+        for step in 1..=input.len().min(64) {
+            assert_eq!(
+                parse(&input, step),
+                whole,
+                "{} read {} bytes at a time",
+                name,
+                step
+            );
+        }
+    }
+}
+
+#[test]
+fn pipelined_corpus_requests_come_out_in_order() {
+    let ok: Vec<Vec<u8>> = corpus()
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("ok-"))
+        .map(|(_, input)| input)
+        .collect();
+
+    let all: Vec<u8> = ok.concat();
+    let one_by_one: Vec<Request> = ok.iter().map(|input| parse(input, 7).0.remove(0)).collect();
+    for step in [1, 5, 4096] {
+        let (requests, error) = parse(&all, step);
+        assert_eq!(error, None);
+        assert_eq!(requests, one_by_one);
+    }
+}
+
+// xorshift, so failures can be reproduced from the seed in the message
+fn rng(mut seed: u64) -> impl FnMut() -> u64 {
+    move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    }
+}
+
+#[test]
+fn mangled_corpus_never_panics_or_depends_on_read_size() {
+    // Bytes that tend to matter to a parser, more likely than random ones to find a bug
+    const INTERESTING: &[u8] = b"\r\n :;,\t\x00\x7f0aF-";
+
+    for (name, input) in corpus() {
+        for seed in 1..=50u64 {
+            let mut next = rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut mangled = input.clone();
+
+            for _ in 0..1 + next() % 4 {
+                let at = (next() as usize) % (mangled.len() + 1);
+                let byte = if next().is_multiple_of(2) {
+                    INTERESTING[(next() as usize) % INTERESTING.len()]
+                } else {
+                    next() as u8
+                };
+                match next() % 4 {
+                    0 if at < mangled.len() => mangled[at] = byte,
+                    1 if at < mangled.len() => {
+                        mangled.remove(at);
+                    }
+                    2 => mangled.truncate(at),
+                    _ => mangled.insert(at, byte),
+                }
+            }
+
+            let whole = parse(&mangled, mangled.len().max(1));
+            for step in [1, 2, 3, 17] {
+                assert_eq!(
+                    parse(&mangled, step),
+                    whole,
+                    "{} seed {} read {} at a time: {:?}",
+                    name,
+                    seed,
+                    step,
+                    String::from_utf8_lossy(&mangled)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Which route wins when several match.  Each handler answers with its own pattern, so the tests
+// can check where a request went without caring what the handlers do.
+
+use webserver::router::Params;
+use webserver::{Method, Parser, Request, Response, Router, StatusCode};
+
+fn request(method: &str, target: &str) -> Request {
+    let raw = format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, target);
+    let mut parser = Parser::new();
+    parser.feed(raw.as_bytes());
+    parser.parse().unwrap().unwrap()
+}
+
+// A handler that names the route it belongs to, followed by the params it was given
+fn named(name: &'static str) -> impl Fn(&Request, &Params) -> Response + Send + Sync {
+    move |_, params| {
+        let mut body = name.to_string();
+        for (param, value) in params.iter() {
+            body.push_str(&format!(" {}={}", param, value));
+        }
+        Response::text(StatusCode::Ok, body)
+    }
+}
+
+fn routed(router: &Router, method: &str, target: &str) -> String {
+    let response = router.handle(&request(method, target));
+    match response.status {
+        StatusCode::Ok => String::from_utf8(response.body.to_vec().unwrap()).unwrap(),
+        status => status.as_u16().to_string(),
+    }
+}
+
+fn users() -> Router {
+    // Added least specific first, to show the order doesn't matter
+    Router::new()
+        .get("/users/*rest", named("wildcard"))
+        .get("/users/:id", named("param"))
+        .get("/users/me", named("literal"))
+        .get("/users/:id/posts", named("posts"))
+        .get("/users/me/:tab", named("my tab"))
+        .get("/", named("root"))
+}
+
+#[test]
+fn literals_beat_params_beat_wildcards() {
+    let router = users();
+    assert_eq!(routed(&router, "GET", "/users/me"), "literal");
+    assert_eq!(routed(&router, "GET", "/users/42"), "param id=42");
+    assert_eq!(
+        routed(&router, "GET", "/users/42/likes"),
+        "wildcard rest=42/likes"
+    );
+    assert_eq!(routed(&router, "GET", "/users/42/posts"), "posts id=42");
+}
+
+#[test]
+fn earlier_segments_decide_first() {
+    let router = users();
+    // `me` is literal in "my tab" and a param in "posts", and that outweighs "posts" having a
+    // literal later on
+    assert_eq!(
+        routed(&router, "GET", "/users/me/posts"),
+        "my tab tab=posts"
+    );
+}
+
+#[test]
+fn paths_match_whole_segments_only() {
+    let router = users();
+    assert_eq!(routed(&router, "GET", "/"), "root");
+    assert_eq!(routed(&router, "GET", "/users"), "404");
+    assert_eq!(routed(&router, "GET", "/users/"), "param id=");
+    assert_eq!(routed(&router, "GET", "/usersx/1"), "404");
+    assert_eq!(routed(&router, "GET", "/users/me?tab=1"), "literal");
+}
+
+#[test]
+fn params_are_percent_decoded() {
+    let router = users();
+    assert_eq!(
+        routed(&router, "GET", "/users/J%C3%BCrgen%20K"),
+        "param id=Jürgen K"
+    );
+    assert_eq!(routed(&router, "GET", "/users/100%"), "param id=100%");
+    assert_eq!(routed(&router, "GET", "/users/%2f"), "param id=/");
+}
+
+#[test]
+fn wrong_method_is_405_with_allow() {
+    let router = users()
+        .route(Method::Delete, "/users/:id", named("delete"))
+        .post("/things", named("new thing"));
+
+    let response = router.handle(&request("PUT", "/users/42"));
+    assert_eq!(response.status, StatusCode::MethodNotAllowed);
+    assert_eq!(response.headers.get("Allow"), Some("DELETE, GET, HEAD"));
+
+    let response = router.handle(&request("GET", "/things"));
+    assert_eq!(response.status, StatusCode::MethodNotAllowed);
+    assert_eq!(response.headers.get("Allow"), Some("POST"));
+
+    assert_eq!(routed(&router, "PUT", "/nowhere"), "404");
+}
+
+#[test]
+fn a_less_specific_route_for_the_method_beats_405() {
+    let router = Router::new()
+        .post("/files/upload", named("upload"))
+        .get("/files/*path", named("download"));
+    assert_eq!(
+        routed(&router, "GET", "/files/upload"),
+        "download path=upload"
+    );
+}
+
+#[test]
+fn head_falls_back_to_get() {
+    let router = users().route(Method::Head, "/users/me", named("head me"));
+    assert_eq!(routed(&router, "HEAD", "/users/42"), "param id=42");
+    assert_eq!(routed(&router, "HEAD", "/users/me"), "head me");
+}
+
+#[test]
+fn custom_not_found() {
+    let router = users().not_found(|request, _| {
+        Response::text(StatusCode::NotFound, format!("no {}", request.path()))
+    });
+    let response = router.handle(&request("GET", "/nope"));
+    assert_eq!(response.status, StatusCode::NotFound);
+    assert_eq!(response.body.to_vec().unwrap(), b"no /nope");
+}
+
+#[test]
+#[should_panic(expected = "clashes")]
+fn indistinguishable_routes_panic() {
+    Router::new()
+        .get("/users/:id", named("one"))
+        .get("/users/:name", named("two"));
+}
+
+#[test]
+#[should_panic(expected = "last segment")]
+fn wildcard_must_be_last() {
+    Router::new().get("/files/*path/edit", named("edit"));
+}
@@ -0,0 +1,125 @@
+// Runs a real Server on a spare port and stops it through its ShutdownHandle while connections
+// are in various states.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use webserver::{KeepAlive, Response, Router, Server, ShutdownHandle, StatusCode};
+
+struct Running {
+    addr: SocketAddr,
+    handle: ShutdownHandle,
+    thread: JoinHandle<()>,
+}
+
+impl Running {
+    // Shuts down and waits for run to return, giving how long that took
+    fn stop(self) -> Duration {
+        let start = Instant::now();
+        self.handle.shutdown();
+        self.thread.join().unwrap();
+        start.elapsed()
+    }
+}
+
+fn start(shutdown_timeout: Duration) -> Running {
+    let router = Router::new()
+        .get("/", |_, _| Response::text(StatusCode::Ok, "hello"))
+        .get("/slow/:ms", |_, params| {
+            let ms = params.get("ms").unwrap().parse().unwrap();
+            thread::sleep(Duration::from_millis(ms));
+            Response::text(StatusCode::Ok, "finally")
+        });
+    let server = Server::bind("127.0.0.1:0", router)
+        .unwrap()
+        .threads(2)
+        // Long enough that a test only passes if shutdown doesn't wait for it
+        .keep_alive(KeepAlive {
+            idle_timeout: Duration::from_secs(30),
+            ..KeepAlive::default()
+        })
+        .shutdown_timeout(shutdown_timeout);
+
+    Running {
+        addr: server.local_addr().unwrap(),
+        handle: server.shutdown_handle(),
+        thread: thread::spawn(move || server.run().unwrap()),
+    }
+}
+
+fn send(addr: SocketAddr, path: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+    stream
+}
+
+fn read_all(stream: &mut TcpStream) -> String {
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn stops_accepting() {
+    let running = start(Duration::from_secs(5));
+    let addr = running.addr;
+    let mut stream = send(addr, "/");
+    let mut buf = [0; 1024];
+    assert!(stream.read(&mut buf).unwrap() > 0);
+    drop(stream);
+
+    assert!(running.stop() < Duration::from_secs(2));
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn in_flight_requests_finish() {
+    let running = start(Duration::from_secs(5));
+    let mut stream = send(running.addr, "/slow/500");
+    // Long enough for the request to have been picked up
+    thread::sleep(Duration::from_millis(100));
+
+    let stopping = thread::spawn(move || running.stop());
+    let response = read_all(&mut stream);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Connection: close\r\n"), "{}", response);
+    assert!(response.ends_with("finally"), "{}", response);
+    stopping.join().unwrap();
+}
+
+#[test]
+fn idle_connections_are_closed() {
+    let running = start(Duration::from_secs(5));
+    // Served, and then left open with nothing more to do
+    let mut stream = send(running.addr, "/");
+    let mut buf = [0; 1024];
+    assert!(stream.read(&mut buf).unwrap() > 0);
+
+    assert!(running.stop() < Duration::from_secs(2));
+    assert_eq!(stream.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn gives_up_at_the_deadline() {
+    let running = start(Duration::from_millis(200));
+    let _stream = send(running.addr, "/slow/5000");
+    thread::sleep(Duration::from_millis(100));
+
+    let took = running.stop();
+    assert!(took >= Duration::from_millis(200), "{:?}", took);
+    assert!(took < Duration::from_secs(2), "{:?}", took);
+}
+
+#[test]
+fn shutdown_twice_is_harmless() {
+    let running = start(Duration::from_secs(5));
+    let handle = running.handle.clone();
+    assert!(!handle.is_shutting_down());
+    handle.shutdown();
+    assert!(handle.is_shutting_down());
+    running.stop();
+}
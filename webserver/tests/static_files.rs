@@ -0,0 +1,455 @@
+// Serves a throwaway document root through a router, the way the server mounts it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use webserver::httpdate;
+use webserver::{Parser, Request, Response, Router, StaticFiles, StatusCode};
+
+// A fresh directory under the system temp dir, removed again when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "webserver-static-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Lays out
+//   outside/secret.txt
+//   root/index.html
+//   root/logo.PNG (not valid UTF-8)
+//   root/docs/a & b.txt
+//   root/docs/guide/index.html
+fn site() -> TempDir {
+    let dir = TempDir::new();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("docs").join("guide")).unwrap();
+    fs::create_dir_all(dir.path().join("outside")).unwrap();
+
+    fs::write(dir.path().join("outside").join("secret.txt"), "secret").unwrap();
+    fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+    fs::write(root.join("logo.PNG"), [0x89, b'P', b'N', b'G', 0xff, 0x00]).unwrap();
+    fs::write(root.join("docs").join("a & b.txt"), "notes").unwrap();
+    fs::write(root.join("docs").join("guide").join("index.html"), "guide").unwrap();
+    dir
+}
+
+fn router(dir: &TempDir, listings: bool) -> Router {
+    let files = StaticFiles::new(dir.path().join("root"))
+        .unwrap()
+        .with_listings(listings);
+    Router::new().get("/*path", files.handler())
+}
+
+fn get(router: &Router, target: &str) -> Response {
+    get_with(router, target, &[])
+}
+
+fn get_with(router: &Router, target: &str, headers: &[(&str, &str)]) -> Response {
+    let mut raw = format!("GET {} HTTP/1.1\r\nHost: test\r\n", target);
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str("\r\n");
+    let mut parser = Parser::new();
+    parser.feed(raw.as_bytes());
+    let request: Request = parser.parse().unwrap().unwrap();
+    router.handle(&request)
+}
+
+fn body(response: &Response) -> Vec<u8> {
+    response.body.to_vec().unwrap()
+}
+
+#[test]
+fn serves_files_with_their_mime_type() {
+    let dir = site();
+    let router = router(&dir, false);
+
+    let response = get(&router, "/logo.PNG");
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.headers.get("Content-Type"), Some("image/png"));
+    assert_eq!(body(&response), [0x89, b'P', b'N', b'G', 0xff, 0x00]);
+
+    let response = get(&router, "/docs/a%20%26%20b.txt");
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(
+        response.headers.get("Content-Type"),
+        Some("text/plain; charset=utf-8")
+    );
+    assert_eq!(body(&response), b"notes");
+
+    assert_eq!(get(&router, "/missing.css").status, StatusCode::NotFound);
+}
+
+#[test]
+fn directories_serve_their_index() {
+    let dir = site();
+    let router = router(&dir, false);
+
+    let response = get(&router, "/");
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(body(&response), b"<h1>home</h1>");
+    assert_eq!(body(&get(&router, "/docs/guide/")), b"guide");
+
+    let response = get(&router, "/docs/guide?v=2");
+    assert_eq!(response.status, StatusCode::MovedPermanently);
+    assert_eq!(response.headers.get("Location"), Some("/docs/guide/?v=2"));
+
+    // No index and no listings
+    assert_eq!(get(&router, "/docs/").status, StatusCode::Forbidden);
+}
+
+#[test]
+fn listings_escape_names() {
+    let dir = site();
+    let router = router(&dir, true);
+
+    let response = get(&router, "/docs/");
+    assert_eq!(response.status, StatusCode::Ok);
+    let html = String::from_utf8(body(&response)).unwrap();
+    assert!(
+        html.contains("<a href=\"a%20%26%20b.txt\">a &amp; b.txt</a>"),
+        "{}",
+        html
+    );
+    assert!(html.contains("<a href=\"guide/\">guide/</a>"), "{}", html);
+    assert!(html.contains("<a href=\"../\">"), "{}", html);
+}
+
+#[test]
+fn cannot_escape_the_root() {
+    let dir = site();
+    let router = router(&dir, true);
+
+    for target in [
+        "/../outside/secret.txt",
+        "/docs/../../outside/secret.txt",
+        "/%2e%2e/outside/secret.txt",
+        "/..%2foutside%2fsecret.txt",
+        "/docs/..%5c..%5coutside%5csecret.txt",
+        "/index.html%00.png",
+    ] {
+        let response = get(&router, target);
+        assert_eq!(response.status, StatusCode::Forbidden, "{}", target);
+        assert!(!body(&response).starts_with(b"secret"), "{}", target);
+    }
+
+    // Collapsed, so it can't redirect to another host
+    let response = get(&router, "//docs");
+    assert_eq!(response.headers.get("Location"), Some("/docs/"));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_are_followed_only_inside_the_root() {
+    use std::os::unix::fs::symlink;
+
+    let dir = site();
+    let root = dir.path().join("root");
+    symlink(root.join("index.html"), root.join("home.html")).unwrap();
+    symlink(dir.path().join("outside"), root.join("escape")).unwrap();
+    symlink(
+        dir.path().join("outside").join("secret.txt"),
+        root.join("secret.txt"),
+    )
+    .unwrap();
+    symlink(
+        dir.path().join("outside").join("secret.txt"),
+        root.join("docs").join("index.html"),
+    )
+    .unwrap();
+    let router = router(&dir, true);
+
+    assert_eq!(body(&get(&router, "/home.html")), b"<h1>home</h1>");
+    assert_eq!(
+        get(&router, "/escape/secret.txt").status,
+        StatusCode::Forbidden
+    );
+    assert_eq!(get(&router, "/secret.txt").status, StatusCode::Forbidden);
+    assert_eq!(get(&router, "/docs/").status, StatusCode::Forbidden);
+}
+
+#[test]
+fn files_carry_validators() {
+    let dir = site();
+    let router = router(&dir, false);
+
+    let response = get(&router, "/index.html");
+    let etag = response.headers.get("ETag").unwrap();
+    assert!(etag.starts_with('"') && etag.ends_with('"'), "{}", etag);
+    let modified = fs::metadata(dir.path().join("root").join("index.html"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(
+        response.headers.get("Last-Modified"),
+        Some(httpdate::format(modified).as_str())
+    );
+    // The same for a directory's index
+    assert_eq!(get(&router, "/").headers.get("ETag"), Some(etag));
+
+    // And a change to the file changes the tag
+    fs::write(
+        dir.path().join("root").join("index.html"),
+        "<h1>new home</h1>",
+    )
+    .unwrap();
+    assert_ne!(get(&router, "/index.html").headers.get("ETag"), Some(etag));
+}
+
+#[test]
+fn if_none_match_gives_304() {
+    let dir = site();
+    let router = router(&dir, false);
+    let etag = get(&router, "/logo.PNG")
+        .headers
+        .get("ETag")
+        .unwrap()
+        .to_string();
+
+    for header in [
+        etag.clone(),
+        format!("W/{}", etag),
+        format!("\"other\", {}", etag),
+        "*".to_string(),
+    ] {
+        let response = get_with(&router, "/logo.PNG", &[("If-None-Match", &header)]);
+        assert_eq!(response.status, StatusCode::NotModified, "{}", header);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+    }
+
+    let response = get_with(&router, "/logo.PNG", &[("If-None-Match", "\"other\"")]);
+    assert_eq!(response.status, StatusCode::Ok);
+}
+
+#[test]
+fn if_modified_since_gives_304() {
+    let dir = site();
+    let router = router(&dir, false);
+    let modified = get(&router, "/logo.PNG")
+        .headers
+        .get("Last-Modified")
+        .unwrap()
+        .to_string();
+    let earlier = httpdate::format(httpdate::parse(&modified).unwrap() - Duration::from_secs(1));
+    let later = httpdate::format(SystemTime::now() + Duration::from_secs(60));
+
+    let status =
+        |since: &str| get_with(&router, "/logo.PNG", &[("If-Modified-Since", since)]).status;
+    assert_eq!(status(&modified), StatusCode::NotModified);
+    assert_eq!(status(&later), StatusCode::NotModified);
+    assert_eq!(status(&earlier), StatusCode::Ok);
+    assert_eq!(status("not a date"), StatusCode::Ok);
+
+    // If-None-Match wins when both are sent
+    let response = get_with(
+        &router,
+        "/logo.PNG",
+        &[
+            ("If-None-Match", "\"other\""),
+            ("If-Modified-Since", &later),
+        ],
+    );
+    assert_eq!(response.status, StatusCode::Ok);
+}
+
+#[test]
+fn cache_control_by_longest_prefix() {
+    let dir = site();
+    let files = StaticFiles::new(dir.path().join("root"))
+        .unwrap()
+        .with_cache_control("/", "no-cache")
+        .with_cache_control("/docs/", "public, max-age=60")
+        .with_cache_control("/docs/guide/", "public, max-age=3600");
+    let router = Router::new().get("/*path", files.handler());
+
+    let cache_control = |target: &str| {
+        get(&router, target)
+            .headers
+            .get("Cache-Control")
+            .map(str::to_string)
+    };
+    assert_eq!(cache_control("/index.html").as_deref(), Some("no-cache"));
+    assert_eq!(cache_control("/").as_deref(), Some("no-cache"));
+    assert_eq!(
+        cache_control("/docs/a%20%26%20b.txt").as_deref(),
+        Some("public, max-age=60")
+    );
+    assert_eq!(
+        cache_control("/docs/guide/").as_deref(),
+        Some("public, max-age=3600")
+    );
+    assert_eq!(
+        cache_control("/docs//./guide/index.html").as_deref(),
+        Some("public, max-age=3600")
+    );
+
+    let etag = get(&router, "/docs/guide/")
+        .headers
+        .get("ETag")
+        .unwrap()
+        .to_string();
+    let response = get_with(&router, "/docs/guide/", &[("If-None-Match", &etag)]);
+    assert_eq!(response.status, StatusCode::NotModified);
+    assert_eq!(
+        response.headers.get("Cache-Control"),
+        Some("public, max-age=3600")
+    );
+}
+
+// Several read chunks long, with no two neighbouring bytes the same
+fn big_file(dir: &TempDir) -> Vec<u8> {
+    let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.path().join("root").join("big.bin"), &contents).unwrap();
+    contents
+}
+
+#[test]
+fn whole_files_stream_with_the_right_length() {
+    let dir = site();
+    let contents = big_file(&dir);
+    let response = get(&router(&dir, false), "/big.bin");
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.headers.get("Accept-Ranges"), Some("bytes"));
+    assert_eq!(response.body.len(), contents.len() as u64);
+
+    let mut written = Vec::new();
+    response.write_to(&mut written).unwrap();
+    let head = format!("Content-Length: {}\r\n\r\n", contents.len());
+    let body_start = written
+        .windows(head.len())
+        .position(|window| window == head.as_bytes())
+        .unwrap()
+        + head.len();
+    assert_eq!(&written[body_start..], &contents[..]);
+}
+
+#[test]
+fn single_ranges() {
+    let dir = site();
+    let contents = big_file(&dir);
+    let router = router(&dir, false);
+
+    for (range, start, end) in [
+        ("bytes=0-9", 0, 9),
+        ("bytes=65530-131080", 65_530, 131_080),
+        ("bytes=199990-", 199_990, 199_999),
+        ("bytes=-5", 199_995, 199_999),
+        ("bytes=199999-300000", 199_999, 199_999),
+    ] {
+        let response = get_with(&router, "/big.bin", &[("Range", range)]);
+        assert_eq!(response.status, StatusCode::PartialContent, "{}", range);
+        assert_eq!(
+            response.headers.get("Content-Range"),
+            Some(format!("bytes {}-{}/200000", start, end).as_str())
+        );
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(body(&response), &contents[start..=end], "{}", range);
+    }
+}
+
+#[test]
+fn multiple_ranges_are_multipart() {
+    let dir = site();
+    let contents = big_file(&dir);
+    let router = router(&dir, false);
+
+    let response = get_with(&router, "/big.bin", &[("Range", "bytes=100-104, -3")]);
+    assert_eq!(response.status, StatusCode::PartialContent);
+    assert_eq!(response.headers.get("Content-Range"), None);
+    let content_type = response.headers.get("Content-Type").unwrap();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap();
+
+    let mut expected = Vec::new();
+    for (start, end) in [(100, 104), (199_997, 199_999)] {
+        expected.extend_from_slice(
+            format!(
+                "\r\n--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/200000\r\n\r\n",
+                boundary, start, end
+            )
+            .as_bytes(),
+        );
+        expected.extend_from_slice(&contents[start..=end]);
+    }
+    expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+    assert_eq!(body(&response), expected);
+    assert_eq!(response.body.len(), expected.len() as u64);
+}
+
+#[test]
+fn unsatisfiable_and_ignored_ranges() {
+    let dir = site();
+    big_file(&dir);
+    let router = router(&dir, false);
+
+    let response = get_with(&router, "/big.bin", &[("Range", "bytes=200000-")]);
+    assert_eq!(response.status, StatusCode::RangeNotSatisfiable);
+    assert_eq!(
+        response.headers.get("Content-Range"),
+        Some("bytes */200000")
+    );
+
+    // Nonsense is treated as if there was no Range at all
+    for range in ["bytes=5-1", "lines=1-2", "bytes=x"] {
+        let response = get_with(&router, "/big.bin", &[("Range", range)]);
+        assert_eq!(response.status, StatusCode::Ok, "{}", range);
+        assert_eq!(response.body.len(), 200_000);
+    }
+}
+
+#[test]
+fn if_range_only_resumes_the_same_version() {
+    let dir = site();
+    big_file(&dir);
+    let router = router(&dir, false);
+    let full = get(&router, "/big.bin");
+    let etag = full.headers.get("ETag").unwrap();
+    let modified = full.headers.get("Last-Modified").unwrap();
+
+    let status = |if_range: &str| {
+        get_with(
+            &router,
+            "/big.bin",
+            &[("Range", "bytes=0-0"), ("If-Range", if_range)],
+        )
+        .status
+    };
+    assert_eq!(status(etag), StatusCode::PartialContent);
+    assert_eq!(status(modified), StatusCode::PartialContent);
+    assert_eq!(status(&format!("W/{}", etag)), StatusCode::Ok);
+    assert_eq!(status("\"stale\""), StatusCode::Ok);
+    assert_eq!(status("Thu, 01 Jan 1970 00:00:00 GMT"), StatusCode::Ok);
+}
+
+#[test]
+fn root_must_be_a_directory() {
+    let dir = site();
+    assert!(StaticFiles::new(dir.path().join("nope")).is_err());
+    assert!(StaticFiles::new(dir.path().join("root").join("index.html")).is_err());
+}
@@ -0,0 +1,620 @@
+// The pool has to keep serving whatever its jobs do, so these throw panics at it and check the
+// work after them still gets done, fill its queue to see each policy at work, wait on spawned
+// jobs' results, watch it grow and shrink, check work stealing finds every job, run scoped
+// jobs that borrow from the test's stack, and count jobs and listen to events along the way.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use webserver::events::{Event, Events, Level};
+use webserver::{ExecuteError, JobPanic, JoinError, PoolStats, QueuePolicy, Scheduler, ThreadPool};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+// Runs `count` jobs on `pool` and waits for them all to report back
+fn run_jobs(pool: &ThreadPool, count: usize) {
+    let (done, finished) = mpsc::channel();
+    for i in 0..count {
+        let done = done.clone();
+        pool.execute(move || done.send(i).unwrap()).unwrap();
+    }
+    let mut seen: Vec<usize> = (0..count)
+        .map(|_| finished.recv_timeout(WAIT).unwrap())
+        .collect();
+    seen.sort_unstable();
+    assert_eq!(seen, (0..count).collect::<Vec<_>>());
+}
+
+#[test]
+fn survives_panicking_jobs() {
+    let panics = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&panics);
+    let pool = ThreadPool::with_panic_handler(2, move |panic: &JobPanic| {
+        reported.lock().unwrap().push(panic.message.clone());
+    });
+
+    // More panics than workers, so a pool that lost a thread per panic would have none left
+    for i in 0..5 {
+        pool.execute(move || panic!("job {} failed", i)).unwrap();
+    }
+    pool.execute(|| std::panic::panic_any(42)).unwrap();
+    run_jobs(&pool, 20);
+
+    // The other worker can get through all of those while one is still unwinding
+    let start = Instant::now();
+    while panics.lock().unwrap().len() < 6 && start.elapsed() < WAIT {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut panics = panics.lock().unwrap().clone();
+    panics.sort();
+    assert_eq!(
+        panics,
+        [
+            "job 0 failed",
+            "job 1 failed",
+            "job 2 failed",
+            "job 3 failed",
+            "job 4 failed",
+            "non-string panic payload"
+        ]
+    );
+}
+
+#[test]
+fn replaces_workers_that_die() {
+    // A handler that panics takes its worker down with it, outside the job's catch_unwind
+    let deaths = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&deaths);
+    let pool = ThreadPool::with_panic_handler(1, move |_: &JobPanic| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        panic!("handler failed too");
+    });
+
+    for _ in 0..3 {
+        pool.execute(|| panic!("job failed")).unwrap();
+    }
+    // With one worker, these only run if it was replaced each time
+    run_jobs(&pool, 5);
+    assert_eq!(deaths.load(Ordering::SeqCst), 3);
+
+    assert!(pool.shutdown(WAIT));
+}
+
+#[test]
+fn drop_joins_replacement_workers() {
+    let pool = ThreadPool::with_panic_handler(2, |_: &JobPanic| panic!("handler failed"));
+    pool.execute(|| panic!("job failed")).unwrap();
+    run_jobs(&pool, 4);
+    // Hangs or panics if it loses track of a replacement
+    drop(pool);
+}
+
+// A pool of one thread with a limited queue
+fn limited(capacity: usize, policy: QueuePolicy) -> ThreadPool {
+    ThreadPool::builder()
+        .max_threads(1)
+        .queue_limit(capacity, policy)
+        .build()
+        .unwrap()
+}
+
+// Gives the pool's one worker a job that holds it until the returned sender is used or dropped
+fn occupy(pool: &ThreadPool) -> mpsc::Sender<()> {
+    let (release, released) = mpsc::channel();
+    let (started, has_started) = mpsc::channel();
+    pool.execute(move || {
+        started.send(()).unwrap();
+        let _ = released.recv();
+    })
+    .unwrap();
+    has_started.recv_timeout(WAIT).unwrap();
+    release
+}
+
+#[test]
+fn reject_fails_when_full() {
+    let pool = limited(2, QueuePolicy::Reject);
+    let release = occupy(&pool);
+    let (done, finished) = mpsc::channel();
+    for i in 0..2 {
+        let done = done.clone();
+        pool.execute(move || done.send(i).unwrap()).unwrap();
+    }
+    assert_eq!(pool.queued(), 2);
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+
+    drop(release);
+    assert_eq!(finished.recv_timeout(WAIT).unwrap(), 0);
+    assert_eq!(finished.recv_timeout(WAIT).unwrap(), 1);
+    // And there's room again
+    run_jobs(&pool, 2);
+}
+
+#[test]
+fn drop_oldest_makes_room() {
+    let pool = limited(2, QueuePolicy::DropOldest);
+    let release = occupy(&pool);
+    let (done, finished) = mpsc::channel();
+    for i in 0..5 {
+        let done = done.clone();
+        pool.execute(move || done.send(i).unwrap()).unwrap();
+    }
+    drop(done);
+    assert_eq!(pool.queued(), 2);
+
+    drop(release);
+    assert_eq!(finished.iter().collect::<Vec<_>>(), [3, 4]);
+}
+
+#[test]
+fn block_waits_for_room() {
+    let pool = Arc::new(limited(1, QueuePolicy::Block));
+    let release = occupy(&pool);
+    pool.execute(|| {}).unwrap();
+
+    let (done, finished) = mpsc::channel();
+    let blocked = Arc::clone(&pool);
+    let adding = thread::spawn(move || {
+        blocked.execute(move || done.send(()).unwrap()).unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(!adding.is_finished());
+
+    drop(release);
+    adding.join().unwrap();
+    finished.recv_timeout(WAIT).unwrap();
+}
+
+#[test]
+fn a_queue_limit_of_zero_still_runs_jobs() {
+    for policy in [
+        QueuePolicy::Block,
+        QueuePolicy::Reject,
+        QueuePolicy::DropOldest,
+    ] {
+        let pool = limited(0, policy);
+        run_jobs(&pool, 1);
+        assert!(pool.shutdown(WAIT));
+    }
+}
+
+#[test]
+fn shutdown_gets_through_a_full_queue() {
+    let pool = limited(1, QueuePolicy::Reject);
+    let release = occupy(&pool);
+    pool.execute(|| {}).unwrap();
+
+    drop(release);
+    assert!(pool.shutdown(WAIT));
+}
+
+#[test]
+fn spawn_gives_back_results() {
+    let pool = ThreadPool::new(3);
+    let handles: Vec<_> = (0..10u64)
+        .map(|n| pool.spawn(move || n * n).unwrap())
+        .collect();
+    let squares: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(squares, (0..10).map(|n| n * n).collect::<Vec<_>>());
+}
+
+#[test]
+fn spawned_panics_come_back_through_the_handle() {
+    let reported = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&reported);
+    let pool = ThreadPool::with_panic_handler(1, move |_: &JobPanic| {
+        counted.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let handle = pool.spawn(|| -> u32 { panic!("bad input") }).unwrap();
+    match handle.join() {
+        Err(error @ JoinError::Panicked(_)) => {
+            assert_eq!(error.to_string(), "job panicked: bad input");
+            let payload = error.into_panic().unwrap();
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"bad input"));
+        }
+        other => panic!("expected a panic, got {:?}", other),
+    }
+    // The handle had it, so the pool's handler didn't
+    assert_eq!(pool.spawn(|| 1).unwrap().join().unwrap(), 1);
+    assert_eq!(reported.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn try_join_and_join_timeout() {
+    let pool = ThreadPool::new(1);
+    let release = occupy(&pool);
+    let mut handle = pool.spawn(|| "done").unwrap();
+
+    assert!(handle.try_join().is_none());
+    assert!(handle.join_timeout(Duration::from_millis(50)).is_none());
+    assert!(!handle.is_finished());
+
+    drop(release);
+    assert_eq!(handle.join_timeout(WAIT).unwrap().unwrap(), "done");
+}
+
+#[test]
+fn dropped_jobs_tell_their_handles() {
+    let pool = limited(1, QueuePolicy::DropOldest);
+    let release = occupy(&pool);
+    let first = pool.spawn(|| 1).unwrap();
+    let second = pool.spawn(|| 2).unwrap();
+
+    assert!(first.is_finished());
+    assert!(matches!(first.join(), Err(JoinError::Dropped)));
+    drop(release);
+    assert_eq!(second.join().unwrap(), 2);
+}
+
+#[test]
+fn spawn_respects_the_queue_limit() {
+    let pool = limited(1, QueuePolicy::Reject);
+    let _release = occupy(&pool);
+    pool.execute(|| {}).unwrap();
+    assert_eq!(pool.spawn(|| 1).unwrap_err(), ExecuteError::QueueFull);
+}
+
+// Polls `stats` until `check` is happy with them, failing after WAIT
+fn wait_for(pool: &ThreadPool, check: impl Fn(&PoolStats) -> bool) -> PoolStats {
+    let start = Instant::now();
+    loop {
+        let stats = pool.stats();
+        if check(&stats) {
+            return stats;
+        }
+        assert!(start.elapsed() < WAIT, "gave up waiting, at {:?}", stats);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn grows_when_busy_and_shrinks_when_idle() {
+    let pool = ThreadPool::builder()
+        .min_threads(1)
+        .max_threads(3)
+        .keep_alive(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    assert_eq!(pool.stats().threads, 1);
+
+    // Each holds its worker, so the pool has to grow to start the next
+    let (release, released) = mpsc::channel::<()>();
+    let released = Arc::new(Mutex::new(released));
+    for _ in 0..5 {
+        let released = Arc::clone(&released);
+        pool.execute(move || {
+            let _ = released.lock().unwrap().recv();
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let stats = wait_for(&pool, |stats| stats.active == 3);
+    assert_eq!(stats.threads, 3);
+    // No more threads allowed, so the rest wait
+    assert_eq!(stats.queued, 2);
+
+    drop(release);
+    let stats = wait_for(&pool, |stats| stats.threads == 1);
+    assert_eq!((stats.active, stats.queued), (0, 0));
+    assert_eq!(stats.idle, 1);
+    run_jobs(&pool, 3);
+}
+
+#[test]
+fn names_threads_and_sets_their_stack_size() {
+    let pool = ThreadPool::builder()
+        .max_threads(1)
+        .thread_name("cruncher")
+        .stack_size(4 * 1024 * 1024)
+        .build()
+        .unwrap();
+    // Deep enough to need more than the 2MB threads get by default
+    let name = pool
+        .spawn(|| {
+            let big = [1u8; 3 * 1024 * 1024];
+            assert_eq!(std::hint::black_box(&big)[0], 1);
+            thread::current().name().map(str::to_string)
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(name.as_deref(), Some("cruncher-0"));
+}
+
+fn stealing(threads: usize) -> ThreadPool {
+    ThreadPool::builder()
+        .max_threads(threads)
+        .scheduler(Scheduler::WorkStealing)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn work_stealing_takes_jobs_from_busy_workers() {
+    let pool = stealing(2);
+    let release = occupy(&pool);
+    // Half of these go on the busy worker's queue, and only run if the other steals them
+    run_jobs(&pool, 10);
+    drop(release);
+}
+
+#[test]
+fn work_stealing_runs_jobs_queued_by_jobs() {
+    // Leaked, as jobs borrowing the pool need it to outlive them
+    let pool: &'static ThreadPool = Box::leak(Box::new(stealing(2)));
+    let mut total = pool
+        .spawn(move || {
+            // Queued on this worker's own queue, and it's busy waiting for them, so the other
+            // worker has to steal them all
+            let parts: Vec<_> = (1..=10u32)
+                .map(|n| pool.spawn(move || n * 10).unwrap())
+                .collect();
+            parts
+                .into_iter()
+                .map(|part| part.join().unwrap())
+                .sum::<u32>()
+        })
+        .unwrap();
+    assert_eq!(total.join_timeout(WAIT).unwrap().unwrap(), 550);
+}
+
+#[test]
+fn work_stealing_keeps_the_queue_limit_and_shuts_down() {
+    let pool = ThreadPool::builder()
+        .max_threads(1)
+        .scheduler(Scheduler::WorkStealing)
+        .queue_limit(2, QueuePolicy::Reject)
+        .build()
+        .unwrap();
+    let release = occupy(&pool);
+    pool.execute(|| {}).unwrap();
+    pool.execute(|| {}).unwrap();
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+    assert_eq!(pool.stats().queued, 2);
+
+    drop(release);
+    assert!(pool.shutdown(WAIT));
+}
+
+#[test]
+fn scoped_jobs_borrow_from_the_stack() {
+    let pool = ThreadPool::new(3);
+    let numbers: Vec<u64> = (1..=1000).collect();
+    let total = AtomicUsize::new(0);
+    let mut tails = vec![0; 4];
+    pool.scope(|scope| {
+        for (chunk, tail) in numbers.chunks(250).zip(tails.iter_mut()) {
+            let total = &total;
+            scope.spawn(move || {
+                let sum: u64 = chunk.iter().sum();
+                total.fetch_add(sum as usize, Ordering::SeqCst);
+                *tail = *chunk.last().unwrap();
+            });
+        }
+    });
+    assert_eq!(total.into_inner(), 500_500);
+    assert_eq!(tails, [250, 500, 750, 1000]);
+}
+
+#[test]
+fn scope_finishes_every_job_before_passing_on_a_panic() {
+    let pool = ThreadPool::new(2);
+    let ran = AtomicUsize::new(0);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        pool.scope(|scope| {
+            scope.spawn(|| panic!("scoped job failed"));
+            for _ in 0..10 {
+                scope.spawn(|| {
+                    thread::sleep(Duration::from_millis(5));
+                    ran.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        })
+    }));
+    let payload = result.unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"scoped job failed"));
+    assert_eq!(ran.load(Ordering::SeqCst), 10);
+    // And the pool is fine afterwards
+    run_jobs(&pool, 4);
+}
+
+#[test]
+fn scope_works_when_the_pool_cant_help() {
+    // The only worker is busy and the queue is full, so the caller runs everything
+    let pool = limited(1, QueuePolicy::Reject);
+    let release = occupy(&pool);
+    pool.execute(|| {}).unwrap();
+    let doubled = pool.par_map(&[1, 2, 3], |n| n * 2);
+    assert_eq!(doubled, [2, 4, 6]);
+    drop(release);
+}
+
+#[test]
+fn scopes_leave_a_drop_oldest_queue_alone() {
+    let pool = limited(4, QueuePolicy::DropOldest);
+    let release = occupy(&pool);
+    let answer = pool.spawn(|| 42).unwrap();
+    let mut numbers: Vec<u32> = (0..100).collect();
+    pool.par_for_each(&mut numbers, |n| *n += 1);
+    assert_eq!(numbers, (1..=100).collect::<Vec<_>>());
+
+    drop(release);
+    assert_eq!(answer.join().unwrap(), 42);
+    assert_eq!(pool.stats().dropped, 0);
+}
+
+#[test]
+fn scopes_inside_jobs_dont_block_on_a_full_queue() {
+    // Every worker is in a scope, with the queue full behind them, so a scope that waited for
+    // room would never get it
+    let pool: &'static ThreadPool = Box::leak(Box::new(
+        ThreadPool::builder()
+            .max_threads(2)
+            .queue_limit(1, QueuePolicy::Block)
+            .build()
+            .unwrap(),
+    ));
+    let (started, has_started) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let released = Arc::new(Mutex::new(released));
+    let sums: Vec<_> = (0..2)
+        .map(|_| {
+            let started = started.clone();
+            let released = Arc::clone(&released);
+            pool.spawn(move || {
+                started.send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+                pool.par_map(&(1..=100u64).collect::<Vec<_>>(), |n| n * n)
+                    .iter()
+                    .sum::<u64>()
+            })
+            .unwrap()
+        })
+        .collect();
+    for _ in 0..2 {
+        has_started.recv_timeout(WAIT).unwrap();
+    }
+    pool.execute(|| {}).unwrap();
+
+    drop(release);
+    for mut sum in sums {
+        assert_eq!(sum.join_timeout(WAIT).unwrap().unwrap(), 338_350);
+    }
+}
+
+#[test]
+fn scopes_inside_jobs_dont_deadlock() {
+    let pool: &'static ThreadPool = Box::leak(Box::new(ThreadPool::new(1)));
+    let sum = pool
+        .spawn(move || {
+            let squares = pool.par_map(&(1..=100u64).collect::<Vec<_>>(), |n| n * n);
+            squares.iter().sum::<u64>()
+        })
+        .unwrap();
+    assert_eq!(sum.join().unwrap(), 338_350);
+}
+
+#[test]
+fn par_map_and_par_for_each() {
+    let pool = stealing(4);
+    let words: Vec<String> = (0..1000).map(|n| format!("word{}", n)).collect();
+    let lengths = pool.par_map(&words, |word| word.len());
+    assert_eq!(lengths, words.iter().map(String::len).collect::<Vec<_>>());
+    assert!(pool.par_map(&[] as &[u8], |&n| n).is_empty());
+
+    let mut numbers: Vec<u32> = (0..1000).collect();
+    pool.par_for_each(&mut numbers, |n| *n += 1);
+    assert_eq!(numbers, (1..=1000).collect::<Vec<_>>());
+}
+
+#[test]
+fn counts_jobs_and_how_long_they_took() {
+    let pool = limited(1, QueuePolicy::Reject);
+    let release = occupy(&pool);
+    pool.execute(|| panic!("job failed")).unwrap();
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::QueueFull));
+    thread::sleep(Duration::from_millis(20));
+    drop(release);
+    wait_for(&pool, |stats| stats.queued == 0);
+    run_jobs(&pool, 1);
+
+    // The occupying job, the panicking one and run_jobs's one
+    let stats = wait_for(&pool, |stats| stats.completed + stats.panicked == 3);
+    assert_eq!((stats.submitted, stats.rejected, stats.dropped), (3, 1, 0));
+    assert_eq!((stats.completed, stats.panicked), (2, 1));
+    // The occupying job ran, and the panicking one waited, for at least the sleep
+    assert!(stats.max_run >= Duration::from_millis(20));
+    assert!(stats.max_wait >= Duration::from_millis(20));
+    assert!(stats.total_wait >= stats.max_wait && stats.total_run >= stats.max_run);
+    assert!(stats.mean_run() <= stats.max_run && stats.mean_run() > Duration::ZERO);
+}
+
+#[test]
+fn reports_events_to_the_sink() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let pool = ThreadPool::builder()
+        .max_threads(1)
+        .queue_limit(1, QueuePolicy::DropOldest)
+        .events(Events::new(move |event: &Event| {
+            sink.lock()
+                .unwrap()
+                .push((event.level(), event.to_string()));
+        }))
+        .build()
+        .unwrap();
+    let release = occupy(&pool);
+    pool.execute(|| {}).unwrap();
+    pool.execute(|| panic!("job failed")).unwrap();
+    drop(release);
+    assert!(pool.shutdown(WAIT));
+
+    let seen = seen.lock().unwrap().clone();
+    let at = |level| {
+        seen.iter()
+            .filter(|(seen, _)| *seen == level)
+            .map(|(_, message)| message.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        at(Level::Error),
+        ["worker 0 panicked running a job: job failed"]
+    );
+    assert_eq!(at(Level::Warn), ["job queue full; dropped the oldest job"]);
+    assert_eq!(at(Level::Info), ["shutting down all workers"]);
+    let debug = at(Level::Debug);
+    assert_eq!(debug.first(), Some(&"worker 0 started"));
+    assert_eq!(debug.last(), Some(&"worker 0 was told to stop"));
+    assert_eq!(debug.iter().filter(|m| m.contains("got a job")).count(), 2);
+    assert_eq!(
+        debug
+            .iter()
+            .filter(|m| m.contains("finished a job"))
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn sinks_can_ask_for_stats() {
+    // A pool that emitted with its workers locked would deadlock here, so this runs on another
+    // thread with a deadline
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let handle = Arc::new(Mutex::new(Weak::<ThreadPool>::new()));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (sink_handle, sink) = (Arc::clone(&handle), Arc::clone(&seen));
+        let pool = ThreadPool::builder()
+            .min_threads(0)
+            .max_threads(1)
+            .panic_handler(|_: &JobPanic| panic!("handler failed"))
+            .events(Events::new(move |event: &Event| {
+                // Holding the handle's lock until the stats are in keeps the test's drop from
+                // racing with it
+                let handle = sink_handle.lock().unwrap();
+                if let Some(pool) = handle.upgrade() {
+                    pool.stats();
+                    sink.lock().unwrap().push(event.to_string());
+                }
+            }))
+            .build()
+            .unwrap();
+        let pool = Arc::new(pool);
+        *handle.lock().unwrap() = Arc::downgrade(&pool);
+
+        // Grows the pool for the first job, then replaces the worker its panic takes down
+        pool.execute(|| panic!("job failed")).unwrap();
+        run_jobs(&pool, 1);
+
+        *handle.lock().unwrap() = Weak::new();
+        drop(pool);
+        done.send(seen.lock().unwrap().clone()).unwrap();
+    });
+
+    let seen = finished.recv_timeout(WAIT).expect("the pool deadlocked");
+    assert!(seen.iter().any(|m| m == "worker 0 started"));
+    assert!(seen.iter().any(|m| m == "worker 1 started"));
+}